@@ -0,0 +1,16 @@
+-- `published_at` was stored as the text representation of `now()`
+ALTER TABLE newsletter_issues
+    ALTER COLUMN published_at TYPE timestamptz USING published_at::timestamptz;
+
+ALTER TABLE newsletter_issues ADD COLUMN slug TEXT;
+UPDATE newsletter_issues SET slug = newsletter_issue_id::text;
+ALTER TABLE newsletter_issues ALTER COLUMN slug SET NOT NULL;
+ALTER TABLE newsletter_issues ADD CONSTRAINT newsletter_issues_slug_key UNIQUE (slug);
+
+-- Only `published` issues are ever shown in the public archive
+ALTER TABLE newsletter_issues
+    ADD COLUMN status TEXT NOT NULL DEFAULT 'published'
+    CHECK (status IN ('draft', 'scheduled', 'published'));
+
+ALTER TABLE newsletter_issues
+    ADD COLUMN hidden_from_archive BOOLEAN NOT NULL DEFAULT false;
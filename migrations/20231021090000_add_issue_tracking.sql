@@ -0,0 +1,15 @@
+ALTER TABLE newsletter_issues
+    ADD COLUMN tracking_enabled BOOLEAN NOT NULL DEFAULT false;
+
+CREATE TABLE issue_tracking_events (
+    event_id uuid NOT NULL,
+    newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues(newsletter_issue_id),
+    subscriber_id uuid NOT NULL REFERENCES subscriptions(id),
+    kind TEXT NOT NULL CHECK (kind IN ('open', 'click')),
+    -- The destination of a click, NULL for opens
+    url TEXT,
+    occurred_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY(event_id)
+);
+
+CREATE INDEX issue_tracking_events_issue_idx ON issue_tracking_events (newsletter_issue_id, kind);
@@ -0,0 +1,11 @@
+-- History of everything that happened to a subscription, e.g. bounces reported by Postmark
+CREATE TABLE subscription_events (
+    event_id uuid NOT NULL,
+    subscriber_id uuid NOT NULL REFERENCES subscriptions(id),
+    event_type TEXT NOT NULL,
+    details jsonb NOT NULL DEFAULT '{}'::jsonb,
+    occurred_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY(event_id)
+);
+
+CREATE INDEX subscription_events_subscriber_idx ON subscription_events (subscriber_id, occurred_at);
@@ -0,0 +1,22 @@
+-- Addresses we must never email again, whatever the state of their subscription
+CREATE TABLE suppressed_emails (
+    -- Always stored lowercased
+    email TEXT NOT NULL,
+    source TEXT NOT NULL CHECK (source IN ('manual', 'bounce', 'complaint', 'unsubscribe')),
+    reason TEXT NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY(email)
+);
+
+INSERT INTO suppressed_emails (email, source, reason)
+SELECT
+    lower(email),
+    CASE status
+        WHEN 'bounced' THEN 'bounce'
+        WHEN 'complained' THEN 'complaint'
+        ELSE 'unsubscribe'
+    END,
+    'Backfilled from the subscription status'
+FROM subscriptions
+WHERE status IN ('bounced', 'complained', 'unsubscribed')
+ON CONFLICT DO NOTHING;
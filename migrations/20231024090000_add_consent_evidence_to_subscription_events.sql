@@ -0,0 +1,11 @@
+-- Evidence of consent: where a subscription or confirmation request came from
+ALTER TABLE subscription_events
+    ADD COLUMN ip_address TEXT,
+    ADD COLUMN user_agent TEXT,
+    ADD COLUMN consent_text TEXT;
+
+-- Subscriptions that predate the history only have their subscription timestamp
+INSERT INTO subscription_events (event_id, subscriber_id, event_type, details, occurred_at)
+-- `gen_random_uuid()` needs Postgres 13, production still runs 12
+SELECT md5(random()::text || id::text)::uuid, id, 'subscribed', '{"backfilled": true}'::jsonb, subscribed_at
+FROM subscriptions;
@@ -0,0 +1,12 @@
+-- Subscribers whose data was erased on request. Only a hash of the address is kept,
+-- so that we can tell when somebody we erased is imported or subscribes again.
+CREATE TABLE erased_subscribers (
+    email_hash TEXT NOT NULL,
+    erased_at timestamptz NOT NULL DEFAULT now(),
+    -- An erased subscriber must stay off our list without us keeping their address:
+    -- their suppression, if any, moves here from suppressed_emails
+    suppression_source TEXT CHECK (
+        suppression_source IN ('manual', 'bounce', 'complaint', 'unsubscribe')
+    ),
+    PRIMARY KEY(email_hash)
+);
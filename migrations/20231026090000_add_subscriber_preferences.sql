@@ -0,0 +1,18 @@
+ALTER TABLE subscriptions
+    ADD COLUMN preferred_format TEXT NOT NULL DEFAULT 'html'
+        CHECK (preferred_format IN ('html', 'text')),
+    ADD COLUMN delivery_frequency TEXT NOT NULL DEFAULT 'every_issue'
+        CHECK (delivery_frequency IN ('every_issue', 'weekly', 'monthly'));
+
+-- Subscribers on a weekly or monthly schedule get their issues batched on a given day
+ALTER TABLE issue_delivery_queue
+    ADD COLUMN execute_after timestamptz NOT NULL DEFAULT now();
+
+-- A new address waiting to be confirmed by its owner before it replaces the current one
+CREATE TABLE email_change_requests (
+    token TEXT NOT NULL,
+    subscriber_id uuid NOT NULL REFERENCES subscriptions(id),
+    new_email TEXT NOT NULL,
+    requested_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY(token)
+);
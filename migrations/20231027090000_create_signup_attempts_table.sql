@@ -0,0 +1,16 @@
+-- Every call to POST /subscriptions, to rate limit signups and count the ones we reject.
+-- Data requests email an address of the visitor's choosing too: they share the limit per IP
+-- address, get a limit per domain of their own, and stay out of the signup statistics.
+CREATE TABLE signup_attempts (
+    kind TEXT NOT NULL CHECK (kind IN ('signup', 'data_request')),
+    ip_address TEXT,
+    email_domain TEXT NOT NULL,
+    outcome TEXT NOT NULL CHECK (
+        outcome IN ('accepted', 'honeypot', 'captcha_failed', 'rate_limited_ip', 'rate_limited_domain')
+    ),
+    attempted_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX signup_attempts_ip_idx ON signup_attempts (ip_address, attempted_at);
+CREATE INDEX signup_attempts_domain_idx ON signup_attempts (email_domain, kind, attempted_at);
+CREATE INDEX signup_attempts_attempted_at_idx ON signup_attempts (attempted_at);
@@ -0,0 +1,6 @@
+-- Background workers check in here, so that readiness checks can tell whether they are still running
+CREATE TABLE worker_heartbeats (
+    worker_name TEXT NOT NULL,
+    last_seen_at timestamptz NOT NULL,
+    PRIMARY KEY(worker_name)
+);
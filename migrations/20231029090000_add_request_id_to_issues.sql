@@ -0,0 +1,3 @@
+-- The id of the HTTP request that published an issue, so that its deliveries can be traced back to it
+ALTER TABLE newsletter_issues ADD COLUMN request_id uuid;
+ALTER TABLE issue_delivery_queue ADD COLUMN request_id uuid;
@@ -0,0 +1,232 @@
+use crate::authentication::{change_password, create_user, get_user_id, PASSWORD_LENGTH};
+use crate::configuration::Settings;
+use crate::data_requests::erase_subscriber_data;
+use crate::idempotency::purge_saved_responses;
+use crate::issue_delivery_worker::enqueue_delivery_tasks;
+use crate::routes::subscriptions_confirm::confirm_subscriber;
+use crate::startup::{get_connection_pool, MIGRATOR};
+use anyhow::{bail, Context};
+use clap::Subcommand;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use std::io::{BufRead, IsTerminal};
+use uuid::Uuid;
+
+/// Operational tasks, run against the database of the current environment
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    /// Manage the users that can log into the admin dashboard
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+    /// Manage subscribers
+    Subscriber {
+        #[command(subcommand)]
+        command: SubscriberCommand,
+    },
+    /// Manage newsletter issues
+    Issue {
+        #[command(subcommand)]
+        command: IssueCommand,
+    },
+    /// Manage the responses saved for idempotent requests
+    Idempotency {
+        #[command(subcommand)]
+        command: IdempotencyCommand,
+    },
+    /// Apply the migrations the database is missing
+    Migrate,
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Create a user. The password is read from standard input.
+    Create { username: String },
+    /// Set a new password for a user. The password is read from standard input.
+    ResetPassword { username: String },
+}
+
+#[derive(Subcommand)]
+pub enum SubscriberCommand {
+    /// Confirm a subscription on behalf of a subscriber who cannot click their link
+    Confirm { email: String },
+    /// Remove everything we hold about an email address
+    Remove { email: String },
+}
+
+#[derive(Subcommand)]
+pub enum IssueCommand {
+    /// Queue a published issue for delivery again
+    Resend {
+        issue_id: Uuid,
+        /// Only send it to this subscriber, rather than to every confirmed subscriber
+        #[arg(long)]
+        to: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum IdempotencyCommand {
+    /// Forget the responses saved for requests older than the given number of hours
+    Purge {
+        #[arg(long, default_value = "48")]
+        older_than_hours: u32,
+    },
+}
+
+pub async fn run_admin_command(
+    configuration: Settings,
+    command: AdminCommand,
+) -> Result<(), anyhow::Error> {
+    let pool = get_connection_pool(&configuration.database);
+    match command {
+        AdminCommand::User { command } => match command {
+            UserCommand::Create { username } => {
+                let password = read_new_password()?;
+                let user_id = create_user(&username, password, &pool).await?;
+                println!("Created user {} with id {}", username, user_id);
+            }
+            UserCommand::ResetPassword { username } => {
+                let Some(user_id) = get_user_id(&username, &pool).await? else {
+                    bail!("There is no user named {}", username);
+                };
+                let password = read_new_password()?;
+                change_password(user_id, password, &pool).await?;
+                println!("Changed the password of {}", username);
+            }
+        },
+        AdminCommand::Subscriber { command } => match command {
+            SubscriberCommand::Confirm { email } => {
+                let Some(subscriber_id) = get_subscriber_id(&pool, &email).await? else {
+                    bail!("There is no subscriber with the address {}", email);
+                };
+                if confirm_subscriber(&pool, subscriber_id, None)
+                    .await
+                    .context("Failed to confirm the subscription")?
+                {
+                    println!("Confirmed the subscription of {}", email);
+                } else {
+                    bail!("The subscription of {} is not pending confirmation", email);
+                }
+            }
+            SubscriberCommand::Remove { email } => {
+                if erase_subscriber_data(&pool, &configuration.application.hmac_secret, &email)
+                    .await
+                    .context("Failed to erase the subscriber data")?
+                {
+                    println!("Removed everything we held about {}", email);
+                } else {
+                    println!("We held nothing about {}", email);
+                }
+            }
+        },
+        AdminCommand::Issue { command } => match command {
+            IssueCommand::Resend { issue_id, to } => {
+                let queued = resend_issue(&pool, issue_id, to.as_deref()).await?;
+                println!("Queued {} deliveries of issue {}", queued, issue_id);
+            }
+        },
+        AdminCommand::Idempotency { command } => match command {
+            IdempotencyCommand::Purge { older_than_hours } => {
+                let cutoff = chrono::Utc::now() - chrono::Duration::hours(older_than_hours.into());
+                let purged = purge_saved_responses(&pool, cutoff)
+                    .await
+                    .context("Failed to purge the saved responses")?;
+                println!("Purged {} saved responses", purged);
+            }
+        },
+        AdminCommand::Migrate => {
+            MIGRATOR
+                .run(&pool)
+                .await
+                .context("Failed to run the migrations")?;
+            println!("The database is up to date");
+        }
+    }
+    Ok(())
+}
+
+/// Prompt for the password when a person is typing it, read a line otherwise
+fn read_new_password() -> Result<Secret<String>, anyhow::Error> {
+    let password = if std::io::stdin().is_terminal() {
+        let password = rpassword::prompt_password("New password: ")?;
+        if password != rpassword::prompt_password("New password again: ")? {
+            bail!("The two passwords do not match");
+        }
+        password
+    } else {
+        let mut password = String::new();
+        std::io::stdin()
+            .lock()
+            .read_line(&mut password)
+            .context("Failed to read the password")?;
+        password.trim_end_matches(['\r', '\n']).to_owned()
+    };
+    let password = Secret::new(password);
+    if !PASSWORD_LENGTH.contains(&password.expose_secret().len()) {
+        bail!(
+            "Password length must be between {} and {} characters.",
+            PASSWORD_LENGTH.start(),
+            PASSWORD_LENGTH.end()
+        );
+    }
+    Ok(password)
+}
+
+/// Addresses are unique only as they were typed: when several subscriptions match regardless of
+/// case, the one typed exactly as given is picked, and we refuse to guess otherwise.
+async fn get_subscriber_id(pool: &PgPool, email: &str) -> Result<Option<Uuid>, anyhow::Error> {
+    let rows = sqlx::query!(
+        "SELECT id, email FROM subscriptions WHERE lower(email) = lower($1) ORDER BY email",
+        email
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to look up the subscriber")?;
+    if rows.len() > 1 {
+        return match rows.iter().find(|r| r.email == email) {
+            Some(row) => Ok(Some(row.id)),
+            None => bail!(
+                "{} subscriptions match {}, give one of these addresses exactly: {}",
+                rows.len(),
+                email,
+                rows.iter()
+                    .map(|r| r.email.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+    }
+    Ok(rows.first().map(|r| r.id))
+}
+
+/// Returns how many deliveries were queued. Subscribers who already have one queued
+/// for this issue are not counted.
+#[tracing::instrument(skip(pool))]
+pub async fn resend_issue(
+    pool: &PgPool,
+    issue_id: Uuid,
+    recipient: Option<&str>,
+) -> Result<u64, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    let issue = sqlx::query!(
+        "SELECT status FROM newsletter_issues WHERE newsletter_issue_id = $1",
+        issue_id
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to look up the issue")?;
+    match issue {
+        None => bail!("There is no issue with id {}", issue_id),
+        Some(issue) if issue.status != "published" => {
+            bail!("Issue {} has not been published", issue_id)
+        }
+        Some(_) => {}
+    }
+    let queued = enqueue_delivery_tasks(&mut transaction, issue_id, recipient, None)
+        .await
+        .context("Failed to enqueue delivery tasks")?;
+    transaction.commit().await?;
+    Ok(queued)
+}
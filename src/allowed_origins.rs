@@ -0,0 +1,107 @@
+use reqwest::Url;
+
+/// The sites allowed to embed our signup widget: our own, plus the ones listed in the CORS settings.
+/// The same list decides which browsers may post subscriptions and where we may send them back to.
+pub struct AllowedOrigins {
+    base_url: Url,
+    origins: Vec<String>,
+}
+
+impl AllowedOrigins {
+    pub fn new(base_url: &str, other_origins: &[String]) -> Result<Self, anyhow::Error> {
+        let base_url = Url::parse(base_url)?;
+        let mut origins = vec![base_url.origin().ascii_serialization()];
+        for origin in other_origins {
+            let url = Url::parse(origin)
+                .map_err(|e| anyhow::anyhow!("{} is not a valid origin: {}", origin, e))?;
+            origins.push(url.origin().ascii_serialization());
+        }
+        Ok(Self { base_url, origins })
+    }
+
+    /// Origins as browsers send them in the `Origin` header, e.g. `https://www.example.com`
+    pub fn origins(&self) -> &[String] {
+        &self.origins
+    }
+
+    /// Where to send a browser back to after it posted a form, if that is one of our allowed sites.
+    /// Relative targets are resolved against our own base URL.
+    pub fn redirect_target(&self, target: &str) -> Option<Url> {
+        let url = self.base_url.join(target).ok()?;
+        let origin = url.origin().ascii_serialization();
+        self.origins.contains(&origin).then_some(url)
+    }
+
+    /// The value of a `Location` header for `url`: pages of our own stay relative
+    pub fn location(&self, url: &Url) -> String {
+        if url.origin() == self.base_url.origin() {
+            match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            }
+        } else {
+            url.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AllowedOrigins;
+
+    fn allowed_origins() -> AllowedOrigins {
+        AllowedOrigins::new(
+            "https://newsletter.example.com",
+            &["https://www.example.com/".into()],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn origins_are_normalized() {
+        assert_eq!(
+            allowed_origins().origins(),
+            ["https://newsletter.example.com", "https://www.example.com"]
+        );
+    }
+
+    #[test]
+    fn relative_targets_stay_on_our_site() {
+        let origins = allowed_origins();
+        let url = origins.redirect_target("/subscribe?from=home").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://newsletter.example.com/subscribe?from=home"
+        );
+        assert_eq!(origins.location(&url), "/subscribe?from=home");
+    }
+
+    #[test]
+    fn targets_on_allowed_origins_are_accepted() {
+        let origins = allowed_origins();
+        let url = origins
+            .redirect_target("https://www.example.com/blog/post")
+            .unwrap();
+        assert_eq!(origins.location(&url), "https://www.example.com/blog/post");
+    }
+
+    #[test]
+    fn targets_elsewhere_are_rejected() {
+        let origins = allowed_origins();
+        for target in [
+            "https://evil.example.com/",
+            "//evil.example.com/",
+            "/\\evil.example.com/",
+            "http://www.example.com/",
+            "https://www.example.com.evil.com/",
+            "javascript:alert(1)",
+        ] {
+            assert!(origins.redirect_target(target).is_none(), "{}", target);
+        }
+    }
+
+    #[test]
+    fn invalid_origins_are_reported() {
+        assert!(AllowedOrigins::new("https://newsletter.example.com", &["nope".into()]).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use actix_session::SessionInsertError;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE},
+        Method,
+    },
+    web, FromRequest,
+};
+use actix_web_lab::middleware::Next;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+
+use crate::{
+    error::AppError,
+    session_state::TypedSession,
+    utils::{constant_time_eq, e500},
+};
+
+/// Header that API clients can use instead of the `csrf_token` form field
+pub const CSRF_TOKEN_HEADER: &str = "X-CSRF-Token";
+
+/// Return the synchronizer token for the current session, generating one on first use.
+/// Every form rendered for a logged-in user must embed it via `partials/csrf.html`.
+pub fn csrf_token(session: &TypedSession) -> Result<String, actix_web::Error> {
+    if let Some(csrf_token) = session.get_csrf_token().map_err(e500)? {
+        return Ok(csrf_token);
+    }
+    let csrf_token = generate_csrf_token();
+    session.insert_csrf_token(&csrf_token).map_err(e500)?;
+    Ok(csrf_token)
+}
+
+/// Replace the synchronizer token, e.g. when the privilege level of the session changes on login
+pub fn rotate_csrf_token(session: &TypedSession) -> Result<(), SessionInsertError> {
+    session.insert_csrf_token(&generate_csrf_token())
+}
+
+/// Verify the synchronizer token on every state-changing request.
+///
+/// The token is read from the `X-CSRF-Token` header or, failing that, from the `csrf_token` field of
+/// an url-encoded form. JSON requests authenticated with a bearer token are exempt: browsers will not
+/// attach an `Authorization` header to a cross-site request without a CORS preflight.
+pub async fn reject_invalid_csrf_tokens(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    if is_safe(req.method()) || is_bearer_json_request(req.headers()) {
+        return next.call(req).await;
+    }
+
+    let session = {
+        let (http_request, payload) = req.parts_mut();
+        TypedSession::from_request(http_request, payload).await
+    }?;
+    let expected = session.get_csrf_token().map_err(e500)?;
+
+    let submitted = match req.headers().get(CSRF_TOKEN_HEADER) {
+        Some(value) => value.to_str().ok().map(ToOwned::to_owned),
+        None if is_form(req.headers()) => {
+            // We have to buffer the body to look for the token, and then hand it back to the handler
+            let body = req.extract::<web::Bytes>().await?;
+            let token = serde_urlencoded::from_bytes::<CsrfTokenForm>(&body)
+                .ok()
+                .and_then(|form| form.csrf_token);
+            let (_, mut payload) = actix_http::h1::Payload::create(true);
+            payload.unread_data(body);
+            req.set_payload(payload.into());
+            token
+        }
+        None => None,
+    };
+
+    match (expected, submitted) {
+        (Some(expected), Some(submitted)) if constant_time_eq(&expected, &submitted) => {
+            next.call(req).await
+        }
+        _ => {
+            tracing::warn!("Rejected a request with a missing or invalid CSRF token");
+            Err(AppError::Forbidden.into())
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct CsrfTokenForm {
+    csrf_token: Option<String>,
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+fn content_type_starts_with(headers: &HeaderMap, prefix: &str) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with(prefix))
+        .unwrap_or(false)
+}
+
+fn is_form(headers: &HeaderMap) -> bool {
+    content_type_starts_with(headers, "application/x-www-form-urlencoded")
+}
+
+fn is_bearer_json_request(headers: &HeaderMap) -> bool {
+    let has_bearer_token = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("Bearer "))
+        .unwrap_or(false);
+    has_bearer_token && content_type_starts_with(headers, "application/json")
+}
+
+fn generate_csrf_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(32)
+        .collect()
+}
@@ -1,6 +1,11 @@
+mod csrf;
 mod middleware;
 mod password;
 
+pub use csrf::{csrf_token, reject_invalid_csrf_tokens, rotate_csrf_token, CSRF_TOKEN_HEADER};
 pub use middleware::reject_anonymous_users;
 pub use middleware::UserId;
-pub use password::{change_password, validate_credentials, AuthError, Credentials};
+pub use password::{
+    change_password, compute_password_hash, create_user, get_user_id, validate_credentials,
+    AuthError, Credentials, PASSWORD_LENGTH,
+};
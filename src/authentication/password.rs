@@ -1,3 +1,4 @@
+use crate::metrics::METRICS;
 use crate::telemetry::spawn_blocking_with_tracing;
 
 use anyhow::Context;
@@ -7,6 +8,10 @@ use secrecy::ExposeSecret;
 use secrecy::Secret;
 
 use sqlx::PgPool;
+use std::ops::RangeInclusive;
+
+/// How long, in characters, a new password has to be
+pub const PASSWORD_LENGTH: RangeInclusive<usize> = 12..=128;
 
 pub struct Credentials {
     pub username: String,
@@ -64,6 +69,7 @@ pub fn verify_password_hash(
         .context("Failed to parse hash in PHC string format")
         .map_err(AuthError::UnexpectedError)?;
 
+    let _timer = METRICS.password_verification_duration.start_timer();
     Argon2::default()
         .verify_password(
             password_candidate.expose_secret().as_bytes(),
@@ -118,7 +124,44 @@ pub async fn change_password(
     Ok(())
 }
 
-fn compute_password_hash(password: Secret<String>) -> Result<Secret<String>, anyhow::Error> {
+#[tracing::instrument(name = "Create a user", skip(password, pool))]
+pub async fn create_user(
+    username: &str,
+    password: Secret<String>,
+    pool: &PgPool,
+) -> Result<uuid::Uuid, anyhow::Error> {
+    let password_hash = spawn_blocking_with_tracing(move || compute_password_hash(password))
+        .await?
+        .context("Failed to hash password")?;
+    let user_id = uuid::Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO users (user_id, username, password_hash)
+        VALUES ($1, $2, $3)
+        "#,
+        user_id,
+        username,
+        password_hash.expose_secret(),
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store the new user in the database")?;
+    Ok(user_id)
+}
+
+#[tracing::instrument(name = "Get user id", skip(pool))]
+pub async fn get_user_id(
+    username: &str,
+    pool: &PgPool,
+) -> Result<Option<uuid::Uuid>, anyhow::Error> {
+    let row = sqlx::query!("SELECT user_id FROM users WHERE username = $1", username)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to perform a query to retrieve a user id")?;
+    Ok(row.map(|r| r.user_id))
+}
+
+pub fn compute_password_hash(password: Secret<String>) -> Result<Secret<String>, anyhow::Error> {
     let salt = SaltString::generate(&mut rand::thread_rng());
     let password_hash = Argon2::new(
         argon2::Algorithm::Argon2id,
@@ -0,0 +1,119 @@
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+
+/// Checks the response of a CAPTCHA widget with its provider
+pub struct CaptchaClient {
+    http_client: Client,
+    verify_url: String,
+    secret: Secret<String>,
+}
+
+#[derive(serde::Serialize)]
+struct VerifyRequest<'a> {
+    secret: &'a str,
+    response: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remoteip: Option<&'a str>,
+}
+
+#[derive(serde::Deserialize)]
+struct VerifyResponse {
+    success: bool,
+}
+
+impl CaptchaClient {
+    pub fn new(verify_url: String, secret: Secret<String>, timeout: std::time::Duration) -> Self {
+        Self {
+            http_client: Client::builder().timeout(timeout).build().unwrap(),
+            verify_url,
+            secret,
+        }
+    }
+
+    /// Whether the provider accepts the response the widget gave to the user
+    pub async fn verify(
+        &self,
+        response: &str,
+        remote_ip: Option<&str>,
+    ) -> Result<bool, reqwest::Error> {
+        let outcome: VerifyResponse = self
+            .http_client
+            .post(&self.verify_url)
+            .form(&VerifyRequest {
+                secret: self.secret.expose_secret(),
+                response,
+                remoteip: remote_ip,
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(outcome.success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CaptchaClient;
+    use claims::{assert_err, assert_ok_eq};
+    use secrecy::Secret;
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn client(server: &MockServer) -> CaptchaClient {
+        CaptchaClient::new(
+            format!("{}/siteverify", server.uri()),
+            Secret::new("captcha-secret".into()),
+            std::time::Duration::from_millis(200),
+        )
+    }
+
+    #[tokio::test]
+    async fn verify_sends_the_secret_and_the_response() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/siteverify"))
+            .and(body_string_contains("secret=captcha-secret"))
+            .and(body_string_contains("response=a-response"))
+            .and(body_string_contains("remoteip=127.0.0.1"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({"success": true})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let outcome = client(&server)
+            .verify("a-response", Some("127.0.0.1"))
+            .await;
+
+        assert_ok_eq!(outcome, true);
+    }
+
+    #[tokio::test]
+    async fn rejected_responses_are_reported() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({"success": false})),
+            )
+            .mount(&server)
+            .await;
+
+        let outcome = client(&server).verify("a-response", None).await;
+
+        assert_ok_eq!(outcome, false);
+    }
+
+    #[tokio::test]
+    async fn provider_errors_are_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        assert_err!(client(&server).verify("a-response", None).await);
+    }
+}
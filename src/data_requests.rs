@@ -0,0 +1,478 @@
+use crate::subscription_history::{get_subscription_events, StoredSubscriptionEvent};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use sqlx::{PgExecutor, PgPool};
+use uuid::Uuid;
+
+/// How long the link emailed to a subscriber stays valid
+const LINK_VALIDITY_HOURS: i64 = 24;
+
+/// What a data subject can ask us to do with their data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DataRequestAction {
+    Export,
+    Erase,
+}
+
+impl DataRequestAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DataRequestAction::Export => "export",
+            DataRequestAction::Erase => "erase",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "export" => Some(DataRequestAction::Export),
+            "erase" => Some(DataRequestAction::Erase),
+            _ => None,
+        }
+    }
+}
+
+/// A verified request, as carried by an emailed link
+#[derive(Debug, PartialEq, Eq)]
+pub struct DataRequest {
+    pub action: DataRequestAction,
+    pub email: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid or expired data request token")]
+pub struct InvalidDataRequestToken;
+
+/// Signs and verifies the links we email to people asking for their data.
+/// Holding the link proves control of the address, so it is all the authentication we ask for.
+pub struct DataRequestLinks {
+    base_url: String,
+    secret: Secret<String>,
+}
+
+impl DataRequestLinks {
+    pub fn new(base_url: String, secret: Secret<String>) -> Self {
+        Self { base_url, secret }
+    }
+
+    pub fn link(&self, action: DataRequestAction, email: &str) -> String {
+        self.link_expiring_at(
+            action,
+            email,
+            Utc::now() + Duration::hours(LINK_VALIDITY_HOURS),
+        )
+    }
+
+    fn link_expiring_at(
+        &self,
+        action: DataRequestAction,
+        email: &str,
+        expires_at: DateTime<Utc>,
+    ) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(format!(
+            "{}|{}|{}",
+            action.as_str(),
+            expires_at.timestamp(),
+            email
+        ));
+        let signature = self.mac(payload.as_bytes()).finalize().into_bytes();
+        format!(
+            "{}/subscriptions/data/{}?token={}.{}",
+            self.base_url,
+            action.as_str(),
+            payload,
+            URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    pub fn verify(&self, token: &str) -> Result<DataRequest, InvalidDataRequestToken> {
+        let (payload, signature) = token.split_once('.').ok_or(InvalidDataRequestToken)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| InvalidDataRequestToken)?;
+        self.mac(payload.as_bytes())
+            .verify_slice(&signature)
+            .map_err(|_| InvalidDataRequestToken)?;
+
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| InvalidDataRequestToken)?;
+        let payload = String::from_utf8(payload).map_err(|_| InvalidDataRequestToken)?;
+        // The email goes last: it is the only part that could contain the separator
+        let mut parts = payload.splitn(3, '|');
+        let action = parts
+            .next()
+            .and_then(DataRequestAction::parse)
+            .ok_or(InvalidDataRequestToken)?;
+        let expires_at = parts
+            .next()
+            .and_then(|t| t.parse().ok())
+            .and_then(|t| Utc.timestamp_opt(t, 0).single())
+            .ok_or(InvalidDataRequestToken)?;
+        let email = parts.next().ok_or(InvalidDataRequestToken)?;
+        if expires_at < Utc::now() {
+            return Err(InvalidDataRequestToken);
+        }
+        Ok(DataRequest {
+            action,
+            email: email.to_owned(),
+        })
+    }
+
+    fn mac(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC can take a key of any size");
+        mac.update(payload);
+        mac
+    }
+}
+
+/// Everything we hold about an email address, as handed over to its owner
+#[derive(serde::Serialize)]
+pub struct SubscriberData {
+    pub email: String,
+    pub exported_at: DateTime<Utc>,
+    /// Addresses are unique only as they were typed: there is a subscription for each spelling
+    pub subscriptions: Vec<SubscriptionRecord>,
+    /// Issues that are queued but not delivered yet
+    pub pending_deliveries: Vec<Uuid>,
+    pub suppression: Option<SuppressionData>,
+}
+
+/// A subscription, with everything that hangs off it
+#[derive(serde::Serialize)]
+pub struct SubscriptionRecord {
+    #[serde(flatten)]
+    pub subscription: SubscriptionData,
+    pub subscription_events: Vec<StoredSubscriptionEvent>,
+    /// New addresses the subscriber asked to move to, not confirmed yet
+    pub pending_email_changes: Vec<String>,
+    pub tracking_events: Vec<TrackingEventData>,
+}
+
+// Subscription tokens are deliberately left out: they are credentials, not personal data
+#[derive(serde::Serialize)]
+pub struct SubscriptionData {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub preferred_format: String,
+    pub delivery_frequency: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct TrackingEventData {
+    pub newsletter_issue_id: Uuid,
+    pub kind: String,
+    pub url: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+pub struct SuppressionData {
+    pub source: String,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What is left of an erased subscriber: addresses are compared case-insensitively.
+/// Keyed with our secret, so that the tombstones cannot be matched against a list of addresses.
+pub fn email_hash(secret: &Secret<String>, email: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(email.trim().to_lowercase().as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Collect everything tied to an email address. Returns `None` if we hold nothing about it.
+#[tracing::instrument(skip_all)]
+pub async fn export_subscriber_data(
+    pool: &PgPool,
+    email: &str,
+) -> Result<Option<SubscriberData>, sqlx::Error> {
+    let subscriptions = sqlx::query_as!(
+        SubscriptionData,
+        r#"
+        SELECT id, email, name, status, subscribed_at, preferred_format, delivery_frequency
+        FROM subscriptions
+        WHERE lower(email) = lower($1)
+        ORDER BY subscribed_at
+        "#,
+        email
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut records = Vec::with_capacity(subscriptions.len());
+    for subscription in subscriptions {
+        let subscription_events = get_subscription_events(pool, subscription.id).await?;
+        let pending_email_changes = sqlx::query!(
+            "SELECT new_email FROM email_change_requests WHERE subscriber_id = $1",
+            subscription.id
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|r| r.new_email)
+        .collect();
+        let tracking_events = sqlx::query_as!(
+            TrackingEventData,
+            r#"
+            SELECT newsletter_issue_id, kind, url, occurred_at
+            FROM issue_tracking_events
+            WHERE subscriber_id = $1
+            ORDER BY occurred_at
+            "#,
+            subscription.id
+        )
+        .fetch_all(pool)
+        .await?;
+        records.push(SubscriptionRecord {
+            subscription,
+            subscription_events,
+            pending_email_changes,
+            tracking_events,
+        });
+    }
+    let pending_deliveries = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id
+        FROM issue_delivery_queue
+        WHERE lower(subscriber_email) = lower($1)
+        "#,
+        email
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| r.newsletter_issue_id)
+    .collect::<Vec<_>>();
+    let suppression = sqlx::query_as!(
+        SuppressionData,
+        "SELECT source, reason, created_at FROM suppressed_emails WHERE email = lower($1)",
+        email
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if records.is_empty() && pending_deliveries.is_empty() && suppression.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(SubscriberData {
+        email: email.to_owned(),
+        exported_at: Utc::now(),
+        subscriptions: records,
+        pending_deliveries,
+        suppression,
+    }))
+}
+
+/// Delete everything tied to an email address and leave a tombstone behind.
+/// A suppression moves to the tombstone, rather than going away: that is what keeps us from
+/// emailing somebody who told us not to. Returns `false` if there was nothing to delete.
+#[tracing::instrument(skip_all)]
+pub async fn erase_subscriber_data(
+    pool: &PgPool,
+    secret: &Secret<String>,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    // Every spelling of the address goes, not just the first one we find
+    let subscriber_ids: Vec<Uuid> = sqlx::query!(
+        "SELECT id FROM subscriptions WHERE lower(email) = lower($1)",
+        email
+    )
+    .fetch_all(&mut transaction)
+    .await?
+    .into_iter()
+    .map(|r| r.id)
+    .collect();
+    let mut erased = false;
+    if !subscriber_ids.is_empty() {
+        sqlx::query!(
+            "DELETE FROM issue_tracking_events WHERE subscriber_id = ANY($1)",
+            &subscriber_ids
+        )
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM subscription_events WHERE subscriber_id = ANY($1)",
+            &subscriber_ids
+        )
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM email_change_requests WHERE subscriber_id = ANY($1)",
+            &subscriber_ids
+        )
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM subscription_tokens WHERE subscriber_id = ANY($1)",
+            &subscriber_ids
+        )
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM subscriptions WHERE id = ANY($1)",
+            &subscriber_ids
+        )
+        .execute(&mut transaction)
+        .await?;
+        erased = true;
+    }
+    let deleted_deliveries = sqlx::query!(
+        "DELETE FROM issue_delivery_queue WHERE lower(subscriber_email) = lower($1)",
+        email
+    )
+    .execute(&mut transaction)
+    .await?;
+    erased |= deleted_deliveries.rows_affected() > 0;
+    // Other subscribers may have asked to move to this address, or moved away from it
+    let deleted_changes = sqlx::query!(
+        "DELETE FROM email_change_requests WHERE lower(new_email) = lower($1)",
+        email
+    )
+    .execute(&mut transaction)
+    .await?;
+    erased |= deleted_changes.rows_affected() > 0;
+    let cleared_events = sqlx::query!(
+        r#"
+        UPDATE subscription_events SET details = details - 'previous_email'
+        WHERE lower(details->>'previous_email') = lower($1)
+        "#,
+        email
+    )
+    .execute(&mut transaction)
+    .await?;
+    erased |= cleared_events.rows_affected() > 0;
+    let suppression_source = sqlx::query!(
+        "DELETE FROM suppressed_emails WHERE email = lower($1) RETURNING source",
+        email
+    )
+    .fetch_optional(&mut transaction)
+    .await?
+    .map(|r| r.source);
+    erased |= suppression_source.is_some();
+    if !erased {
+        return Ok(false);
+    }
+    sqlx::query!(
+        r#"
+        INSERT INTO erased_subscribers (email_hash, suppression_source)
+        VALUES ($1, $2)
+        ON CONFLICT (email_hash) DO UPDATE SET
+            erased_at = now(),
+            suppression_source = COALESCE(
+                EXCLUDED.suppression_source,
+                erased_subscribers.suppression_source
+            )
+        "#,
+        email_hash(secret, email),
+        suppression_source
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(true)
+}
+
+/// Whether the data of this address was erased at some point
+#[tracing::instrument(skip(executor, secret))]
+pub async fn was_erased(
+    executor: impl PgExecutor<'_>,
+    secret: &Secret<String>,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT EXISTS(SELECT 1 FROM erased_subscribers WHERE email_hash = $1) AS "erased!""#,
+        email_hash(secret, email)
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(row.erased)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{email_hash, DataRequest, DataRequestAction, DataRequestLinks};
+    use chrono::{Duration, Utc};
+    use claims::assert_err;
+    use secrecy::Secret;
+
+    fn links() -> DataRequestLinks {
+        DataRequestLinks::new("http://127.0.0.1".into(), Secret::new("secret".into()))
+    }
+
+    fn token(link: &str) -> &str {
+        link.split_once("?token=").unwrap().1
+    }
+
+    #[test]
+    fn links_round_trip() {
+        let links = links();
+        let link = links.link(DataRequestAction::Erase, "ursula|le_guin@gmail.com");
+        assert!(link.starts_with("http://127.0.0.1/subscriptions/data/erase?token="));
+        assert_eq!(
+            links.verify(token(&link)).unwrap(),
+            DataRequest {
+                action: DataRequestAction::Erase,
+                email: "ursula|le_guin@gmail.com".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn expired_links_are_rejected() {
+        let links = links();
+        let link = links.link_expiring_at(
+            DataRequestAction::Export,
+            "ursula@gmail.com",
+            Utc::now() - Duration::minutes(1),
+        );
+        assert_err!(links.verify(token(&link)));
+    }
+
+    #[test]
+    fn links_signed_with_another_secret_are_rejected() {
+        let other = DataRequestLinks::new("http://127.0.0.1".into(), Secret::new("other".into()));
+        let link = other.link(DataRequestAction::Export, "ursula@gmail.com");
+        assert_err!(links().verify(token(&link)));
+    }
+
+    #[test]
+    fn tampered_links_are_rejected() {
+        let links = links();
+        let link = links.link(DataRequestAction::Export, "ursula@gmail.com");
+        let (_, signature) = token(&link).split_once('.').unwrap();
+        let forged = links.link(DataRequestAction::Erase, "ursula@gmail.com");
+        let (payload, _) = token(&forged).split_once('.').unwrap();
+        assert_err!(links.verify(&format!("{}.{}", payload, signature)));
+    }
+
+    #[test]
+    fn email_hash_ignores_case_and_surrounding_whitespace() {
+        let secret = Secret::new("secret".into());
+        assert_eq!(
+            email_hash(&secret, "Ursula@Gmail.com "),
+            email_hash(&secret, "ursula@gmail.com")
+        );
+        assert_ne!(
+            email_hash(&secret, "ursula@gmail.com"),
+            email_hash(&secret, "le_guin@gmail.com")
+        );
+    }
+
+    #[test]
+    fn email_hash_depends_on_the_secret() {
+        assert_ne!(
+            email_hash(&Secret::new("secret".into()), "ursula@gmail.com"),
+            email_hash(&Secret::new("other".into()), "ursula@gmail.com")
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use uuid::Uuid;
+
+/// The maximum number of characters taken from the title, so that URLs stay readable
+const MAX_TITLE_CHARS: usize = 60;
+
+/// URL-friendly identifier of a newsletter issue in the public archive,
+/// e.g. `our-first-issue-3fa85f64`
+#[derive(Debug)]
+pub struct IssueSlug(String);
+
+impl IssueSlug {
+    /// Derive a slug from the issue title. The first segment of the issue id is appended
+    /// so that two issues with the same title still get distinct slugs.
+    pub fn new(title: &str, issue_id: Uuid) -> Self {
+        let mut slug = String::new();
+        for c in title.chars() {
+            if c.is_ascii_alphanumeric() {
+                slug.push(c.to_ascii_lowercase());
+            } else if !slug.is_empty() && !slug.ends_with('-') {
+                slug.push('-');
+            }
+        }
+        let mut slug: String = slug.chars().take(MAX_TITLE_CHARS).collect();
+        if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+        let id = issue_id.simple().to_string();
+        slug.push_str(&id[..8]);
+        Self(slug)
+    }
+}
+
+impl AsRef<str> for IssueSlug {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IssueSlug;
+    use uuid::Uuid;
+
+    fn id() -> Uuid {
+        Uuid::parse_str("3fa85f64-5717-4562-b3fc-2c963f66afa6").unwrap()
+    }
+
+    #[test]
+    fn title_is_lowercased_and_punctuation_becomes_dashes() {
+        let slug = IssueSlug::new("Our First Issue: Hello, World!", id());
+        assert_eq!(slug.as_ref(), "our-first-issue-hello-world-3fa85f64");
+    }
+
+    #[test]
+    fn title_without_alphanumeric_characters_falls_back_to_the_id() {
+        let slug = IssueSlug::new("!!!", id());
+        assert_eq!(slug.as_ref(), "3fa85f64");
+    }
+
+    #[test]
+    fn long_titles_are_truncated() {
+        let slug = IssueSlug::new(&"a".repeat(500), id());
+        assert_eq!(slug.as_ref().len(), 60 + 1 + 8);
+    }
+
+    #[test]
+    fn non_ascii_characters_are_dropped() {
+        let slug = IssueSlug::new("Café Olé", id());
+        assert_eq!(slug.as_ref(), "caf-ol-3fa85f64");
+    }
+}
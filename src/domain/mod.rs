@@ -1,7 +1,11 @@
+pub(crate) mod issue_slug;
 pub(crate) mod new_subscriber;
 pub(crate) mod subscriber_email;
 pub(crate) mod subscriber_name;
+pub(crate) mod subscriber_preferences;
 
+pub use issue_slug::IssueSlug;
 pub use new_subscriber::NewSubscriber;
 pub use subscriber_email::SubscriberEmail;
 pub use subscriber_name::SubscriberName;
+pub use subscriber_preferences::{DeliveryFrequency, EmailFormat};
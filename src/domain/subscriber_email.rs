@@ -11,6 +11,14 @@ impl SubscriberEmail {
             Err(format!("{} is not recognized as a valid email", s))
         }
     }
+
+    /// The part after the `@`, lowercased
+    pub fn domain(&self) -> String {
+        self.0
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.to_lowercase())
+            .unwrap_or_default()
+    }
 }
 
 impl AsRef<str> for SubscriberEmail {
@@ -67,4 +75,10 @@ mod tests {
         let email = "@example.com".to_string();
         assert_err!(SubscriberEmail::parse(email));
     }
+
+    #[test]
+    fn domain_is_lowercased() {
+        let email = SubscriberEmail::parse("Ursula@Gmail.COM".to_string()).unwrap();
+        assert_eq!(email.domain(), "gmail.com");
+    }
 }
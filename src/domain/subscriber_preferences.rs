@@ -0,0 +1,97 @@
+/// The part of an issue a subscriber wants to receive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailFormat {
+    /// HTML with a plain text alternative
+    Html,
+    /// Plain text only
+    Text,
+}
+
+impl EmailFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmailFormat::Html => "html",
+            EmailFormat::Text => "text",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "html" => Ok(EmailFormat::Html),
+            "text" => Ok(EmailFormat::Text),
+            other => Err(format!("{} is not a supported email format.", other)),
+        }
+    }
+}
+
+/// How often a subscriber wants to hear from us.
+/// Issues for weekly and monthly subscribers are held back until the next delivery day,
+/// then sent together in a single digest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryFrequency {
+    EveryIssue,
+    /// Delivered on Mondays
+    Weekly,
+    /// Delivered on the first day of the month
+    Monthly,
+}
+
+impl DeliveryFrequency {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryFrequency::EveryIssue => "every_issue",
+            DeliveryFrequency::Weekly => "weekly",
+            DeliveryFrequency::Monthly => "monthly",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "every_issue" => Ok(DeliveryFrequency::EveryIssue),
+            "weekly" => Ok(DeliveryFrequency::Weekly),
+            "monthly" => Ok(DeliveryFrequency::Monthly),
+            other => Err(format!("{} is not a supported delivery frequency.", other)),
+        }
+    }
+
+    /// The subject of the email carrying held issues, if this frequency holds them at all
+    pub fn digest_title(&self) -> Option<&'static str> {
+        match self {
+            DeliveryFrequency::EveryIssue => None,
+            DeliveryFrequency::Weekly => Some("Your weekly digest"),
+            DeliveryFrequency::Monthly => Some("Your monthly digest"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeliveryFrequency, EmailFormat};
+    use claims::assert_err;
+
+    #[test]
+    fn formats_round_trip() {
+        for format in [EmailFormat::Html, EmailFormat::Text] {
+            assert_eq!(EmailFormat::parse(format.as_str()), Ok(format));
+        }
+    }
+
+    #[test]
+    fn frequencies_round_trip() {
+        for frequency in [
+            DeliveryFrequency::EveryIssue,
+            DeliveryFrequency::Weekly,
+            DeliveryFrequency::Monthly,
+        ] {
+            assert_eq!(DeliveryFrequency::parse(frequency.as_str()), Ok(frequency));
+        }
+    }
+
+    #[test]
+    fn unknown_values_are_rejected() {
+        assert_err!(EmailFormat::parse("pdf"));
+        assert_err!(DeliveryFrequency::parse("daily"));
+    }
+}
@@ -2,6 +2,8 @@ use reqwest::Client;
 use secrecy::{ExposeSecret, Secret};
 
 use crate::domain::SubscriberEmail;
+use crate::metrics::METRICS;
+use crate::telemetry::trace_context_headers;
 
 pub struct EmailClient {
     http_client: Client,
@@ -24,12 +26,54 @@ impl EmailClient {
             authorization_token,
         }
     }
+    /// Send an email with an HTML body and its plain text alternative
     pub async fn send_email(
         &self,
         recipient: &SubscriberEmail,
         subject: &str,
         html_content: &str,
         text_content: &str,
+    ) -> Result<(), reqwest::Error> {
+        self.send(recipient, subject, Some(html_content), text_content, &[])
+            .await
+    }
+
+    /// Send an issue of the newsletter, with the headers mail clients use to offer one-click
+    /// unsubscription (RFC 8058). Without an HTML body, the issue is sent as plain text only.
+    pub async fn send_issue(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: Option<&str>,
+        text_content: &str,
+        unsubscribe_url: Option<&str>,
+    ) -> Result<(), reqwest::Error> {
+        let list_unsubscribe = unsubscribe_url.map(|url| format!("<{}>", url));
+        let headers = match &list_unsubscribe {
+            Some(list_unsubscribe) => vec![
+                EmailHeader {
+                    name: "List-Unsubscribe",
+                    value: list_unsubscribe,
+                },
+                EmailHeader {
+                    name: "List-Unsubscribe-Post",
+                    value: "List-Unsubscribe=One-Click",
+                },
+            ],
+            None => vec![],
+        };
+        self.send(recipient, subject, html_content, text_content, &headers)
+            .await
+    }
+
+    #[tracing::instrument(name = "Call the email API", skip_all)]
+    async fn send(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: Option<&str>,
+        text_content: &str,
+        headers: &[EmailHeader<'_>],
     ) -> Result<(), reqwest::Error> {
         let url = format!("{}/email", self.base_url);
         let request_body = SendEmailRequest {
@@ -38,18 +82,29 @@ impl EmailClient {
             subject,
             html_body: html_content,
             text_body: text_content,
+            headers,
         };
 
-        self.http_client
+        let response = self
+            .http_client
             .post(&url)
             .header(
                 "X-Postmark-Server-Token",
                 self.authorization_token.expose_secret(),
             )
+            .headers(trace_context_headers())
             .json(&request_body)
             .send()
-            .await?
-            .error_for_status()?;
+            .await;
+        let status = match &response {
+            Ok(response) => response.status().as_str().to_owned(),
+            Err(_) => "error".to_owned(),
+        };
+        METRICS
+            .email_api_responses
+            .with_label_values(&[&status])
+            .inc();
+        response?.error_for_status()?;
         Ok(())
     }
 }
@@ -60,8 +115,19 @@ pub struct SendEmailRequest<'a> {
     from: &'a str,
     to: &'a str,
     subject: &'a str,
-    html_body: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    html_body: Option<&'a str>,
     text_body: &'a str,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    headers: &'a [EmailHeader<'a>],
+}
+
+/// A header for Postmark to add to the message
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmailHeader<'a> {
+    name: &'a str,
+    value: &'a str,
 }
 
 #[cfg(test)]
@@ -168,6 +234,27 @@ mod test {
         assert_err!(outcome);
     }
 
+    #[tokio::test]
+    async fn send_issue_without_html_leaves_out_the_html_body() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client(mock_server.uri())
+            .send_issue(&email(), &subject(), None, &content(), None)
+            .await;
+
+        assert_ok!(outcome);
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert!(body.get("HtmlBody").is_none());
+        assert!(body.get("TextBody").is_some());
+    }
+
     fn subject() -> String {
         Sentence(1..2).fake()
     }
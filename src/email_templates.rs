@@ -0,0 +1,571 @@
+use crate::data_requests::DataRequestAction;
+use crate::error::error_chain_fmt;
+use crate::html::{sanitize_newsletter_html, SanitizedHtml};
+use crate::tracking::RecipientTracking;
+use tera::{Context, Tera};
+
+/// Layout and transactional email templates, embedded in the binary so that the
+/// runtime image does not need to ship the `templates` directory.
+const TEMPLATES: [(&str, &str); 16] = [
+    (
+        "email/layout.html",
+        include_str!("../templates/email/layout.html"),
+    ),
+    (
+        "email/header.html",
+        include_str!("../templates/email/header.html"),
+    ),
+    (
+        "email/footer.html",
+        include_str!("../templates/email/footer.html"),
+    ),
+    (
+        "email/unsubscribe.html",
+        include_str!("../templates/email/unsubscribe.html"),
+    ),
+    (
+        "email/layout.txt",
+        include_str!("../templates/email/layout.txt"),
+    ),
+    (
+        "email/header.txt",
+        include_str!("../templates/email/header.txt"),
+    ),
+    (
+        "email/footer.txt",
+        include_str!("../templates/email/footer.txt"),
+    ),
+    (
+        "email/unsubscribe.txt",
+        include_str!("../templates/email/unsubscribe.txt"),
+    ),
+    (
+        "email/digest.html",
+        include_str!("../templates/email/digest.html"),
+    ),
+    (
+        "email/digest.txt",
+        include_str!("../templates/email/digest.txt"),
+    ),
+    (
+        "email/confirmation.html",
+        include_str!("../templates/email/confirmation.html"),
+    ),
+    (
+        "email/confirmation.txt",
+        include_str!("../templates/email/confirmation.txt"),
+    ),
+    (
+        "email/data_request.html",
+        include_str!("../templates/email/data_request.html"),
+    ),
+    (
+        "email/data_request.txt",
+        include_str!("../templates/email/data_request.txt"),
+    ),
+    (
+        "email/email_change.html",
+        include_str!("../templates/email/email_change.html"),
+    ),
+    (
+        "email/email_change.txt",
+        include_str!("../templates/email/email_change.txt"),
+    ),
+];
+
+pub struct EmailTemplates {
+    tera: Tera,
+    base_url: String,
+}
+
+pub struct RenderedEmail {
+    pub html: String,
+    pub text: String,
+}
+
+/// A newsletter issue as stored by the editor, before it is personalized
+pub struct Issue<'a> {
+    pub title: &'a str,
+    pub slug: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+}
+
+/// Everything we know about a subscriber that an issue template can refer to
+pub struct Recipient<'a> {
+    pub name: &'a str,
+    pub email: &'a str,
+    pub subscription_token: Option<&'a str>,
+    /// Set when the issue was published with open and click tracking enabled
+    pub tracking: Option<RecipientTracking<'a>>,
+}
+
+/// An issue held back for a weekly or monthly subscriber, tracked on its own within the digest
+pub struct DigestIssue<'a> {
+    pub issue: Issue<'a>,
+    pub tracking: Option<RecipientTracking<'a>>,
+}
+
+#[derive(serde::Serialize)]
+struct RenderedDigestIssue<'a> {
+    title: &'a str,
+    web_url: String,
+    content: String,
+    tracking_pixel_url: Option<String>,
+}
+
+impl Recipient<'static> {
+    /// Stand-in recipient used to check that an issue renders before we fan it out
+    fn sample() -> Self {
+        Self {
+            name: "Subscriber",
+            email: "subscriber@example.com",
+            subscription_token: None,
+            tracking: None,
+        }
+    }
+
+    /// Anonymous reader of the public archive - we know nothing about them
+    fn web_reader() -> Self {
+        Self {
+            name: "Subscriber",
+            email: "",
+            subscription_token: None,
+            tracking: None,
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum TemplateError {
+    #[error("{0}")]
+    InvalidTemplate(String),
+    #[error("Failed to render email template")]
+    RenderError(#[source] tera::Error),
+}
+
+impl std::fmt::Debug for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl EmailTemplates {
+    pub fn new(base_url: String) -> Result<Self, tera::Error> {
+        let mut tera = Tera::default();
+        tera.add_raw_templates(TEMPLATES)?;
+        Ok(Self { tera, base_url })
+    }
+
+    /// Render an issue with a sample recipient, so that a broken template is rejected
+    /// at publish time instead of failing for every subscriber during delivery.
+    /// Returns the elements that are not allowed, and will be removed from every copy of the issue.
+    pub fn validate_issue(&self, issue: &Issue) -> Result<Vec<String>, TemplateError> {
+        let sample = Recipient::sample();
+        self.render_issue(issue, &sample)
+            .and_then(|_| issue_html(issue, &recipient_context(&sample)))
+            .map(|sanitized| sanitized.removed_elements)
+            .map_err(|e| match e {
+                TemplateError::RenderError(e) => TemplateError::InvalidTemplate(describe(&e)),
+                e => e,
+            })
+    }
+
+    pub fn render_issue(
+        &self,
+        issue: &Issue,
+        recipient: &Recipient,
+    ) -> Result<RenderedEmail, TemplateError> {
+        let mut context = recipient_context(recipient);
+        let (html_body, text_body) = issue_bodies(issue, &context, recipient.tracking.as_ref())?;
+
+        context.insert("title", issue.title);
+        context.insert("web_url", &self.issue_url(issue.slug));
+        context.insert(
+            "tracking_pixel_url",
+            &recipient.tracking.as_ref().map(|t| t.pixel_url()),
+        );
+        self.insert_subscription_links(&mut context, recipient.subscription_token);
+
+        context.insert("content", &html_body);
+        let html = self.render("email/layout.html", &context)?;
+        context.insert("content", &text_body);
+        let text = self.render("email/layout.txt", &context)?;
+        Ok(RenderedEmail { html, text })
+    }
+
+    /// Several issues in a single email, for subscribers who asked to hear from us weekly or
+    /// monthly. The tracking of `recipient` is ignored: each issue comes with its own.
+    pub fn render_digest(
+        &self,
+        title: &str,
+        issues: &[DigestIssue],
+        recipient: &Recipient,
+    ) -> Result<RenderedEmail, TemplateError> {
+        let mut context = recipient_context(recipient);
+        let mut html_issues = Vec::with_capacity(issues.len());
+        let mut text_issues = Vec::with_capacity(issues.len());
+        for DigestIssue { issue, tracking } in issues {
+            let (html_body, text_body) = issue_bodies(issue, &context, tracking.as_ref())?;
+            let web_url = self.issue_url(issue.slug);
+            text_issues.push(RenderedDigestIssue {
+                title: issue.title,
+                web_url: web_url.clone(),
+                content: text_body,
+                tracking_pixel_url: None,
+            });
+            html_issues.push(RenderedDigestIssue {
+                title: issue.title,
+                web_url,
+                content: html_body,
+                tracking_pixel_url: tracking.as_ref().map(|t| t.pixel_url()),
+            });
+        }
+
+        context.insert("title", title);
+        self.insert_subscription_links(&mut context, recipient.subscription_token);
+
+        context.insert("issues", &html_issues);
+        let html = self.render("email/digest.html", &context)?;
+        context.insert("issues", &text_issues);
+        let text = self.render("email/digest.txt", &context)?;
+        Ok(RenderedEmail { html, text })
+    }
+
+    fn insert_subscription_links(&self, context: &mut Context, subscription_token: Option<&str>) {
+        context.insert(
+            "unsubscribe_url",
+            &subscription_token.map(|token| self.unsubscribe_url(token)),
+        );
+        context.insert(
+            "preferences_url",
+            &subscription_token.map(|token| {
+                format!(
+                    "{}/subscriptions/preferences?subscription_token={}",
+                    self.base_url, token
+                )
+            }),
+        );
+    }
+
+    /// The HTML content of an issue as shown in the public archive, without the email layout
+    pub fn render_archived_issue(&self, issue: &Issue) -> Result<String, TemplateError> {
+        issue_html(issue, &recipient_context(&Recipient::web_reader()))
+            .map(|sanitized| sanitized.html)
+    }
+
+    /// Where an issue can be read in a browser
+    pub fn issue_url(&self, slug: &str) -> String {
+        format!("{}/issues/{}", self.base_url, slug)
+    }
+
+    /// Shows a confirmation page on GET, and unsubscribes right away on POST
+    pub fn unsubscribe_url(&self, subscription_token: &str) -> String {
+        format!(
+            "{}/subscriptions/unsubscribe?subscription_token={}",
+            self.base_url, subscription_token
+        )
+    }
+
+    pub fn render_confirmation(
+        &self,
+        name: &str,
+        confirmation_link: &str,
+    ) -> Result<RenderedEmail, TemplateError> {
+        let mut context = Context::new();
+        context.insert("name", name);
+        context.insert("confirmation_link", confirmation_link);
+        Ok(RenderedEmail {
+            html: self.render("email/confirmation.html", &context)?,
+            text: self.render("email/confirmation.txt", &context)?,
+        })
+    }
+
+    /// Sent to the new address when a subscriber changes their email
+    pub fn render_email_change(
+        &self,
+        name: &str,
+        confirmation_link: &str,
+    ) -> Result<RenderedEmail, TemplateError> {
+        let mut context = Context::new();
+        context.insert("name", name);
+        context.insert("confirmation_link", confirmation_link);
+        Ok(RenderedEmail {
+            html: self.render("email/email_change.html", &context)?,
+            text: self.render("email/email_change.txt", &context)?,
+        })
+    }
+
+    /// The email carrying the link that lets somebody export or erase their data
+    pub fn render_data_request(
+        &self,
+        action: DataRequestAction,
+        link: &str,
+    ) -> Result<RenderedEmail, TemplateError> {
+        let mut context = Context::new();
+        context.insert("action", action.as_str());
+        context.insert("link", link);
+        Ok(RenderedEmail {
+            html: self.render("email/data_request.html", &context)?,
+            text: self.render("email/data_request.txt", &context)?,
+        })
+    }
+
+    fn render(&self, template_name: &str, context: &Context) -> Result<String, TemplateError> {
+        self.tera
+            .render(template_name, context)
+            .map_err(TemplateError::RenderError)
+    }
+}
+
+/// The HTML content of an issue, personalized and then sanitized: editors write Tera templates,
+/// and their syntax does not survive an HTML sanitizer
+fn issue_html(issue: &Issue, context: &Context) -> Result<SanitizedHtml, TemplateError> {
+    let html =
+        Tera::one_off(issue.html_content, context, true).map_err(TemplateError::RenderError)?;
+    Ok(sanitize_newsletter_html(&html))
+}
+
+/// The HTML and text content of an issue for one recipient, with its links tracked if asked to
+fn issue_bodies(
+    issue: &Issue,
+    context: &Context,
+    tracking: Option<&RecipientTracking>,
+) -> Result<(String, String), TemplateError> {
+    let mut html_body = issue_html(issue, context)?.html;
+    if let Some(tracking) = tracking {
+        html_body = tracking.rewrite_links(&html_body);
+    }
+    let text_body =
+        Tera::one_off(issue.text_content, context, false).map_err(TemplateError::RenderError)?;
+    Ok((html_body, text_body))
+}
+
+fn recipient_context(recipient: &Recipient) -> Context {
+    let mut context = Context::new();
+    context.insert("name", recipient.name);
+    context.insert("email", recipient.email);
+    context
+}
+
+// `tera` puts the useful part of the message (line, column, missing variable) in the source chain
+fn describe(e: &tera::Error) -> String {
+    let mut message = e.to_string();
+    let mut current = std::error::Error::source(e);
+    while let Some(cause) = current {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        current = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DigestIssue, EmailTemplates, Issue, Recipient, TemplateError};
+    use crate::tracking::Tracking;
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn templates() -> EmailTemplates {
+        EmailTemplates::new("http://127.0.0.1".into()).unwrap()
+    }
+
+    fn issue<'a>(html_content: &'a str, text_content: &'a str) -> Issue<'a> {
+        Issue {
+            title: "Title",
+            slug: "title-3fa85f64",
+            html_content,
+            text_content,
+        }
+    }
+
+    fn recipient(name: &str) -> Recipient<'_> {
+        Recipient {
+            name,
+            email: "ursula@example.com",
+            subscription_token: Some("a-token"),
+            tracking: None,
+        }
+    }
+
+    #[test]
+    fn issue_is_personalized_for_the_recipient() {
+        let email = templates()
+            .render_issue(
+                &issue("<p>Hi {{ name }}</p>", "Hi {{ name }}"),
+                &recipient("Ursula"),
+            )
+            .unwrap();
+        assert!(email.html.contains("<p>Hi Ursula</p>"));
+        assert!(email.text.contains("Hi Ursula"));
+    }
+
+    #[test]
+    fn recipient_name_is_escaped_in_html_body() {
+        let email = templates()
+            .render_issue(
+                &issue("{{ name }}", "{{ name }}"),
+                &recipient("Tom & Jerry"),
+            )
+            .unwrap();
+        assert!(email.html.contains("Tom &amp; Jerry"));
+        assert!(email.text.contains("Tom & Jerry"));
+    }
+
+    #[test]
+    fn layout_includes_unsubscribe_link() {
+        let email = templates()
+            .render_issue(&issue("body", "body"), &recipient("Ursula"))
+            .unwrap();
+        let link = "http://127.0.0.1/subscriptions/unsubscribe?subscription_token=a-token";
+        assert!(email.html.contains(link));
+        assert!(email.text.contains(link));
+    }
+
+    #[test]
+    fn layout_includes_preferences_link() {
+        let email = templates()
+            .render_issue(&issue("body", "body"), &recipient("Ursula"))
+            .unwrap();
+        let link = "http://127.0.0.1/subscriptions/preferences?subscription_token=a-token";
+        assert!(email.html.contains(link));
+        assert!(email.text.contains(link));
+    }
+
+    #[test]
+    fn layout_includes_view_in_browser_link() {
+        let email = templates()
+            .render_issue(&issue("body", "body"), &recipient("Ursula"))
+            .unwrap();
+        let link = "http://127.0.0.1/issues/title-3fa85f64";
+        assert!(email.html.contains(link));
+        assert!(email.text.contains(link));
+    }
+
+    #[test]
+    fn tracking_rewrites_content_links_and_adds_a_pixel() {
+        let tracking = Tracking::new("http://127.0.0.1".into(), Secret::new("secret".into()));
+        let recipient = Recipient {
+            tracking: Some(tracking.for_recipient(Uuid::new_v4(), Uuid::new_v4())),
+            ..recipient("Ursula")
+        };
+        let email = templates()
+            .render_issue(
+                &issue(r#"<a href="https://example.com">read more</a>"#, "body"),
+                &recipient,
+            )
+            .unwrap();
+        assert!(!email.html.contains("https://example.com"));
+        assert!(email.html.contains(r#"<a href="http://127.0.0.1/t/c/"#));
+        assert!(email.html.contains(r#"<img src="http://127.0.0.1/t/o/"#));
+        // Links of the layout itself are left alone
+        assert!(email
+            .html
+            .contains("http://127.0.0.1/subscriptions/unsubscribe?subscription_token=a-token"));
+    }
+
+    #[test]
+    fn no_tracking_without_a_tracker() {
+        let email = templates()
+            .render_issue(
+                &issue(r#"<a href="https://example.com">read more</a>"#, "body"),
+                &recipient("Ursula"),
+            )
+            .unwrap();
+        assert!(email
+            .html
+            .contains(r#"<a href="https://example.com" rel="noopener noreferrer">"#));
+        assert!(!email.html.contains("/t/o/"));
+    }
+
+    #[test]
+    fn digests_carry_every_issue_with_its_own_tracking() {
+        let tracking = Tracking::new("http://127.0.0.1".into(), Secret::new("secret".into()));
+        let subscriber_id = Uuid::new_v4();
+        let issues = [
+            DigestIssue {
+                issue: Issue {
+                    title: "First",
+                    slug: "first-3fa85f64",
+                    html_content: r#"<p>Hi {{ name }}</p><a href="https://example.com">more</a>"#,
+                    text_content: "First for {{ name }}",
+                },
+                tracking: Some(tracking.for_recipient(Uuid::new_v4(), subscriber_id)),
+            },
+            DigestIssue {
+                issue: Issue {
+                    title: "Second",
+                    slug: "second-3fa85f64",
+                    html_content: "<p>Second</p>",
+                    text_content: "Second",
+                },
+                tracking: None,
+            },
+        ];
+
+        let email = templates()
+            .render_digest("Your weekly digest", &issues, &recipient("Ursula"))
+            .unwrap();
+
+        assert!(email.html.contains("<p>Hi Ursula</p>"));
+        assert!(email.html.contains("<p>Second</p>"));
+        assert!(email
+            .html
+            .contains("http://127.0.0.1/issues/first-3fa85f64"));
+        assert!(email
+            .html
+            .contains("http://127.0.0.1/issues/second-3fa85f64"));
+        assert!(!email.html.contains("https://example.com"));
+        assert_eq!(
+            email
+                .html
+                .matches(r#"<img src="http://127.0.0.1/t/o/"#)
+                .count(),
+            1
+        );
+        assert!(email.text.contains("First for Ursula"));
+        assert!(email.text.contains("Second"));
+        assert!(email
+            .text
+            .contains("http://127.0.0.1/subscriptions/unsubscribe?subscription_token=a-token"));
+    }
+
+    #[test]
+    fn archived_issue_is_rendered_for_an_anonymous_reader() {
+        let html = templates()
+            .render_archived_issue(&issue("<p>Hi {{ name }}</p>", "Hi {{ name }}"))
+            .unwrap();
+        assert_eq!(html, "<p>Hi Subscriber</p>");
+    }
+
+    #[test]
+    fn unknown_variables_are_rejected() {
+        let outcome = templates().validate_issue(&issue("Hi {{ nickname }}", "Hi"));
+        assert!(matches!(outcome, Err(TemplateError::InvalidTemplate(_))));
+    }
+
+    #[test]
+    fn malformed_templates_are_rejected() {
+        assert_err!(templates().validate_issue(&issue("<p>ok</p>", "Hi {{ name")));
+    }
+
+    #[test]
+    fn templates_are_sanitized_once_rendered() {
+        let issue = issue(
+            r#"<p>{% if 1 < 2 %}Hi {{ name | default(value="<b>you</b>") }}{% endif %}</p><script>x</script>"#,
+            "Hi",
+        );
+        assert_eq!(templates().validate_issue(&issue).unwrap(), vec!["script"]);
+        assert_eq!(
+            templates().render_archived_issue(&issue).unwrap(),
+            "<p>Hi Subscriber</p>"
+        );
+    }
+
+    #[test]
+    fn valid_templates_are_accepted() {
+        assert_ok!(templates().validate_issue(&issue("<p>Hi {{ name }}</p>", "Hi {{ name }}")));
+    }
+}
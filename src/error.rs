@@ -0,0 +1,278 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{
+    InternalError, JsonPayloadError, PathError, QueryPayloadError, UrlencodedError,
+};
+use actix_web::http::header::{self, Accept, Header, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use actix_web_lab::middleware::Next;
+use askama::Template;
+use std::collections::BTreeMap;
+use tracing_actix_web::RequestId;
+
+/// Everything a request can fail with, and what the client gets to know about it.
+/// The message shown to clients is the `Display` representation: on 5xx it never carries
+/// the cause, which is left to `Debug` for our logs.
+#[derive(thiserror::Error)]
+pub enum AppError {
+    #[error("{0}")]
+    Validation(FieldErrors),
+    #[error("{0}")]
+    BadRequest(String),
+    /// With the challenge to send back in `WWW-Authenticate`, if any
+    #[error("Authentication failed")]
+    Unauthorized(Option<&'static str>),
+    #[error("You are not allowed to do that")]
+    Forbidden,
+    #[error("Not found")]
+    NotFound,
+    #[error("{0}")]
+    Conflict(String),
+    #[error("Too many requests, please try again later.")]
+    RateLimited,
+    #[error("Something went wrong, please try again later.")]
+    Unexpected(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl AppError {
+    /// A stable identifier for clients to act upon, unlike the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Validation(_) => "validation_failed",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden => "forbidden",
+            AppError::NotFound => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::RateLimited => "rate_limited",
+            AppError::Unexpected(_) => "internal_error",
+        }
+    }
+
+    fn body(&self, request_id: Option<String>) -> ErrorBody<'_> {
+        ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            fields: match self {
+                AppError::Validation(errors) => Some(errors),
+                _ => None,
+            },
+            request_id,
+        }
+    }
+
+    /// The content type and body of the error, or `None` if we failed to render it
+    fn render(&self, html: bool, request_id: Option<String>) -> Option<(&'static str, String)> {
+        let body = self.body(request_id);
+        if html {
+            let page = ErrorTemplate {
+                flash_messages: Vec::new(),
+                status: self.status_code(),
+                body,
+            }
+            .render();
+            match page {
+                Ok(page) => Some(("text/html; charset=utf-8", page)),
+                Err(e) => {
+                    tracing::error!(error.cause_chain = ?e, "Failed to render an error page");
+                    None
+                }
+            }
+        } else {
+            serde_json::to_string(&body)
+                .ok()
+                .map(|json| ("application/json", json))
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Validation(_) | AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unexpected(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // `render_errors` swaps the body for HTML if the client prefers it, and adds the request id
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::Unauthorized(Some(challenge)) = self {
+            response.insert_header((header::WWW_AUTHENTICATE, *challenge));
+        }
+        response.json(self.body(None))
+    }
+}
+
+/// Validation errors, keyed by the name of the field they are about
+#[derive(Debug, Default, serde::Serialize)]
+pub struct FieldErrors(BTreeMap<&'static str, String>);
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &'static str, message: String) {
+        self.0.insert(field, message);
+    }
+}
+
+impl std::fmt::Display for FieldErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let messages: Vec<&str> = self.0.values().map(String::as_str).collect();
+        write!(f, "{}", messages.join(" "))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ErrorBody<'a> {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<&'a FieldErrors>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+struct ErrorTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    status: StatusCode,
+    body: ErrorBody<'a>,
+}
+
+/// Render `AppError`s the way the client asked for, with the id of the request
+/// so that whoever reports a problem can point us to the relevant logs
+pub async fn render_errors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let html = prefers_html(req.request());
+    let request_id = req.extensions().get::<RequestId>().map(ToString::to_string);
+    match next.call(req).await {
+        Ok(response) => {
+            let (request, response) = response.into_parts();
+            let rendered = response
+                .error()
+                .and_then(|e| e.as_error::<AppError>())
+                .and_then(|e| e.render(html, request_id));
+            let response = match rendered {
+                Some(rendered) => with_body(response, rendered),
+                None => response.map_into_boxed_body(),
+            };
+            Ok(ServiceResponse::new(request, response))
+        }
+        // Middlewares fail with an error rather than with a response
+        Err(e) => match e.as_error::<AppError>() {
+            Some(error) => match error.render(html, request_id) {
+                Some(rendered) => {
+                    let response = with_body(error.error_response(), rendered);
+                    // Wrapped, the original error is still the one `TracingLogger` logs
+                    Err(InternalError::from_response(e, response).into())
+                }
+                None => Err(e),
+            },
+            None => Err(e),
+        },
+    }
+}
+
+/// Extractors fail with their own errors, rendered as plain text: these turn them into `AppError`s.
+/// Register them with `JsonConfig`, `FormConfig` and `QueryConfig`.
+pub fn json_error(error: JsonPayloadError, _request: &HttpRequest) -> actix_web::Error {
+    AppError::BadRequest(error.to_string()).into()
+}
+
+pub fn form_error(error: UrlencodedError, _request: &HttpRequest) -> actix_web::Error {
+    AppError::BadRequest(error.to_string()).into()
+}
+
+pub fn query_error(error: QueryPayloadError, _request: &HttpRequest) -> actix_web::Error {
+    AppError::BadRequest(error.to_string()).into()
+}
+
+/// A path that matches a route, but not the types of its segments, is not one of our pages
+pub fn path_error(_error: PathError, _request: &HttpRequest) -> actix_web::Error {
+    AppError::NotFound.into()
+}
+
+/// For requests that match none of our routes
+pub async fn not_found() -> Result<HttpResponse, AppError> {
+    Err(AppError::NotFound)
+}
+
+// Setting the body keeps the error attached to the response, for `TracingLogger` to log it
+fn with_body<B>(
+    response: HttpResponse<B>,
+    (content_type, body): (&'static str, String),
+) -> HttpResponse {
+    let mut response = response.set_body(BoxBody::new(body));
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+/// Browsers get a page, everybody else gets JSON
+fn prefers_html(request: &HttpRequest) -> bool {
+    let Ok(accept) = Accept::parse(request) else {
+        return false;
+    };
+    accept
+        .ranked()
+        .into_iter()
+        .find(|mime| matches!(mime.essence_str(), "text/html" | "application/json"))
+        .is_some_and(|mime| mime.essence_str() == "text/html")
+}
+
+pub fn error_chain_fmt(
+    e: &impl std::error::Error,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    writeln!(f, "{}\n", e)?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{}", cause)?;
+        current = cause.source();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::prefers_html;
+    use actix_web::test::TestRequest;
+
+    fn accepting(accept: &str) -> bool {
+        prefers_html(
+            &TestRequest::default()
+                .insert_header(("Accept", accept))
+                .to_http_request(),
+        )
+    }
+
+    #[test]
+    fn browsers_get_html() {
+        assert!(accepting(
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+        ));
+    }
+
+    #[test]
+    fn everybody_else_gets_json() {
+        assert!(!prefers_html(&TestRequest::default().to_http_request()));
+        assert!(!accepting("*/*"));
+        assert!(!accepting("application/json"));
+        assert!(!accepting("text/html;q=0.5, application/json"));
+    }
+}
@@ -0,0 +1,259 @@
+use crate::utils::e500;
+use actix_web::{http::header::ContentType, HttpResponse};
+use actix_web_flash_messages::{IncomingFlashMessages, Level};
+use askama::Template;
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::states::RawKind;
+use html5ever::tokenizer::{
+    BufferQueue, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+};
+use std::collections::{BTreeSet, HashSet};
+
+/// Tags an editor is allowed to use in the HTML content of a newsletter issue.
+/// Anything else is stripped on publish and reported back to the editor.
+const ALLOWED_TAGS: [&str; 47] = [
+    "a",
+    "abbr",
+    "b",
+    "blockquote",
+    "br",
+    "caption",
+    "center",
+    "cite",
+    "code",
+    "col",
+    "colgroup",
+    "dd",
+    "del",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "figcaption",
+    "figure",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "s",
+    "small",
+    "span",
+    "strong",
+    "sub",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "tr",
+    "u",
+    "ul",
+];
+
+/// Tags whose content is dropped along with the tag itself, rather than kept as text
+const CLEAN_CONTENT_TAGS: [&str; 2] = ["script", "style"];
+
+/// Render a page template into a response.
+/// Templates escape every interpolated value unless it is explicitly marked as `safe`.
+pub fn render(template: &impl Template) -> Result<HttpResponse, actix_web::Error> {
+    let body = template.render().map_err(e500)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(body))
+}
+
+/// The content of the flash messages to show on a page, for `partials/flash.html`
+pub fn flash_messages(incoming: &IncomingFlashMessages) -> Vec<&str> {
+    incoming
+        .iter()
+        .filter(|m| m.level() >= Level::Info)
+        .map(|m| m.content())
+        .collect()
+}
+
+pub struct SanitizedHtml {
+    pub html: String,
+    /// Names of the elements that were not on the allow-list, deduplicated and sorted
+    pub removed_elements: Vec<String>,
+}
+
+/// Strip everything that is not on our allow-list from the HTML of a newsletter issue.
+/// It runs on the rendered issue: the template syntax is not HTML, and must reach Tera untouched.
+pub fn sanitize_newsletter_html(input: &str) -> SanitizedHtml {
+    let allowed: HashSet<&str> = ALLOWED_TAGS.into_iter().collect();
+    let html = ammonia::Builder::default()
+        .tags(allowed.clone())
+        .clean_content_tags(CLEAN_CONTENT_TAGS.into_iter().collect())
+        .url_schemes(["http", "https", "mailto"].into_iter().collect())
+        .link_rel(Some("noopener noreferrer"))
+        .clean(input)
+        .to_string();
+    let removed_elements = element_names(input)
+        .into_iter()
+        .filter(|name| !allowed.contains(name.as_str()))
+        .collect();
+    SanitizedHtml {
+        html,
+        removed_elements,
+    }
+}
+
+/// Strip everything that is not on our allow-list from an issue as written, i.e. a Tera template.
+/// Tera tags are set aside while the markup around them is sanitized, then put back untouched:
+/// what they render to is sanitized again by `sanitize_newsletter_html` once rendered.
+pub fn sanitize_newsletter_template(input: &str) -> SanitizedHtml {
+    let mut tera_tags = Vec::new();
+    let mut masked = String::with_capacity(input.len());
+    // Our placeholders are made of these: an editor's copy of them would be mistaken for one
+    let input = input.replace([PLACEHOLDER_START, PLACEHOLDER_END], "");
+    let mut rest = input.as_str();
+    while let Some(start) = ["{{", "{%", "{#"]
+        .into_iter()
+        .filter_map(|opening| rest.find(opening))
+        .min()
+    {
+        let closing = match &rest[start..start + 2] {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        let Some(length) = rest[start + 2..].find(closing) else {
+            // Tera rejects an unterminated tag: no need to protect it
+            break;
+        };
+        let end = start + 2 + length + 2;
+        masked.push_str(&rest[..start]);
+        masked.push(PLACEHOLDER_START);
+        masked.push_str(&tera_tags.len().to_string());
+        masked.push(PLACEHOLDER_END);
+        tera_tags.push(rest[start..end].to_owned());
+        rest = &rest[end..];
+    }
+    masked.push_str(rest);
+
+    let sanitized = sanitize_newsletter_html(&masked);
+    let mut html = String::with_capacity(sanitized.html.len());
+    let mut rest = sanitized.html.as_str();
+    while let Some(start) = rest.find(PLACEHOLDER_START) {
+        html.push_str(&rest[..start]);
+        rest = &rest[start + PLACEHOLDER_START.len_utf8()..];
+        let end = rest.find(PLACEHOLDER_END).unwrap_or(rest.len());
+        if let Some(tag) = rest[..end]
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| tera_tags.get(i))
+        {
+            html.push_str(tag);
+        }
+        rest = &rest[(end + PLACEHOLDER_END.len_utf8()).min(rest.len())..];
+    }
+    html.push_str(rest);
+    SanitizedHtml {
+        html,
+        removed_elements: sanitized.removed_elements,
+    }
+}
+
+/// Characters from the Private Use Area, which stand in for Tera tags while we sanitize a template
+const PLACEHOLDER_START: char = '\u{E000}';
+const PLACEHOLDER_END: char = '\u{E001}';
+
+/// The names of the elements in `input`, as the HTML tokenizer `ammonia` relies on sees them:
+/// comments, attribute values and the content of `<script>` are not mistaken for markup.
+fn element_names(input: &str) -> BTreeSet<String> {
+    let mut tokenizer = Tokenizer::new(StartTags::default(), TokenizerOpts::default());
+    let mut queue = BufferQueue::new();
+    queue.push_back(StrTendril::from_slice(input));
+    let _ = tokenizer.feed(&mut queue);
+    tokenizer.end();
+    tokenizer.sink.0
+}
+
+#[derive(Default)]
+struct StartTags(BTreeSet<String>);
+
+impl TokenSink for StartTags {
+    type Handle = ();
+
+    fn process_token(&mut self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        let Token::TagToken(tag) = token else {
+            return TokenSinkResult::Continue;
+        };
+        if tag.kind != TagKind::StartTag {
+            return TokenSinkResult::Continue;
+        }
+        self.0.insert(tag.name.to_string());
+        // Without a tree builder, we have to tell the tokenizer which elements hold text only
+        match &*tag.name {
+            "script" => TokenSinkResult::RawData(RawKind::ScriptData),
+            "style" | "xmp" | "iframe" | "noembed" | "noframes" | "noscript" => {
+                TokenSinkResult::RawData(RawKind::Rawtext)
+            }
+            "textarea" | "title" => TokenSinkResult::RawData(RawKind::Rcdata),
+            "plaintext" => TokenSinkResult::Plaintext,
+            _ => TokenSinkResult::Continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sanitize_newsletter_html, sanitize_newsletter_template};
+
+    #[test]
+    fn allowed_markup_is_preserved() {
+        let input = r#"<h1>Title</h1><p>Hi <strong>Ursula</strong>, <a href="https://example.com">read more</a></p>"#;
+        let sanitized = sanitize_newsletter_html(input);
+        assert!(sanitized.removed_elements.is_empty());
+        assert!(sanitized.html.contains("<strong>Ursula</strong>"));
+        assert!(sanitized.html.contains(r#"href="https://example.com""#));
+    }
+
+    #[test]
+    fn disallowed_elements_are_removed_and_reported() {
+        let input = r#"<p>Hello</p><script>alert(1)</script><iframe src="https://evil.example"></iframe><SCRIPT>x</SCRIPT>"#;
+        let sanitized = sanitize_newsletter_html(input);
+        assert_eq!(sanitized.removed_elements, vec!["iframe", "script"]);
+        assert!(!sanitized.html.contains("script"));
+        assert!(!sanitized.html.contains("alert"));
+        assert!(!sanitized.html.contains("iframe"));
+        assert!(sanitized.html.contains("<p>Hello</p>"));
+    }
+
+    #[test]
+    fn only_actual_elements_are_reported() {
+        let input = r#"<p title="<iframe>">1 <2, a<3</p><!-- <form> --><script>document.write("<object>")</script>"#;
+        let sanitized = sanitize_newsletter_html(input);
+        assert_eq!(sanitized.removed_elements, vec!["script"]);
+    }
+
+    #[test]
+    fn template_syntax_survives_the_sanitizer() {
+        let input = r#"<p>{% if 1 < 2 %}Hi {{ name | default(value="you") }}{% endif %}</p><a href="{{ web_url }}">Read online</a>{# "<note>" #}<script>{{ name }}</script>"#;
+        let sanitized = sanitize_newsletter_template(input);
+        assert_eq!(sanitized.removed_elements, vec!["script"]);
+        assert_eq!(
+            sanitized.html,
+            r#"<p>{% if 1 < 2 %}Hi {{ name | default(value="you") }}{% endif %}</p><a href="{{ web_url }}" rel="noopener noreferrer">Read online</a>{# "<note>" #}"#
+        );
+    }
+
+    #[test]
+    fn event_handlers_and_javascript_urls_are_stripped() {
+        let input = r#"<p onclick="alert(1)">Hi</p><a href="javascript:alert(1)">x</a>"#;
+        let sanitized = sanitize_newsletter_html(input);
+        assert!(!sanitized.html.contains("onclick"));
+        assert!(!sanitized.html.contains("javascript"));
+    }
+}
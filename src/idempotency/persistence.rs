@@ -2,6 +2,7 @@ use super::IdempotencyKey;
 use actix_web::body::to_bytes;
 use actix_web::http::StatusCode;
 use actix_web::HttpResponse;
+use chrono::{DateTime, Utc};
 use sqlx::postgres::PgHasArrayType;
 use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
@@ -100,6 +101,7 @@ impl PgHasArrayType for HeaderPairRecord {
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum NextAction {
     // Return tranasaction for later processing
     StartProcessing(Transaction<'static, Postgres>),
@@ -137,3 +139,16 @@ pub async fn try_processing(
         Ok(NextAction::ReturnSavedResponse(saved_response))
     }
 }
+
+/// Forget the responses saved before `cutoff`: retrying a request that old
+/// runs it again instead of replaying the response
+pub async fn purge_saved_responses(
+    pool: &PgPool,
+    cutoff: DateTime<Utc>,
+) -> Result<u64, anyhow::Error> {
+    let purged = sqlx::query!("DELETE FROM idempotency WHERE created_at < $1", cutoff)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(purged)
+}
@@ -1,9 +1,25 @@
 use crate::{
-    configuration::Settings, domain::SubscriberEmail, email_client::EmailClient,
-    startup::get_connection_pool,
+    configuration::Settings,
+    domain::{DeliveryFrequency, EmailFormat, SubscriberEmail},
+    email_client::EmailClient,
+    email_templates::{
+        DigestIssue, EmailTemplates, Issue, Recipient, RenderedEmail, TemplateError,
+    },
+    metrics::{metrics_server, METRICS},
+    signup_protection::purge_signup_attempts,
+    startup::{get_connection_pool_of_size, MAX_DB_CONNECTIONS},
+    suppression::is_suppressed,
+    tracking::{RecipientTracking, Tracking},
 };
-use sqlx::{PgPool, Postgres, Transaction};
-use std::time::Duration;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use futures_util::future::join_all;
+use secrecy::Secret;
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+use std::net::TcpListener;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 use tracing::{field::display, Span};
 use uuid::Uuid;
 
@@ -13,92 +29,339 @@ pub enum ExecutionOutcome {
     EmptyQueue,
 }
 
-#[tracing::instrument(skip_all, fields(newsletter_issue_id=tracing::field::Empty,subscriber_email=tracing::field::Empty), err)]
+#[tracing::instrument(skip_all, fields(newsletter_issue_id=tracing::field::Empty,subscriber_email=tracing::field::Empty,request_id=tracing::field::Empty,digest_size=tracing::field::Empty), err)]
 pub async fn try_execute_task(
     pool: &PgPool,
     email_client: &EmailClient,
+    templates: &EmailTemplates,
+    tracking: &Tracking,
+    hmac_secret: &Secret<String>,
 ) -> Result<ExecutionOutcome, anyhow::Error> {
     let task = dequeue_task(pool).await?;
     if task.is_none() {
         return Ok(ExecutionOutcome::EmptyQueue);
     }
-    let (_, issue_id, email) = task.unwrap();
+    let (mut transaction, task) = task.unwrap();
+    let Task {
+        issue_id,
+        email,
+        request_id,
+    } = task;
     Span::current()
-        .record("newsletter_issue_id", &display(issue_id))
-        .record("subscriber_email", &display(&email));
+        .record("newsletter_issue_id", display(issue_id))
+        .record("subscriber_email", display(&email));
+    // Ties the delivery back to the HTTP request that published the issue
+    if let Some(request_id) = request_id {
+        Span::current().record("request_id", display(request_id));
+    }
+    let mut issue_ids = vec![issue_id];
     match SubscriberEmail::parse(email.clone()) {
-        Ok(email) => {
-            let issue = get_issue(pool, issue_id).await?;
-            if let Err(e) = email_client
-                .send_email(
-                    &email,
-                    &issue.title,
-                    &issue.html_content,
-                    &issue.text_content,
-                )
-                .await
-            {
+        Ok(email) if is_suppressed(pool, hmac_secret, email.as_ref()).await? => {
+            tracing::info!("Skipping a subscriber whose address is suppressed.");
+        }
+        Ok(email) => match get_subscriber(pool, email.as_ref()).await? {
+            Some(subscriber) => {
+                let digest_title = subscriber.delivery_frequency.digest_title();
+                if digest_title.is_some() {
+                    issue_ids.extend(
+                        dequeue_held_tasks(&mut transaction, issue_id, email.as_ref()).await?,
+                    );
+                }
+                let delivery = Delivery {
+                    email_client,
+                    templates,
+                    tracking,
+                    email: &email,
+                    subscriber: &subscriber,
+                };
+                match digest_title {
+                    Some(title) if issue_ids.len() > 1 => {
+                        Span::current().record("digest_size", issue_ids.len());
+                        delivery.send_digest(pool, title, &issue_ids).await?;
+                    }
+                    _ => delivery.send_issue(pool, issue_id).await?,
+                }
+            }
+            None => {
+                tracing::info!("Skipping a subscriber that is no longer confirmed.");
+            }
+        },
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Skipping a confirmed subscriber. Their stored contact details are invalid."
+            );
+        }
+    }
+    delete_tasks(transaction, &issue_ids, &email).await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// What it takes to send issues to one subscriber
+struct Delivery<'a> {
+    email_client: &'a EmailClient,
+    templates: &'a EmailTemplates,
+    tracking: &'a Tracking,
+    email: &'a SubscriberEmail,
+    subscriber: &'a Subscriber,
+}
+
+impl Delivery<'_> {
+    fn recipient(&self) -> Recipient<'_> {
+        Recipient {
+            name: &self.subscriber.name,
+            email: self.email.as_ref(),
+            subscription_token: self.subscriber.subscription_token.as_deref(),
+            tracking: None,
+        }
+    }
+
+    fn tracking(&self, issue_id: Uuid, issue: &NewletterIssue) -> Option<RecipientTracking<'_>> {
+        issue
+            .tracking_enabled
+            .then(|| self.tracking.for_recipient(issue_id, self.subscriber.id))
+    }
+
+    async fn send_issue(&self, pool: &PgPool, issue_id: Uuid) -> Result<(), anyhow::Error> {
+        let issue = get_issue(pool, issue_id).await?;
+        let recipient = Recipient {
+            tracking: self.tracking(issue_id, &issue),
+            ..self.recipient()
+        };
+        let rendered = self.templates.render_issue(&issue.content(), &recipient);
+        self.send(&issue.title, rendered).await;
+        Ok(())
+    }
+
+    /// Issues held back for a weekly or monthly subscriber, in the order they were published
+    async fn send_digest(
+        &self,
+        pool: &PgPool,
+        title: &str,
+        issue_ids: &[Uuid],
+    ) -> Result<(), anyhow::Error> {
+        let mut issues = Vec::with_capacity(issue_ids.len());
+        for issue_id in issue_ids {
+            issues.push((*issue_id, get_issue(pool, *issue_id).await?));
+        }
+        issues.sort_by_key(|(_, issue)| issue.published_at);
+        let digest: Vec<DigestIssue> = issues
+            .iter()
+            .map(|(issue_id, issue)| DigestIssue {
+                issue: issue.content(),
+                tracking: self.tracking(*issue_id, issue),
+            })
+            .collect();
+        let rendered = self
+            .templates
+            .render_digest(title, &digest, &self.recipient());
+        self.send(title, rendered).await;
+        Ok(())
+    }
+
+    /// Failures are logged and the delivery skipped, rather than retried
+    async fn send(&self, subject: &str, rendered: Result<RenderedEmail, TemplateError>) {
+        let rendered = match rendered {
+            Ok(rendered) => rendered,
+            Err(e) => {
                 tracing::error!(
                     error.cause_chain = ?e,
                     error.message = %e,
-                    "Failed to deliver issue to a confirmed subscriber.  Skipping."
+                    "Failed to render issue for a confirmed subscriber.  Skipping."
                 );
+                return;
             }
-        }
-        Err(e) => {
+        };
+        let timer = METRICS.issue_delivery_duration.start_timer();
+        let html = match self.subscriber.preferred_format {
+            EmailFormat::Html => Some(rendered.html.as_str()),
+            EmailFormat::Text => None,
+        };
+        let unsubscribe_url = self
+            .subscriber
+            .subscription_token
+            .as_deref()
+            .map(|token| self.templates.unsubscribe_url(token));
+        let outcome = self
+            .email_client
+            .send_issue(
+                self.email,
+                subject,
+                html,
+                &rendered.text,
+                unsubscribe_url.as_deref(),
+            )
+            .await;
+        timer.observe_duration();
+        let result = if outcome.is_ok() {
+            "success"
+        } else {
+            "failure"
+        };
+        METRICS.issue_deliveries.with_label_values(&[result]).inc();
+        if let Err(e) = outcome {
             tracing::error!(
                 error.cause_chain = ?e,
                 error.message = %e,
-                "Skipping a confirmed subscriber. Their stored contact details are invalid."
+                "Failed to deliver issue to a confirmed subscriber.  Skipping."
             );
         }
     }
-    Ok(ExecutionOutcome::TaskCompleted)
 }
 
 type PgTransaction = Transaction<'static, Postgres>;
 
+/// Queue an issue for every confirmed subscriber, or only for `recipient` when given.
+/// Deliveries that are already queued are left alone: returns how many were added.
+/// `request_id` is the request that asked for the deliveries, if they came from one.
+#[tracing::instrument(skip(transaction))]
+pub async fn enqueue_delivery_tasks(
+    transaction: &mut PgTransaction,
+    newsletter_issue_id: Uuid,
+    recipient: Option<&str>,
+    request_id: Option<Uuid>,
+) -> Result<u64, sqlx::Error> {
+    // Weekly and monthly subscribers get the issue at the start of the next week or month,
+    // along with the others held back for them: see `reschedule_deliveries` too
+    let queued = sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue (
+            newsletter_issue_id,
+            subscriber_email,
+            execute_after,
+            request_id
+        )
+        SELECT $1, email, CASE delivery_frequency
+            WHEN 'weekly' THEN date_trunc('week', now()) + interval '7 days'
+            WHEN 'monthly' THEN date_trunc('month', now()) + interval '1 month'
+            ELSE now()
+        END, $3
+        FROM subscriptions
+        WHERE
+            status = 'confirmed'
+            AND lower(email) NOT IN (SELECT email FROM suppressed_emails)
+            AND ($2::text IS NULL OR lower(email) = lower($2))
+        ON CONFLICT DO NOTHING
+    "#,
+        newsletter_issue_id,
+        recipient,
+        request_id
+    )
+    .execute(transaction)
+    .await?;
+    Ok(queued.rows_affected())
+}
+
+/// Move the deliveries queued for a subscriber to the schedule of their delivery frequency,
+/// e.g. to now for issues held back for a subscriber who no longer wants a digest.
+/// Deliveries already due are left alone: a worker may be on its way to send them.
+#[tracing::instrument(skip(executor))]
+pub async fn reschedule_deliveries(
+    executor: impl PgExecutor<'_>,
+    subscriber_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    let rescheduled = sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue q
+        SET execute_after = CASE s.delivery_frequency
+            WHEN 'weekly' THEN date_trunc('week', now()) + interval '7 days'
+            WHEN 'monthly' THEN date_trunc('month', now()) + interval '1 month'
+            ELSE now()
+        END
+        FROM subscriptions s
+        WHERE s.id = $1 AND q.subscriber_email = s.email AND q.execute_after > now()
+    "#,
+        subscriber_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(rescheduled.rows_affected())
+}
+
+/// Namespace of the advisory locks a worker takes on a subscriber while delivering to them,
+/// so that two workers never split the issues held back for the same subscriber
+const DELIVERY_LOCK_CLASS: i32 = 1;
+
+struct Task {
+    issue_id: Uuid,
+    email: String,
+    /// The request that queued the delivery, if any
+    request_id: Option<Uuid>,
+}
+
 #[tracing::instrument(skip_all)]
-async fn dequeue_task(
-    pool: &PgPool,
-) -> Result<Option<(PgTransaction, Uuid, String)>, anyhow::Error> {
+async fn dequeue_task(pool: &PgPool) -> Result<Option<(PgTransaction, Task)>, anyhow::Error> {
     let mut transaction = pool.begin().await?;
     let r = sqlx::query!(
         r#"
-        SELECT newsletter_issue_id, subscriber_email
+        SELECT newsletter_issue_id, subscriber_email, request_id
         FROM issue_delivery_queue
+        WHERE
+            execute_after <= now()
+            AND pg_try_advisory_xact_lock($1, hashtext(subscriber_email))
         FOR UPDATE
         SKIP LOCKED
         LIMIT 1
-    "#
+    "#,
+        DELIVERY_LOCK_CLASS
     )
     .fetch_optional(&mut transaction)
     .await?;
     if let Some(r) = r {
         Ok(Some((
             transaction,
-            r.newsletter_issue_id,
-            r.subscriber_email,
+            Task {
+                issue_id: r.newsletter_issue_id,
+                email: r.subscriber_email,
+                request_id: r.request_id,
+            },
         )))
     } else {
         Ok(None)
     }
 }
 
+/// The other issues due for the subscriber of a task we dequeued, to send them all at once.
+/// Our advisory lock on the subscriber keeps other workers away from them.
 #[tracing::instrument(skip_all)]
-async fn delete_task(
-    mut transaction: PgTransaction,
+async fn dequeue_held_tasks(
+    transaction: &mut PgTransaction,
     issue_id: Uuid,
     email: &str,
+) -> Result<Vec<Uuid>, anyhow::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id
+        FROM issue_delivery_queue
+        WHERE
+            subscriber_email = $1
+            AND newsletter_issue_id != $2
+            AND execute_after <= now()
+        FOR UPDATE
+    "#,
+        email,
+        issue_id
+    )
+    .fetch_all(transaction)
+    .await?;
+    Ok(rows.into_iter().map(|r| r.newsletter_issue_id).collect())
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_tasks(
+    mut transaction: PgTransaction,
+    issue_ids: &[Uuid],
+    email: &str,
 ) -> Result<(), anyhow::Error> {
     sqlx::query!(
         r#"
         DELETE FROM issue_delivery_queue
         WHERE
-            newsletter_issue_id = $1
+            newsletter_issue_id = ANY($1)
             AND subscriber_email = $2
     "#,
-        issue_id,
+        issue_ids,
         email
     )
     .execute(&mut transaction)
@@ -109,8 +372,22 @@ async fn delete_task(
 
 struct NewletterIssue {
     title: String,
+    slug: String,
     text_content: String,
     html_content: String,
+    tracking_enabled: bool,
+    published_at: DateTime<Utc>,
+}
+
+impl NewletterIssue {
+    fn content(&self) -> Issue<'_> {
+        Issue {
+            title: &self.title,
+            slug: &self.slug,
+            html_content: &self.html_content,
+            text_content: &self.text_content,
+        }
+    }
 }
 
 #[tracing::instrument(skip_all)]
@@ -118,7 +395,7 @@ async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<NewletterIssue, anyh
     let issue = sqlx::query_as!(
         NewletterIssue,
         r#"
-        SELECT title, text_content, html_content
+        SELECT title, slug, text_content, html_content, tracking_enabled, published_at
         FROM newsletter_issues
         WHERE 
             newsletter_issue_id = $1
@@ -130,22 +407,226 @@ async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<NewletterIssue, anyh
     Ok(issue)
 }
 
-async fn worker_loop(pool: PgPool, email_client: EmailClient) -> Result<(), anyhow::Error> {
-    loop {
-        match try_execute_task(&pool, &email_client).await {
-            Ok(ExecutionOutcome::EmptyQueue) => {
-                tokio::time::sleep(Duration::from_secs(10)).await;
+struct Subscriber {
+    id: Uuid,
+    name: String,
+    subscription_token: Option<String>,
+    preferred_format: EmailFormat,
+    delivery_frequency: DeliveryFrequency,
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_subscriber(pool: &PgPool, email: &str) -> Result<Option<Subscriber>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT
+            s.id,
+            s.name,
+            s.preferred_format,
+            s.delivery_frequency,
+            t.subscription_token as "subscription_token?"
+        FROM subscriptions s
+        LEFT JOIN subscription_tokens t ON t.subscriber_id = s.id
+        WHERE s.email = $1 AND s.status = 'confirmed'
+        LIMIT 1
+    "#,
+        email
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    Ok(Some(Subscriber {
+        id: row.id,
+        name: row.name,
+        subscription_token: row.subscription_token,
+        preferred_format: EmailFormat::parse(&row.preferred_format).map_err(anyhow::Error::msg)?,
+        delivery_frequency: DeliveryFrequency::parse(&row.delivery_frequency)
+            .map_err(anyhow::Error::msg)?,
+    }))
+}
+
+/// The name the delivery worker checks in with in `worker_heartbeats`
+pub const WORKER_NAME: &str = "issue_delivery";
+/// How often the worker checks in, at most: it also checks in whenever it wakes up from an empty queue
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Let readiness checks know that the worker is still running
+#[tracing::instrument(skip(pool))]
+pub async fn record_heartbeat(pool: &PgPool, worker_name: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO worker_heartbeats (worker_name, last_seen_at)
+        VALUES ($1, now())
+        ON CONFLICT (worker_name) DO UPDATE SET last_seen_at = EXCLUDED.last_seen_at
+    "#,
+        worker_name
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn last_heartbeat(
+    pool: &PgPool,
+    worker_name: &str,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT last_seen_at FROM worker_heartbeats WHERE worker_name = $1",
+        worker_name
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.last_seen_at))
+}
+
+/// Deliver issues until `shutdown` is cancelled. The task in progress at that point gets
+/// `deadline` to finish and commit: past it, it is abandoned and stays in the queue.
+async fn worker_loop(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    tracking: &Tracking,
+    hmac_secret: &Secret<String>,
+    shutdown: &CancellationToken,
+    deadline: Duration,
+) {
+    let mut last_heartbeat: Option<Instant> = None;
+    while !shutdown.is_cancelled() {
+        if last_heartbeat.is_none_or(|at| at.elapsed() >= HEARTBEAT_INTERVAL) {
+            match record_heartbeat(pool, WORKER_NAME).await {
+                Ok(()) => last_heartbeat = Some(Instant::now()),
+                Err(e) => tracing::warn!(error.message = %e, "Failed to record a heartbeat"),
             }
-            Err(_) => {
-                tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+        let task = try_execute_task(pool, email_client, templates, tracking, hmac_secret);
+        tokio::pin!(task);
+        let outcome = tokio::select! {
+            outcome = &mut task => outcome,
+            _ = shutdown.cancelled() => match tokio::time::timeout(deadline, &mut task).await {
+                Ok(outcome) => outcome,
+                Err(_) => {
+                    tracing::warn!("Abandoned a delivery in progress at the shutdown deadline");
+                    break;
+                }
+            },
+        };
+        match outcome {
+            Ok(ExecutionOutcome::EmptyQueue) | Err(_) => {
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(10)) => {}
+                    _ = shutdown.cancelled() => {}
+                }
             }
             Ok(ExecutionOutcome::TaskCompleted) => {}
         }
     }
 }
 
-pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
-    let connection_pool = get_connection_pool(&configuration.database);
-    let email_client = configuration.email_client.client();
-    worker_loop(connection_pool, email_client).await
+/// How often the worker clears out the records we only keep for a while
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Keep the clean-ups that would otherwise slow requests down off the request path
+async fn housekeeping_loop(pool: &PgPool, shutdown: &CancellationToken) {
+    while !shutdown.is_cancelled() {
+        match purge_signup_attempts(pool).await {
+            Ok(purged) => tracing::info!(purged, "Purged old signup attempts"),
+            Err(e) => tracing::warn!(error.message = %e, "Failed to purge old signup attempts"),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(HOUSEKEEPING_INTERVAL) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+}
+
+/// Whatever is still queued is picked up by the next worker to start
+async fn log_work_left_undone(pool: &PgPool) {
+    let queue = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(*) FILTER (WHERE execute_after <= now()) AS "due!",
+            COUNT(*) FILTER (WHERE execute_after > now()) AS "scheduled!"
+        FROM issue_delivery_queue
+        "#
+    )
+    .fetch_one(pool)
+    .await;
+    match queue {
+        Ok(queue) => tracing::info!(
+            due_deliveries = queue.due,
+            scheduled_deliveries = queue.scheduled,
+            "The delivery worker has stopped"
+        ),
+        Err(e) => tracing::warn!(
+            error.message = %e,
+            "The delivery worker has stopped, but we failed to count the deliveries left undone"
+        ),
+    }
+}
+
+/// Deliver queued issues with `concurrency` loops side by side until `shutdown` is cancelled.
+/// Loops never pick the same task, whether they run in this process or in another one:
+/// `dequeue_task` skips the rows other transactions have locked. When running without the API,
+/// pass a `metrics_listener` to serve the worker's metrics on.
+pub async fn run_worker_until_stopped(
+    configuration: Settings,
+    concurrency: NonZeroUsize,
+    metrics_listener: Option<TcpListener>,
+    shutdown: CancellationToken,
+) -> Result<(), anyhow::Error> {
+    // Each loop holds a connection for its transaction, and needs another for its other queries
+    let max_connections = MAX_DB_CONNECTIONS.max((2 * concurrency.get()).try_into()?);
+    let connection_pool = get_connection_pool_of_size(&configuration.database, max_connections);
+    let metrics_server = metrics_listener
+        .map(|listener| {
+            metrics_server(
+                listener,
+                connection_pool.clone(),
+                max_connections,
+                configuration.metrics,
+            )
+        })
+        .transpose()?;
+    let email_client = configuration.email_client.client()?;
+    let templates = EmailTemplates::new(configuration.application.base_url.clone())?;
+    let deadline = configuration.application.shutdown_deadline();
+    let hmac_secret = configuration.application.hmac_secret;
+    let tracking = Tracking::new(configuration.application.base_url, hmac_secret.clone());
+    tracing::info!(
+        concurrency = concurrency.get(),
+        "Starting the delivery worker"
+    );
+    let deliveries = join_all((0..concurrency.get()).map(|_| {
+        worker_loop(
+            &connection_pool,
+            &email_client,
+            &templates,
+            &tracking,
+            &hmac_secret,
+            &shutdown,
+            deadline,
+        )
+    }));
+    let metrics = async {
+        if let Some(server) = metrics_server {
+            let handle = server.handle();
+            let stop = async {
+                shutdown.cancelled().await;
+                handle.stop(true).await;
+            };
+            tokio::join!(server, stop).0
+        } else {
+            Ok(())
+        }
+    };
+    let (_, _, metrics) = tokio::join!(
+        deliveries,
+        housekeeping_loop(&connection_pool, &shutdown),
+        metrics
+    );
+    log_work_left_undone(&connection_pool).await;
+    metrics.context("Failed to serve the worker metrics")?;
+    Ok(())
 }
@@ -1,11 +1,25 @@
+pub mod admin;
+pub mod allowed_origins;
 pub mod authentication;
+pub mod captcha;
 pub mod configuration;
+pub mod data_requests;
 pub mod domain;
 pub mod email_client;
+pub mod email_templates;
+pub mod error;
+pub mod html;
 pub mod idempotency;
 pub mod issue_delivery_worker;
+pub mod metrics;
 pub mod routes;
 pub mod session_state;
+pub mod shutdown;
+pub mod signup_protection;
 pub mod startup;
+pub mod subscription_history;
+pub mod suppression;
 pub mod telemetry;
+pub mod tracking;
+pub mod trusted_proxies;
 pub mod utils;
@@ -1,26 +1,150 @@
+use clap::{Parser, Subcommand};
+use opentelemetry::trace::TracerProvider as _;
 use std::fmt::{Debug, Display};
+use std::net::TcpListener;
+use std::num::NonZeroUsize;
 use tokio::task::JoinError;
-use zero2prod::configuration::get_configuration;
+use tokio_util::sync::CancellationToken;
+use zero2prod::admin::{run_admin_command, AdminCommand};
+use zero2prod::configuration::{get_configuration, read_configuration};
 use zero2prod::issue_delivery_worker::run_worker_until_stopped;
-use zero2prod::startup::Application;
-use zero2prod::telemetry::{get_subscriber, init_subscriber};
+use zero2prod::shutdown::{cancel_on_exit, cancel_on_signal};
+use zero2prod::startup::{prepare_database, Application};
+use zero2prod::telemetry::{get_subscriber, init_subscriber, otlp_tracer_provider};
+
+#[derive(Parser)]
+#[command(version, about = "A newsletter delivery service")]
+struct Cli {
+    /// What to run: both the API and the delivery worker when left out
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Check the configuration, report every problem with it and exit
+    #[arg(long)]
+    check_config: bool,
+    /// Print the effective configuration as JSON, with secrets redacted, and exit
+    #[arg(long)]
+    print_config: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the HTTP API
+    Serve,
+    /// Deliver queued newsletter issues. Worker processes can be run alongside each other.
+    Worker {
+        /// How many deliveries to run at the same time
+        #[arg(long, default_value = "1")]
+        concurrency: NonZeroUsize,
+    },
+    /// Serve the HTTP API and deliver queued newsletter issues from the same process
+    All {
+        /// How many deliveries to run at the same time
+        #[arg(long, default_value = "1")]
+        concurrency: NonZeroUsize,
+    },
+    /// Operational tasks: managing users, subscribers and issues, applying migrations
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let subscriber = get_subscriber("zero2prod".into(), "info".into(), std::io::stdout);
-    init_subscriber(subscriber);
+    let cli = Cli::parse();
+    if cli.print_config {
+        let configuration = read_configuration()?;
+        println!("{:#}", configuration.redacted());
+        return Ok(());
+    }
+    if cli.check_config {
+        get_configuration()?;
+        println!("The configuration is valid");
+        return Ok(());
+    }
+    let configuration = get_configuration()?;
+    let (serve, worker_concurrency) = match cli.command {
+        Some(Command::Serve) => (true, None),
+        Some(Command::Worker { concurrency }) => (false, Some(concurrency)),
+        Some(Command::All { concurrency }) => (true, Some(concurrency)),
+        None => (true, Some(NonZeroUsize::MIN)),
+        Some(Command::Admin { command }) => {
+            // Only warnings and errors, on stderr: stdout is for what the command has to say
+            let subscriber =
+                get_subscriber("zero2prod".into(), "warn".into(), std::io::stderr, None);
+            init_subscriber(subscriber);
+            return run_admin_command(configuration, command).await;
+        }
+    };
 
-    let configuration = get_configuration().expect("Failed to read configuration");
+    let tracer_provider = configuration
+        .opentelemetry
+        .as_ref()
+        .map(|settings| otlp_tracer_provider("zero2prod", settings))
+        .transpose()?;
+    let subscriber = get_subscriber(
+        "zero2prod".into(),
+        "info".into(),
+        std::io::stdout,
+        tracer_provider
+            .as_ref()
+            .map(|provider| provider.tracer("zero2prod")),
+    );
+    init_subscriber(subscriber);
 
-    let application = Application::build(configuration.clone()).await?;
+    prepare_database(
+        &configuration.database,
+        configuration.application.run_migrations,
+    )
+    .await?;
 
-    let application_task = tokio::spawn(application.run_until_stopped());
-    let worker_task = tokio::spawn(run_worker_until_stopped(configuration));
+    let shutdown = CancellationToken::new();
+    cancel_on_signal(shutdown.clone())?;
 
-    tokio::select! {
-        o = application_task => report_exit("API", o),
-        o = worker_task => report_exit("Background worker", o),
+    let application_task = if serve {
+        let application = Application::build(configuration.clone()).await?;
+        Some(tokio::spawn(cancel_on_exit(
+            shutdown.clone(),
+            application.run_until_stopped(shutdown.clone()),
+        )))
+    } else {
+        None
+    };
+    // Without the API, the worker serves its metrics on a port of its own
+    let metrics_listener = if !serve && configuration.metrics.bearer_token.is_some() {
+        Some(TcpListener::bind((
+            configuration.application.host.as_str(),
+            configuration.metrics.worker_port,
+        ))?)
+    } else {
+        None
     };
+    let worker_task = worker_concurrency.map(|concurrency| {
+        tokio::spawn(cancel_on_exit(
+            shutdown.clone(),
+            run_worker_until_stopped(
+                configuration,
+                concurrency,
+                metrics_listener,
+                shutdown.clone(),
+            ),
+        ))
+    });
+
+    // Each stops on its own once `shutdown` is cancelled, within the shutdown deadline
+    if let Some(task) = application_task {
+        report_exit("API", task.await);
+    }
+    if let Some(task) = worker_task {
+        report_exit("Background worker", task.await);
+    }
+
+    // Flush the spans that are still waiting for their batch to be exported
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            tracing::error!(error.message = %e, "Failed to export the last traces");
+        }
+    }
 
     Ok(())
 }
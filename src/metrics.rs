@@ -0,0 +1,280 @@
+use crate::configuration::MetricsSettings;
+use crate::error::AppError;
+use crate::utils::{constant_time_eq, e500};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Server, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::{web, App, HttpResponse, HttpServer};
+use actix_web_lab::middleware::Next;
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+use secrecy::ExposeSecret;
+use sqlx::PgPool;
+use std::net::TcpListener;
+use std::time::Instant;
+use tracing_actix_web::TracingLogger;
+
+/// Everything we export on `/metrics`, kept in our own registry rather than in the default one,
+/// so that the endpoint only shows what we chose to measure
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// By method, route pattern and status code
+    pub http_requests: IntCounterVec,
+    /// By method and route pattern
+    pub http_request_duration: HistogramVec,
+    /// By state: `due`, or `scheduled` for later by a subscriber's delivery frequency
+    pub issue_delivery_queue_depth: IntGaugeVec,
+    pub issue_delivery_queue_oldest_due_task_age: Gauge,
+    /// By outcome: `success` or `failure`
+    pub issue_deliveries: IntCounterVec,
+    pub issue_delivery_duration: Histogram,
+    /// By the HTTP status code of the email API, or `error` when we got no response at all
+    pub email_api_responses: IntCounterVec,
+    pub password_verification_duration: Histogram,
+    /// By reason: `honeypot`, `captcha_failed`, `rate_limited_ip` or `rate_limited_domain`
+    pub signup_rejections: IntCounterVec,
+    /// By state: `idle` or `in_use`
+    pub db_pool_connections: IntGaugeVec,
+    pub db_pool_max_connections: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("zero2prod".into()), None)
+            .expect("Failed to create the metrics registry");
+        let metrics = Self {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests handled"),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time spent handling HTTP requests",
+                ),
+                &["method", "route"],
+            )
+            .unwrap(),
+            issue_delivery_queue_depth: IntGaugeVec::new(
+                Opts::new(
+                    "issue_delivery_queue_depth",
+                    "Newsletter issue deliveries waiting in the queue",
+                ),
+                &["state"],
+            )
+            .unwrap(),
+            issue_delivery_queue_oldest_due_task_age: Gauge::new(
+                "issue_delivery_queue_oldest_due_task_age_seconds",
+                "How long the oldest due delivery has been waiting for the worker",
+            )
+            .unwrap(),
+            issue_deliveries: IntCounterVec::new(
+                Opts::new(
+                    "issue_deliveries_total",
+                    "Newsletter issues the worker tried to send",
+                ),
+                &["outcome"],
+            )
+            .unwrap(),
+            issue_delivery_duration: Histogram::with_opts(HistogramOpts::new(
+                "issue_delivery_duration_seconds",
+                "Time spent sending a newsletter issue to one subscriber",
+            ))
+            .unwrap(),
+            email_api_responses: IntCounterVec::new(
+                Opts::new(
+                    "email_api_responses_total",
+                    "Responses from the email delivery API",
+                ),
+                &["status"],
+            )
+            .unwrap(),
+            // Argon2 is slow on purpose: the default buckets top out at 10 seconds, which is plenty
+            password_verification_duration: Histogram::with_opts(HistogramOpts::new(
+                "password_verification_duration_seconds",
+                "Time spent verifying a password hash",
+            ))
+            .unwrap(),
+            signup_rejections: IntCounterVec::new(
+                Opts::new("signup_rejections_total", "Signups we turned down"),
+                &["reason"],
+            )
+            .unwrap(),
+            db_pool_connections: IntGaugeVec::new(
+                Opts::new(
+                    "db_pool_connections",
+                    "Open connections in the database pool",
+                ),
+                &["state"],
+            )
+            .unwrap(),
+            db_pool_max_connections: IntGauge::new(
+                "db_pool_max_connections",
+                "Connections the database pool is allowed to open",
+            )
+            .unwrap(),
+            registry,
+        };
+        let collectors: [Box<dyn prometheus::core::Collector>; 11] = [
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_request_duration.clone()),
+            Box::new(metrics.issue_delivery_queue_depth.clone()),
+            Box::new(metrics.issue_delivery_queue_oldest_due_task_age.clone()),
+            Box::new(metrics.issue_deliveries.clone()),
+            Box::new(metrics.issue_delivery_duration.clone()),
+            Box::new(metrics.email_api_responses.clone()),
+            Box::new(metrics.password_verification_duration.clone()),
+            Box::new(metrics.signup_rejections.clone()),
+            Box::new(metrics.db_pool_connections.clone()),
+            Box::new(metrics.db_pool_max_connections.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("Failed to register a metric");
+        }
+        metrics
+    }
+}
+
+/// Count and time every request, by the pattern of the route that handled it.
+/// Using the pattern rather than the path keeps the number of series bounded.
+pub async fn record_http_metrics(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let response = next.call(req).await?;
+    let route = response
+        .request()
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".into());
+    METRICS
+        .http_request_duration
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    METRICS
+        .http_requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    Ok(response)
+}
+
+/// The `max_connections` a process built its pool with, which sqlx does not let us read back
+pub struct DbPoolSize(pub u32);
+
+#[tracing::instrument(name = "Export metrics", skip_all)]
+pub async fn metrics_endpoint(
+    request: actix_web::HttpRequest,
+    pool: web::Data<PgPool>,
+    pool_size: web::Data<DbPoolSize>,
+    settings: web::Data<MetricsSettings>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(expected_token) = &settings.bearer_token else {
+        return Err(AppError::NotFound.into());
+    };
+    match bearer_token(request.headers()) {
+        Some(token) if constant_time_eq(token, expected_token.expose_secret()) => {}
+        _ => return Err(AppError::Unauthorized(Some(r#"Bearer realm="metrics""#)).into()),
+    }
+
+    // Gauges of things we do not own are read when Prometheus asks for them
+    record_queue_metrics(&pool).await.map_err(e500)?;
+    record_pool_metrics(&pool, pool_size.0);
+
+    let mut body = Vec::new();
+    let encoder = TextEncoder::new();
+    encoder
+        .encode(&METRICS.registry.gather(), &mut body)
+        .map_err(e500)?;
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body))
+}
+
+/// Serve `/metrics` from a process without the API, i.e. `zero2prod worker`:
+/// the registry lives in each process, so each has to export its own.
+pub fn metrics_server(
+    listener: TcpListener,
+    pool: PgPool,
+    pool_size: u32,
+    settings: MetricsSettings,
+) -> Result<Server, std::io::Error> {
+    let pool = web::Data::new(pool);
+    let pool_size = web::Data::new(DbPoolSize(pool_size));
+    let settings = web::Data::new(settings);
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(TracingLogger::default())
+            .route("/metrics", web::get().to(metrics_endpoint))
+            .app_data(pool.clone())
+            .app_data(pool_size.clone())
+            .app_data(settings.clone())
+    })
+    .workers(1)
+    // Signals are handled for the whole process: the worker stops us once it is asked to stop
+    .disable_signals()
+    .listen(listener)?
+    .run();
+    Ok(server)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+async fn record_queue_metrics(pool: &PgPool) -> Result<(), anyhow::Error> {
+    let queue = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(*) FILTER (WHERE execute_after <= now()) AS "due!",
+            COUNT(*) FILTER (WHERE execute_after > now()) AS "scheduled!",
+            EXTRACT(EPOCH FROM now() - MIN(execute_after) FILTER (WHERE execute_after <= now()))::float8
+                AS oldest_due_task_age
+        FROM issue_delivery_queue
+        "#
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to measure the issue delivery queue")?;
+    METRICS
+        .issue_delivery_queue_depth
+        .with_label_values(&["due"])
+        .set(queue.due);
+    METRICS
+        .issue_delivery_queue_depth
+        .with_label_values(&["scheduled"])
+        .set(queue.scheduled);
+    METRICS
+        .issue_delivery_queue_oldest_due_task_age
+        .set(queue.oldest_due_task_age.unwrap_or(0.0));
+    Ok(())
+}
+
+fn record_pool_metrics(pool: &PgPool, max_connections: u32) {
+    let idle = pool.num_idle() as i64;
+    METRICS
+        .db_pool_connections
+        .with_label_values(&["idle"])
+        .set(idle);
+    METRICS
+        .db_pool_connections
+        .with_label_values(&["in_use"])
+        .set(i64::from(pool.size()) - idle);
+    METRICS
+        .db_pool_max_connections
+        .set(i64::from(max_connections));
+}
@@ -1,50 +1,74 @@
-use crate::{authentication::UserId, utils::e500};
-use actix_web::{http::header::ContentType, web, HttpResponse};
-use actix_web_flash_messages::{IncomingFlashMessages, Level};
+use crate::authentication::{csrf_token, UserId};
+use crate::html;
+use crate::session_state::TypedSession;
+use crate::signup_protection::{rejected_signups, RejectedSignups};
+use crate::utils::e500;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
 use anyhow::Context;
+use askama::Template;
 use sqlx::PgPool;
-use std::fmt::Write;
 use uuid::Uuid;
 
+#[derive(Template)]
+#[template(path = "admin/dashboard.html")]
+struct DashboardTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    csrf_token: String,
+    username: String,
+    issue_stats: Vec<IssueStats>,
+    rejected_signups: Vec<RejectedSignups>,
+}
+
+/// Engagement with an issue that was published with tracking enabled
+struct IssueStats {
+    title: String,
+    unique_opens: i64,
+    unique_clicks: i64,
+    total_clicks: i64,
+}
+
 pub async fn admin_dashboard(
     pool: web::Data<PgPool>,
     user_id: web::ReqData<UserId>,
+    session: TypedSession,
     flash_messages: IncomingFlashMessages,
 ) -> Result<HttpResponse, actix_web::Error> {
     let user_id = user_id.into_inner();
     let username = get_username(*user_id, &pool).await.map_err(e500)?;
-    let mut msg = String::new();
-    for m in flash_messages.iter().filter(|m| m.level() >= Level::Info) {
-        writeln!(msg, "<p><i>{}</i></p>", m.content()).unwrap();
-    }
-    Ok(HttpResponse::Ok()
-        .content_type(ContentType::html())
-        .body(format!(
-            r#"<!DOCTYPE html>
-    <html lang="en">
-        <head>
-            <meta http-equiv="content-type" content="text/html; charset=utf-8">
-            <title>Admin dashboard</title>
-        </head>
-        <body>
-            <p>Welcome {username}</p>
-            {msg}
-            <p>Available actions:</p>
-            <ol>
-              <li><a href="/admin/password">Change Password</a></li>
-              <li>
-                <form name="logoutForm" action="/admin/logout" method="post">
-                    <input type="submit" value="Logout">
-                </form>
-              </li>
-              <li>
-                <a href="/admin/newsletters">Send a newsletter issue</a>
-              </li>
-            </ol>
-        </body>
-    </html>
-    "#
-        )))
+    let issue_stats = get_issue_stats(&pool).await.map_err(e500)?;
+    let rejected_signups = rejected_signups(&pool).await.map_err(e500)?;
+    html::render(&DashboardTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        csrf_token: csrf_token(&session)?,
+        username,
+        issue_stats,
+        rejected_signups,
+    })
+}
+
+#[tracing::instrument(name = "Get issue tracking stats", skip(pool))]
+async fn get_issue_stats(pool: &PgPool) -> Result<Vec<IssueStats>, anyhow::Error> {
+    let stats = sqlx::query_as!(
+        IssueStats,
+        r#"
+        SELECT
+            i.title,
+            COUNT(DISTINCT e.subscriber_id) FILTER (WHERE e.kind = 'open') AS "unique_opens!",
+            COUNT(DISTINCT e.subscriber_id) FILTER (WHERE e.kind = 'click') AS "unique_clicks!",
+            COUNT(e.event_id) FILTER (WHERE e.kind = 'click') AS "total_clicks!"
+        FROM newsletter_issues i
+        LEFT JOIN issue_tracking_events e ON e.newsletter_issue_id = i.newsletter_issue_id
+        WHERE i.tracking_enabled
+        GROUP BY i.newsletter_issue_id
+        ORDER BY i.published_at DESC
+        LIMIT 10
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch issue tracking stats")?;
+    Ok(stats)
 }
 
 #[tracing::instrument(name = "Get username", skip(pool))]
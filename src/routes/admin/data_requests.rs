@@ -0,0 +1,47 @@
+use crate::data_requests::{erase_subscriber_data, export_subscriber_data};
+use crate::routes::data_requests::export_response;
+use crate::startup::HmacSecret;
+use crate::utils::{e500, see_other};
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct DataRequestParameters {
+    email: String,
+}
+
+#[tracing::instrument(name = "Export subscriber data on behalf of a subscriber", skip_all)]
+pub async fn admin_export_data(
+    parameters: web::Query<DataRequestParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    match export_subscriber_data(&pool, parameters.email.trim())
+        .await
+        .map_err(e500)?
+    {
+        Some(data) => Ok(export_response(&data)),
+        None => {
+            FlashMessage::error(format!("We hold no data about {}.", parameters.email)).send();
+            Ok(see_other("/admin/subscribers"))
+        }
+    }
+}
+
+// The address is left out of the spans: logs outlive the erasure
+#[tracing::instrument(name = "Erase subscriber data on behalf of a subscriber", skip_all)]
+pub async fn admin_erase_data(
+    form: web::Form<DataRequestParameters>,
+    pool: web::Data<PgPool>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if erase_subscriber_data(&pool, &hmac_secret.0, form.email.trim())
+        .await
+        .map_err(e500)?
+    {
+        FlashMessage::info(format!("All data about {} has been erased.", form.email)).send();
+    } else {
+        FlashMessage::error(format!("We hold no data about {}.", form.email)).send();
+    }
+    Ok(see_other("/admin/subscribers"))
+}
@@ -0,0 +1,100 @@
+use crate::authentication::csrf_token;
+use crate::error::AppError;
+use crate::html;
+use crate::routes::issues::format_date;
+use crate::session_state::TypedSession;
+use crate::utils::{e500, see_other};
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use anyhow::Context;
+use askama::Template;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+struct IssueRow {
+    newsletter_issue_id: Uuid,
+    title: String,
+    slug: String,
+    status: String,
+    hidden_from_archive: bool,
+    published_at: DateTime<Utc>,
+}
+
+impl IssueRow {
+    fn published_on(&self) -> String {
+        format_date(&self.published_at)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin/issues.html")]
+struct IssuesTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    csrf_token: String,
+    issues: Vec<IssueRow>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct VisibilityFormData {
+    hidden: bool,
+}
+
+pub async fn list_issues(
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let issues = get_issues(&pool).await.map_err(e500)?;
+    html::render(&IssuesTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        csrf_token: csrf_token(&session)?,
+        issues,
+    })
+}
+
+#[tracing::instrument(name = "Change the archive visibility of an issue", skip(form, pool), fields(hidden=%form.hidden))]
+pub async fn set_issue_visibility(
+    issue_id: web::Path<Uuid>,
+    form: web::Form<VisibilityFormData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let updated = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET hidden_from_archive = $2
+        WHERE newsletter_issue_id = $1
+        "#,
+        *issue_id,
+        form.hidden
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to update the archive visibility of a newsletter issue")
+    .map_err(e500)?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::NotFound.into());
+    }
+    if form.hidden {
+        FlashMessage::info("The issue has been hidden from the archive.").send();
+    } else {
+        FlashMessage::info("The issue is visible in the archive again.").send();
+    }
+    Ok(see_other("/admin/issues"))
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issues(pool: &PgPool) -> Result<Vec<IssueRow>, anyhow::Error> {
+    let issues = sqlx::query_as!(
+        IssueRow,
+        r#"
+        SELECT newsletter_issue_id, title, slug, status, hidden_from_archive, published_at
+        FROM newsletter_issues
+        ORDER BY published_at DESC
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch newsletter issues")?;
+    Ok(issues)
+}
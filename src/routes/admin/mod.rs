@@ -1,7 +1,15 @@
 mod dashboard;
+mod data_requests;
+mod issues;
 mod logout;
 mod password;
+mod subscribers;
+mod suppressions;
 
 pub use dashboard::*;
+pub use data_requests::*;
+pub use issues::*;
 pub use logout::*;
 pub use password::*;
+pub use subscribers::*;
+pub use suppressions::*;
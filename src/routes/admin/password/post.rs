@@ -1,5 +1,5 @@
 use crate::authentication::UserId;
-use crate::authentication::{validate_credentials, AuthError, Credentials};
+use crate::authentication::{validate_credentials, AuthError, Credentials, PASSWORD_LENGTH};
 use crate::routes::get_username;
 use crate::utils::{e500, see_other};
 use actix_web::{web, HttpResponse};
@@ -31,7 +31,7 @@ pub async fn change_password(
     }
 
     let pwd_len = form.new_password.expose_secret().len();
-    if !(12..=128).contains(&pwd_len) {
+    if !PASSWORD_LENGTH.contains(&pwd_len) {
         FlashMessage::error("Password length must be between 12 and 128 characters.").send();
         return Ok(see_other("/admin/password"));
     }
@@ -0,0 +1,143 @@
+use crate::authentication::csrf_token;
+use crate::error::AppError;
+use crate::html;
+use crate::session_state::TypedSession;
+use crate::subscription_history::{get_subscription_events, StoredSubscriptionEvent};
+use crate::utils::e500;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use anyhow::Context;
+use askama::Template;
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Serialize)]
+pub struct Subscriber {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+    preferred_format: String,
+    delivery_frequency: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin/subscribers.html")]
+struct SubscribersTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    csrf_token: String,
+    subscribers: Vec<Subscriber>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/subscriber.html")]
+struct SubscriberTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    csrf_token: String,
+    subscriber: Subscriber,
+    events: Vec<StoredSubscriptionEvent>,
+}
+
+/// The history of a subscription, as handed over to answer a data-protection inquiry
+#[derive(serde::Serialize)]
+struct SubscriptionHistoryExport {
+    subscriber: Subscriber,
+    events: Vec<StoredSubscriptionEvent>,
+}
+
+/// Timestamps are shown in full: they are evidence, not decoration
+fn timestamp(date: &DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+pub async fn list_subscribers(
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let subscribers = get_subscribers(&pool).await.map_err(e500)?;
+    html::render(&SubscribersTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        csrf_token: csrf_token(&session)?,
+        subscribers,
+    })
+}
+
+pub async fn subscriber_history(
+    subscriber_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(subscriber) = get_subscriber(&pool, *subscriber_id).await.map_err(e500)? else {
+        return Err(AppError::NotFound.into());
+    };
+    let events = get_subscription_events(&pool, subscriber.id)
+        .await
+        .map_err(e500)?;
+    html::render(&SubscriberTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        csrf_token: csrf_token(&session)?,
+        subscriber,
+        events,
+    })
+}
+
+#[tracing::instrument(name = "Export the history of a subscription", skip(pool))]
+pub async fn export_subscriber_history(
+    subscriber_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(subscriber) = get_subscriber(&pool, *subscriber_id).await.map_err(e500)? else {
+        return Err(AppError::NotFound.into());
+    };
+    let events = get_subscription_events(&pool, subscriber.id)
+        .await
+        .map_err(e500)?;
+    let filename = format!("subscriber-{}-history.json", subscriber.id);
+    Ok(HttpResponse::Ok()
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(filename)],
+        })
+        .json(SubscriptionHistoryExport { subscriber, events }))
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_subscribers(pool: &PgPool) -> Result<Vec<Subscriber>, anyhow::Error> {
+    let subscribers = sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT id, email, name, status, subscribed_at, preferred_format, delivery_frequency
+        FROM subscriptions
+        ORDER BY subscribed_at DESC
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch subscribers")?;
+    Ok(subscribers)
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<Subscriber>, anyhow::Error> {
+    let subscriber = sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT id, email, name, status, subscribed_at, preferred_format, delivery_frequency
+        FROM subscriptions
+        WHERE id = $1
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to fetch a subscriber")?;
+    Ok(subscriber)
+}
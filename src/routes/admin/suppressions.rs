@@ -0,0 +1,106 @@
+use crate::authentication::csrf_token;
+use crate::domain::SubscriberEmail;
+use crate::html;
+use crate::routes::issues::format_date;
+use crate::session_state::TypedSession;
+use crate::startup::HmacSecret;
+use crate::suppression::{
+    list_suppressions, remove_suppression, suppress_email, SuppressedEmail, SuppressionSource,
+};
+use crate::utils::{e500, see_other};
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use askama::Template;
+use sqlx::PgPool;
+
+#[derive(Template)]
+#[template(path = "admin/suppressions.html")]
+struct SuppressionsTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    csrf_token: String,
+    suppressions: Vec<SuppressedEmail>,
+}
+
+impl SuppressionsTemplate<'_> {
+    fn added_on(&self, suppression: &SuppressedEmail) -> String {
+        format_date(&suppression.created_at)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct AddSuppressionFormData {
+    email: String,
+    reason: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RemoveSuppressionFormData {
+    email: String,
+}
+
+pub async fn suppressions_page(
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let suppressions = list_suppressions(&pool).await.map_err(e500)?;
+    html::render(&SuppressionsTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        csrf_token: csrf_token(&session)?,
+        suppressions,
+    })
+}
+
+#[tracing::instrument(name = "Suppress an email address", skip_all, fields(email=%form.email))]
+pub async fn add_suppression(
+    form: web::Form<AddSuppressionFormData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let form = form.into_inner();
+    let email = match SubscriberEmail::parse(form.email) {
+        Ok(email) => email,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other("/admin/suppressions"));
+        }
+    };
+    let reason = match form.reason.trim() {
+        "" => "Added by an admin",
+        reason => reason,
+    };
+    suppress_email(
+        pool.get_ref(),
+        email.as_ref(),
+        SuppressionSource::Manual,
+        reason,
+    )
+    .await
+    .map_err(e500)?;
+    FlashMessage::info(format!(
+        "{} will not receive any more emails.",
+        email.as_ref()
+    ))
+    .send();
+    Ok(see_other("/admin/suppressions"))
+}
+
+#[tracing::instrument(name = "Remove an email address from the suppression list", skip_all, fields(email=%form.email))]
+pub async fn delete_suppression(
+    form: web::Form<RemoveSuppressionFormData>,
+    pool: web::Data<PgPool>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if remove_suppression(&pool, &hmac_secret.0, &form.email)
+        .await
+        .map_err(e500)?
+    {
+        FlashMessage::info(format!(
+            "{} has been removed from the suppression list.",
+            form.email
+        ))
+        .send();
+    } else {
+        FlashMessage::error(format!("{} is not on the suppression list.", form.email)).send();
+    }
+    Ok(see_other("/admin/suppressions"))
+}
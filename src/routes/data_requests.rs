@@ -0,0 +1,208 @@
+use crate::data_requests::{
+    erase_subscriber_data, export_subscriber_data, DataRequest, DataRequestAction,
+    DataRequestLinks, SubscriberData,
+};
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_templates::EmailTemplates;
+use crate::error::AppError;
+use crate::html;
+use crate::signup_protection::{AttemptKind, SignupAttempt, SignupOutcome, SignupProtection};
+use crate::startup::HmacSecret;
+use crate::subscription_history::RequestOrigin;
+use crate::utils::{e500, see_other};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use anyhow::Context;
+use askama::Template;
+use sqlx::PgPool;
+
+#[derive(Template)]
+#[template(path = "data_requests/request.html")]
+struct DataRequestTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "data_requests/erase.html")]
+struct ConfirmErasureTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    email: &'a str,
+    token: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+pub struct DataRequestFormData {
+    email: String,
+    action: DataRequestAction,
+    /// Honeypot, as on the subscription form
+    #[serde(default)]
+    website: String,
+    captcha_response: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct TokenParameters {
+    token: String,
+}
+
+/// The data we hold about an address, as a JSON file to download
+pub(crate) fn export_response(data: &SubscriberData) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("subscriber-data.json".into())],
+        })
+        .json(data)
+}
+
+pub async fn data_request_form(
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    html::render(&DataRequestTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+    })
+}
+
+#[tracing::instrument(
+    name = "Request an export or erasure of subscriber data",
+    skip_all,
+    fields(action=?form.action)
+)]
+// Every dependency is an extractor, so the handler signature grows with the features
+#[allow(clippy::too_many_arguments)]
+pub async fn request_data(
+    request: HttpRequest,
+    form: web::Form<DataRequestFormData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    templates: web::Data<EmailTemplates>,
+    links: web::Data<DataRequestLinks>,
+    signup_protection: web::Data<SignupProtection>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let DataRequestFormData {
+        email,
+        action,
+        website,
+        captcha_response,
+    } = form.into_inner();
+    let email = match SubscriberEmail::parse(email) {
+        Ok(email) => email,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other("/subscriptions/data"));
+        }
+    };
+    let sent = FlashMessage::info(format!(
+        "If we hold any data about {}, we have sent a link to that address.",
+        email.as_ref()
+    ));
+    // Like signups, data requests send an email to an address of the visitor's choosing
+    let origin = RequestOrigin::from_request(&request);
+    let outcome = signup_protection
+        .check(
+            &pool,
+            &SignupAttempt {
+                kind: AttemptKind::DataRequest,
+                email: &email,
+                ip_address: origin.ip_address.as_deref(),
+                honeypot: &website,
+                captcha_response: captcha_response.as_deref(),
+            },
+        )
+        .await
+        .map_err(e500)?;
+    match outcome {
+        SignupOutcome::Accepted => {}
+        SignupOutcome::Honeypot => {
+            sent.send();
+            return Ok(see_other("/subscriptions/data"));
+        }
+        SignupOutcome::CaptchaFailed => {
+            FlashMessage::error("We could not verify that you are not a robot.").send();
+            return Ok(see_other("/subscriptions/data"));
+        }
+        SignupOutcome::RateLimitedByIp | SignupOutcome::RateLimitedByDomain => {
+            return Err(AppError::RateLimited.into())
+        }
+    }
+    // Only the owner of the address gets to know whether we hold anything about it
+    if export_subscriber_data(&pool, email.as_ref())
+        .await
+        .map_err(e500)?
+        .is_some()
+    {
+        let message = templates
+            .render_data_request(action, &links.link(action, email.as_ref()))
+            .context("Failed to render the data request email")
+            .map_err(e500)?;
+        email_client
+            .send_email(&email, "Your data request", &message.html, &message.text)
+            .await
+            .context("Failed to send the data request email")
+            .map_err(e500)?;
+    }
+    sent.send();
+    Ok(see_other("/subscriptions/data"))
+}
+
+/// Check that a token was issued by us, for this action, and has not expired
+fn verify(links: &DataRequestLinks, token: &str, action: DataRequestAction) -> Option<DataRequest> {
+    links
+        .verify(token)
+        .ok()
+        .filter(|request| request.action == action)
+}
+
+#[tracing::instrument(name = "Export subscriber data", skip_all)]
+pub async fn export_data(
+    parameters: web::Query<TokenParameters>,
+    pool: web::Data<PgPool>,
+    links: web::Data<DataRequestLinks>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(request) = verify(&links, &parameters.token, DataRequestAction::Export) else {
+        return Err(AppError::Unauthorized(None).into());
+    };
+    match export_subscriber_data(&pool, &request.email)
+        .await
+        .map_err(e500)?
+    {
+        Some(data) => Ok(export_response(&data)),
+        None => Err(AppError::NotFound.into()),
+    }
+}
+
+// Erasure takes a second, explicit step: link scanners in mail clients follow every link
+// they see, and must not be able to erase anything by doing so.
+pub async fn confirm_erasure_form(
+    parameters: web::Query<TokenParameters>,
+    links: web::Data<DataRequestLinks>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(request) = verify(&links, &parameters.token, DataRequestAction::Erase) else {
+        return Err(AppError::Unauthorized(None).into());
+    };
+    html::render(&ConfirmErasureTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        email: &request.email,
+        token: &parameters.token,
+    })
+}
+
+#[tracing::instrument(name = "Erase subscriber data", skip_all)]
+pub async fn erase_data(
+    form: web::Form<TokenParameters>,
+    pool: web::Data<PgPool>,
+    links: web::Data<DataRequestLinks>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(request) = verify(&links, &form.token, DataRequestAction::Erase) else {
+        return Err(AppError::Unauthorized(None).into());
+    };
+    erase_subscriber_data(&pool, &hmac_secret.0, &request.email)
+        .await
+        .map_err(e500)?;
+    FlashMessage::info("Your data has been erased.").send();
+    Ok(see_other("/subscriptions/data"))
+}
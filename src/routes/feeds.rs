@@ -0,0 +1,219 @@
+use crate::email_templates::{EmailTemplates, Issue};
+use crate::startup::ApplicationBaseUrl;
+use crate::utils::e500;
+use actix_web::http::header::{
+    ETag, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch, LastModified,
+};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use anyhow::Context;
+use atom_syndication::{ContentBuilder, EntryBuilder, FeedBuilder, LinkBuilder};
+use chrono::{DateTime, SubsecRound, TimeZone, Utc};
+use rss::{ChannelBuilder, GuidBuilder, ItemBuilder};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// Number of issues included in the feeds, newest first
+const FEED_LENGTH: i64 = 20;
+
+const FEED_TITLE: &str = "zero2prod newsletter";
+const FEED_DESCRIPTION: &str = "Published issues of the zero2prod newsletter";
+
+struct FeedIssue {
+    newsletter_issue_id: Uuid,
+    title: String,
+    slug: String,
+    text_content: String,
+    html_content: String,
+    published_at: DateTime<Utc>,
+}
+
+/// An issue as it appears in a feed, with its content rendered for an anonymous reader
+struct FeedEntry {
+    id: Uuid,
+    title: String,
+    url: String,
+    content: String,
+    published_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(name = "Serve the RSS feed", skip_all)]
+pub async fn rss_feed(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let entries = get_feed_entries(&pool, &templates).await.map_err(e500)?;
+    let items = entries
+        .iter()
+        .map(|entry| {
+            ItemBuilder::default()
+                .title(entry.title.clone())
+                .link(entry.url.clone())
+                .guid(
+                    GuidBuilder::default()
+                        .value(entry.url.clone())
+                        .permalink(true)
+                        .build(),
+                )
+                .pub_date(entry.published_at.to_rfc2822())
+                .description(entry.content.clone())
+                .build()
+        })
+        .collect::<Vec<_>>();
+    let channel = ChannelBuilder::default()
+        .title(FEED_TITLE)
+        .link(format!("{}/issues", base_url.0))
+        .description(FEED_DESCRIPTION)
+        .last_build_date(last_updated(&entries).map(|date| date.to_rfc2822()))
+        .items(items)
+        .build();
+    Ok(feed_response(
+        &request,
+        "application/rss+xml; charset=utf-8",
+        channel.to_string(),
+        last_updated(&entries),
+    ))
+}
+
+#[tracing::instrument(name = "Serve the Atom feed", skip_all)]
+pub async fn atom_feed(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let entries = get_feed_entries(&pool, &templates).await.map_err(e500)?;
+    let atom_entries = entries
+        .iter()
+        .map(|entry| {
+            EntryBuilder::default()
+                .id(format!("urn:uuid:{}", entry.id))
+                .title(entry.title.clone())
+                .link(LinkBuilder::default().href(entry.url.clone()).build())
+                .published(Some(entry.published_at.into()))
+                .updated(entry.published_at)
+                .content(Some(
+                    ContentBuilder::default()
+                        .content_type(Some("html".to_string()))
+                        .value(Some(entry.content.clone()))
+                        .build(),
+                ))
+                .build()
+        })
+        .collect::<Vec<_>>();
+    // Atom requires an `updated` timestamp even for an empty feed: fall back to a fixed
+    // date rather than `now()`, so that the ETag of an empty feed is stable
+    let updated = last_updated(&entries).unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap());
+    let feed = FeedBuilder::default()
+        .id(format!("{}/feed.atom", base_url.0))
+        .title(FEED_TITLE)
+        .subtitle(Some(FEED_DESCRIPTION.into()))
+        .updated(updated)
+        .link(
+            LinkBuilder::default()
+                .href(format!("{}/feed.atom", base_url.0))
+                .rel("self")
+                .build(),
+        )
+        .link(
+            LinkBuilder::default()
+                .href(format!("{}/issues", base_url.0))
+                .build(),
+        )
+        .entries(atom_entries)
+        .build();
+    Ok(feed_response(
+        &request,
+        "application/atom+xml; charset=utf-8",
+        feed.to_string(),
+        last_updated(&entries),
+    ))
+}
+
+fn last_updated(entries: &[FeedEntry]) -> Option<DateTime<Utc>> {
+    entries.iter().map(|entry| entry.published_at).max()
+}
+
+/// Wrap a rendered feed in a response, honouring conditional GETs.
+/// The ETag is a hash of the body, so hiding an issue from the archive changes it as well.
+fn feed_response(
+    request: &HttpRequest,
+    content_type: &str,
+    body: String,
+    last_updated: Option<DateTime<Utc>>,
+) -> HttpResponse {
+    let etag = EntityTag::new_strong(hex::encode(Sha256::digest(body.as_bytes())));
+    // HTTP dates have a one second resolution: a sub-second `published_at` would always be
+    // later than the `Last-Modified` we handed out, and never match `If-Modified-Since`
+    let last_modified =
+        last_updated.map(|date| HttpDate::from(SystemTime::from(date.trunc_subsecs(0))));
+
+    let not_modified = match request.get_header::<IfNoneMatch>() {
+        // `If-None-Match` takes precedence over `If-Modified-Since`, see RFC 9110 13.2.2
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => match (request.get_header::<IfModifiedSince>(), last_modified) {
+            (Some(IfModifiedSince(since)), Some(last_modified)) => last_modified <= since,
+            _ => false,
+        },
+    };
+
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response.insert_header(ETag(etag));
+    if let Some(last_modified) = last_modified {
+        response.insert_header(LastModified(last_modified));
+    }
+    if not_modified {
+        response.finish()
+    } else {
+        response.content_type(content_type).body(body)
+    }
+}
+
+async fn get_feed_entries(
+    pool: &PgPool,
+    templates: &EmailTemplates,
+) -> Result<Vec<FeedEntry>, anyhow::Error> {
+    let issues = sqlx::query_as!(
+        FeedIssue,
+        r#"
+        SELECT newsletter_issue_id, title, slug, text_content, html_content, published_at
+        FROM newsletter_issues
+        WHERE status = 'published' AND NOT hidden_from_archive
+        ORDER BY published_at DESC
+        LIMIT $1
+        "#,
+        FEED_LENGTH
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch newsletter issues for the feed")?;
+
+    issues
+        .into_iter()
+        .map(|issue| {
+            let content = templates
+                .render_archived_issue(&Issue {
+                    title: &issue.title,
+                    slug: &issue.slug,
+                    html_content: &issue.html_content,
+                    text_content: &issue.text_content,
+                })
+                .context("Failed to render a newsletter issue for the feed")?;
+            Ok(FeedEntry {
+                id: issue.newsletter_issue_id,
+                url: templates.issue_url(&issue.slug),
+                title: issue.title,
+                content,
+                published_at: issue.published_at,
+            })
+        })
+        .collect()
+}
@@ -1,5 +1,146 @@
-use actix_web::{HttpResponse, Responder};
+use crate::issue_delivery_worker::{last_heartbeat, WORKER_NAME};
+use crate::startup::MIGRATOR;
+use actix_web::{web, HttpResponse, Responder};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// How long a dependency gets to answer before we call it down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/// The worker checks in every few seconds, and whenever it is done waiting on an empty queue
+const WORKER_HEARTBEAT_TIMEOUT: chrono::Duration = chrono::Duration::seconds(60);
 
 pub async fn health_check() -> impl Responder {
     HttpResponse::Ok().finish()
 }
+
+/// The process is up and serving requests: restart it if this ever fails
+pub async fn liveness() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "up" }))
+}
+
+#[derive(serde::Serialize)]
+struct Readiness {
+    status: &'static str,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+#[derive(serde::Serialize)]
+struct Check {
+    status: &'static str,
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Everything we need to serve requests is available: send traffic our way only if this succeeds.
+/// The delivery worker is reported too, but it runs in its own process: a stopped worker
+/// delays issues, it must not take the API out of rotation.
+#[tracing::instrument(name = "Check readiness", skip_all)]
+pub async fn readiness(pool: web::Data<PgPool>, redis: web::Data<redis::Client>) -> HttpResponse {
+    let (database, redis, migrations, worker) = tokio::join!(
+        check(check_database(&pool)),
+        check(check_redis(&redis)),
+        check(check_migrations(&pool)),
+        check(check_worker(&pool)),
+    );
+    let ready = [&database, &redis, &migrations]
+        .iter()
+        .all(|check| check.error.is_none());
+    let checks = BTreeMap::from([
+        ("database", database),
+        ("redis", redis),
+        ("migrations", migrations),
+        ("worker", worker),
+    ]);
+    if ready {
+        HttpResponse::Ok().json(Readiness {
+            status: "ready",
+            checks,
+        })
+    } else {
+        HttpResponse::ServiceUnavailable().json(Readiness {
+            status: "not_ready",
+            checks,
+        })
+    }
+}
+
+async fn check(probe: impl Future<Output = Result<(), anyhow::Error>>) -> Check {
+    let start = Instant::now();
+    let outcome = tokio::time::timeout(CHECK_TIMEOUT, probe).await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+    let error = match outcome {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => {
+            tracing::warn!(error.cause_chain = ?e, "A readiness check failed");
+            Some(e.to_string())
+        }
+        Err(_) => Some("Timed out".into()),
+    };
+    Check {
+        status: if error.is_none() { "up" } else { "down" },
+        latency_ms,
+        error,
+    }
+}
+
+async fn check_database(pool: &PgPool) -> Result<(), anyhow::Error> {
+    sqlx::query("SELECT 1")
+        .execute(pool)
+        .await
+        .context("Failed to query the database")?;
+    Ok(())
+}
+
+async fn check_redis(client: &redis::Client) -> Result<(), anyhow::Error> {
+    let mut connection = client
+        .get_async_connection()
+        .await
+        .context("Failed to connect to Redis")?;
+    redis::cmd("PING")
+        .query_async::<_, String>(&mut connection)
+        .await
+        .context("Failed to ping Redis")?;
+    Ok(())
+}
+
+/// Migrations we ship with, but that have not been applied to the database yet
+async fn check_migrations(pool: &PgPool) -> Result<(), anyhow::Error> {
+    // `_sqlx_migrations` is created by the first migration run, so it is not known at compile time
+    let applied: Vec<i64> =
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(pool)
+            .await
+            .context("Failed to read the applied migrations")?;
+    let pending: Vec<String> = MIGRATOR
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .map(|migration| migration.version.to_string())
+        .collect();
+    if pending.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Pending migrations: {}",
+            pending.join(", ")
+        ))
+    }
+}
+
+async fn check_worker(pool: &PgPool) -> Result<(), anyhow::Error> {
+    let last_seen_at = last_heartbeat(pool, WORKER_NAME)
+        .await
+        .context("Failed to read the worker heartbeat")?
+        .ok_or_else(|| anyhow::anyhow!("The delivery worker has never checked in"))?;
+    if Utc::now() - last_seen_at > WORKER_HEARTBEAT_TIMEOUT {
+        return Err(anyhow::anyhow!(
+            "The delivery worker was last seen at {}",
+            last_seen_at.to_rfc3339()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use crate::email_templates::{EmailTemplates, Issue};
+use crate::error::AppError;
+use crate::html;
+use crate::utils::e500;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use anyhow::Context;
+use askama::Template;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+/// Number of issues listed on each page of the archive
+const ISSUES_PER_PAGE: i64 = 10;
+
+#[derive(serde::Deserialize)]
+pub struct ArchiveParameters {
+    page: Option<u32>,
+}
+
+struct ArchivedIssue {
+    title: String,
+    slug: String,
+    published_at: DateTime<Utc>,
+}
+
+impl ArchivedIssue {
+    fn published_on(&self) -> String {
+        format_date(&self.published_at)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "issues/index.html")]
+struct ArchiveTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    issues: Vec<ArchivedIssue>,
+    previous_page: Option<u32>,
+    next_page: Option<u32>,
+}
+
+#[derive(Template)]
+#[template(path = "issues/issue.html")]
+struct IssueTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    title: String,
+    published_on: String,
+    content: String,
+}
+
+#[tracing::instrument(name = "Show the newsletter archive", skip_all)]
+pub async fn issues_archive(
+    parameters: web::Query<ArchiveParameters>,
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let page = parameters.page.unwrap_or(1).max(1);
+    let mut issues = get_archived_issues(&pool, page).await.map_err(e500)?;
+    // We fetch one issue more than we show to know whether there is a next page
+    let has_next_page = issues.len() as i64 > ISSUES_PER_PAGE;
+    issues.truncate(ISSUES_PER_PAGE as usize);
+    html::render(&ArchiveTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        issues,
+        previous_page: (page > 1).then(|| page - 1),
+        next_page: has_next_page.then(|| page + 1),
+    })
+}
+
+/// Show a single issue, looked up by either its id or its slug
+#[tracing::instrument(
+    name = "Show an archived newsletter issue",
+    skip(pool, templates, flash_messages)
+)]
+pub async fn issue_page(
+    issue: web::Path<String>,
+    pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let issue = match get_archived_issue(&pool, &issue).await.map_err(e500)? {
+        Some(issue) => issue,
+        None => return Err(AppError::NotFound.into()),
+    };
+    let content = templates
+        .render_archived_issue(&Issue {
+            title: &issue.title,
+            slug: &issue.slug,
+            html_content: &issue.html_content,
+            text_content: &issue.text_content,
+        })
+        .map_err(e500)?;
+    html::render(&IssueTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        published_on: format_date(&issue.published_at),
+        title: issue.title,
+        content,
+    })
+}
+
+pub fn format_date(date: &DateTime<Utc>) -> String {
+    date.format("%B %-d, %Y").to_string()
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_archived_issues(
+    pool: &PgPool,
+    page: u32,
+) -> Result<Vec<ArchivedIssue>, anyhow::Error> {
+    let issues = sqlx::query_as!(
+        ArchivedIssue,
+        r#"
+        SELECT title, slug, published_at
+        FROM newsletter_issues
+        WHERE status = 'published' AND NOT hidden_from_archive
+        ORDER BY published_at DESC
+        LIMIT $1 OFFSET $2
+        "#,
+        ISSUES_PER_PAGE + 1,
+        (i64::from(page) - 1) * ISSUES_PER_PAGE
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch archived newsletter issues")?;
+    Ok(issues)
+}
+
+struct StoredIssue {
+    title: String,
+    slug: String,
+    text_content: String,
+    html_content: String,
+    published_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_archived_issue(
+    pool: &PgPool,
+    id_or_slug: &str,
+) -> Result<Option<StoredIssue>, anyhow::Error> {
+    let issue = sqlx::query_as!(
+        StoredIssue,
+        r#"
+        SELECT title, slug, text_content, html_content, published_at
+        FROM newsletter_issues
+        WHERE
+            (newsletter_issue_id::text = $1 OR slug = $1)
+            AND status = 'published'
+            AND NOT hidden_from_archive
+        "#,
+        id_or_slug
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to fetch a newsletter issue")?;
+    Ok(issue)
+}
@@ -1,39 +1,18 @@
-use actix_web::{http::header::ContentType, HttpResponse};
-use actix_web_flash_messages::{IncomingFlashMessages, Level};
-use std::fmt::Write;
+use crate::html;
+use actix_web::HttpResponse;
+use actix_web_flash_messages::IncomingFlashMessages;
+use askama::Template;
 
-pub async fn login_form(flash_messages: IncomingFlashMessages) -> HttpResponse {
-    let mut error_html = String::new();
-    for m in flash_messages.iter().filter(|m| m.level() >= Level::Info) {
-        writeln!(error_html, "<p><i>{}</i></p>", m.content()).unwrap();
-    }
-    HttpResponse::Ok()
-        .content_type(ContentType::html())
-        .body(format!(
-            r#"<!DOCTYPE html>
-<html lang="en">
-  <head>
-    <meta http-equiv="content-type" content="text/html; charset=utf-8" />
-    <title>Login</title>
-  </head>
-  <body>
-    {error_html}
-    <form method="post" action="/login">
-      <label
-        >Username<input
-          type="text"
-          placeholder="Enter Username"
-          name="username"
-      /></label>
-      <label
-        >Password
-        <input type="password" placeholder="Enter Password" name="password" />
-      </label>
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+}
 
-      <button type="submit">Login</button>
-    </form>
-  </body>
-</html>
-        "#
-        ))
+pub async fn login_form(
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    html::render(&LoginTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+    })
 }
@@ -1,5 +1,5 @@
-use crate::authentication::{validate_credentials, AuthError, Credentials};
-use crate::routes::error_chain_fmt;
+use crate::authentication::{rotate_csrf_token, validate_credentials, AuthError, Credentials};
+use crate::error::AppError;
 use crate::session_state::TypedSession;
 
 use actix_web::error::InternalError;
@@ -21,53 +21,45 @@ pub async fn login(
     form: web::Form<FormData>,
     pool: web::Data<PgPool>,
     session: TypedSession,
-) -> Result<HttpResponse, InternalError<LoginError>> {
+) -> Result<HttpResponse, InternalError<AppError>> {
     let credentials = Credentials {
         username: form.0.username,
         password: form.0.password,
     };
 
-    tracing::Span::current().record("username", &tracing::field::display(&credentials.username));
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
     match validate_credentials(credentials, &pool).await {
         Ok(user_id) => {
-            tracing::Span::current().record("user_id", &tracing::field::display(&user_id));
+            tracing::Span::current().record("user_id", tracing::field::display(&user_id));
             // prevent session fixation attacks by rotating session token when user logs in
             session.renew();
             session
                 .insert_user_id(user_id)
-                .map_err(|e| login_redirect(LoginError::UnexpectedError(e.into())))?;
+                .map_err(|e| login_redirect(AppError::Unexpected(e.into())))?;
+            rotate_csrf_token(&session)
+                .map_err(|e| login_redirect(AppError::Unexpected(e.into())))?;
             Ok(HttpResponse::SeeOther()
                 .insert_header((LOCATION, "/admin/dashboard"))
                 .finish())
         }
         Err(e) => {
             let e = match e {
-                AuthError::InvalidCredentials(_) => LoginError::AuthError(e.into()),
-                AuthError::UnexpectedError(_) => LoginError::UnexpectedError(e.into()),
+                AuthError::InvalidCredentials(e) => {
+                    tracing::info!(error.cause_chain = ?e, "Rejected a login attempt");
+                    AppError::Unauthorized(None)
+                }
+                AuthError::UnexpectedError(e) => AppError::Unexpected(e),
             };
             Err(login_redirect(e))
         }
     }
 }
 
-fn login_redirect(e: LoginError) -> InternalError<LoginError> {
+// A redirect rather than an error page: wrapped, the error is left alone by `render_errors`
+fn login_redirect(e: AppError) -> InternalError<AppError> {
     FlashMessage::error(e.to_string()).send();
     let response = HttpResponse::SeeOther()
         .insert_header((LOCATION, "/login"))
         .finish();
     InternalError::from_response(e, response)
 }
-
-#[derive(thiserror::Error)]
-pub enum LoginError {
-    #[error("Authentication failed")]
-    AuthError(#[source] anyhow::Error),
-    #[error("Something went wrong")]
-    UnexpectedError(#[from] anyhow::Error),
-}
-
-impl std::fmt::Debug for LoginError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        error_chain_fmt(self, f)
-    }
-}
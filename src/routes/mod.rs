@@ -1,15 +1,35 @@
 mod admin;
+pub(crate) mod data_requests;
+mod feeds;
 pub(crate) mod health_check;
 mod home;
+pub(crate) mod issues;
 mod login;
 pub(crate) mod newsletter;
+pub(crate) mod signup;
 pub(crate) mod subscriptions;
 pub(crate) mod subscriptions_confirm;
+mod subscriptions_preferences;
+pub(crate) mod subscriptions_unsubscribe;
+mod tracking;
+mod webhooks;
 
 pub use admin::*;
+pub use data_requests::{
+    confirm_erasure_form, data_request_form, erase_data, export_data, request_data,
+};
+pub use feeds::{atom_feed, rss_feed};
 pub use health_check::*;
 pub use home::*;
+pub use issues::{issue_page, issues_archive};
 pub use login::*;
 pub use newsletter::*;
+pub use signup::{signup_widget, signup_widget_script, subscribe_page};
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_preferences::{
+    confirm_email_change, confirm_email_change_form, preferences_form, update_preferences,
+};
+pub use subscriptions_unsubscribe::{confirm_unsubscribe_form, unsubscribe};
+pub use tracking::{track_click, track_open};
+pub use webhooks::{postmark_webhook, WEBHOOK_SECRET_HEADER};
@@ -1,5 +1,4 @@
 mod get;
+pub use get::publish_newsletter_form;
 mod post;
-
-pub use get::*;
-pub use post::*;
+pub use post::publish_newsletter;
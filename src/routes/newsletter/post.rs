@@ -1,63 +1,34 @@
 use crate::authentication::UserId;
+use crate::domain::IssueSlug;
+use crate::email_templates::{EmailTemplates, Issue};
+use crate::html::sanitize_newsletter_template;
 use crate::idempotency::save_response;
 use crate::idempotency::try_processing;
 use crate::idempotency::IdempotencyKey;
 use crate::idempotency::NextAction;
-use crate::routes::error_chain_fmt;
+use crate::issue_delivery_worker::enqueue_delivery_tasks;
 use crate::utils::e400;
 use crate::utils::e500;
 use crate::utils::see_other;
 
-use actix_web::http::header;
-use actix_web::http::StatusCode;
 use actix_web::Either;
-use actix_web::{web, HttpResponse, ResponseError};
+use actix_web::{web, HttpResponse};
 use actix_web_flash_messages::FlashMessage;
 use anyhow::Context;
-use reqwest::header::HeaderValue;
 use sqlx::PgPool;
 use sqlx::Postgres;
 use sqlx::Transaction;
+use tracing_actix_web::RequestId;
 use uuid::Uuid;
 
-#[derive(thiserror::Error)]
-pub enum PublishError {
-    #[error(transparent)]
-    UnexpectedError(#[from] anyhow::Error),
-    #[error("Authentication failed")]
-    AuthError(#[source] anyhow::Error),
-}
-
-impl std::fmt::Debug for PublishError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        error_chain_fmt(self, f)
-    }
-}
-
-impl ResponseError for PublishError {
-    // Since we're providing a custom 'error_response', there's no longer any need to override 'status_code'
-    fn error_response(&self) -> HttpResponse {
-        match self {
-            PublishError::UnexpectedError(_) => {
-                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-            }
-            PublishError::AuthError(_) => {
-                let mut response = HttpResponse::new(StatusCode::UNAUTHORIZED);
-                let header_value = HeaderValue::from_str(r#"Basic realm="publish""#).unwrap();
-                response
-                    .headers_mut()
-                    .insert(header::WWW_AUTHENTICATE, header_value);
-                response
-            }
-        }
-    }
-}
-
 #[derive(Debug, serde::Deserialize)]
 pub struct BodyData {
     title: String,
     content: Content,
     idempotency_key: IdempotencyKey,
+    /// Inject an open tracking pixel and rewrite links for click tracking
+    #[serde(default)]
+    tracking_enabled: bool,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -72,15 +43,21 @@ pub struct FormData {
     html_content: String,
     text_content: String,
     idempotency_key: String,
+    #[serde(default)]
+    tracking_enabled: bool,
 }
 
 #[tracing::instrument(name = "Publish a newsletter", skip_all, fields(user_id=%&*user_id))]
 pub async fn publish_newsletter(
     body: Either<web::Form<FormData>, web::Json<BodyData>>,
     pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
     user_id: web::ReqData<UserId>,
+    request_id: RequestId,
 ) -> Result<HttpResponse, actix_web::Error> {
     let user_id = user_id.into_inner();
+    // API clients never see flash messages: they get what we removed in the response body
+    let json = matches!(body, Either::Right(_));
     let body: BodyData = match body {
         Either::Right(json) => BodyData {
             title: json.title.to_owned(),
@@ -89,6 +66,7 @@ pub async fn publish_newsletter(
                 text: json.content.text.to_owned(),
             },
             idempotency_key: json.idempotency_key.clone(),
+            tracking_enabled: json.tracking_enabled,
         },
         Either::Left(form) => BodyData {
             title: form.title.to_owned(),
@@ -97,9 +75,26 @@ pub async fn publish_newsletter(
                 text: form.text_content.to_owned(),
             },
             idempotency_key: form.idempotency_key.clone().try_into().map_err(e400)?,
+            tracking_enabled: form.tracking_enabled,
         },
     };
     let idempotency_key = body.idempotency_key;
+    let issue_id = Uuid::new_v4();
+    let slug = IssueSlug::new(&body.title, issue_id);
+    // Stored sanitized, and sanitized again once rendered: Tera tags may render to markup
+    let sanitized = sanitize_newsletter_template(&body.content.html);
+    let issue = Issue {
+        title: &body.title,
+        slug: slug.as_ref(),
+        html_content: &sanitized.html,
+        text_content: &body.content.text,
+    };
+
+    // Reject broken templates before fan-out, rather than failing for every subscriber in the worker
+    let mut removed_elements = sanitized.removed_elements;
+    removed_elements.extend(templates.validate_issue(&issue).map_err(e400)?);
+    removed_elements.sort();
+    removed_elements.dedup();
 
     let mut transaction = match try_processing(&pool, &idempotency_key, *user_id)
         .await
@@ -107,82 +102,94 @@ pub async fn publish_newsletter(
     {
         NextAction::StartProcessing(t) => t,
         NextAction::ReturnSavedResponse(saved_response) => {
-            success_message().send();
+            if !json {
+                send_flash_messages(&removed_elements);
+            }
             return Ok(saved_response);
         }
     };
 
-    let issue_id = insert_newsletter_issue(
+    insert_newsletter_issue(
         &mut transaction,
-        &body.title,
-        &body.content.html,
-        &body.content.text,
+        issue_id,
+        &issue,
+        body.tracking_enabled,
+        *request_id,
     )
     .await
     .context("Failed to store newsletter issue details")
     .map_err(e500)?;
 
-    enqueue_delivery_tasks(&mut transaction, issue_id)
+    enqueue_delivery_tasks(&mut transaction, issue_id, None, Some(*request_id))
         .await
         .context("Failed to enqueue delivery tasks")
         .map_err(e500)?;
 
-    let response = see_other("/admin/dashboard");
+    // Not a redirect: clients following it would throw the body away
+    let response = if json {
+        HttpResponse::Created().json(serde_json::json!({
+            "newsletter_issue_id": issue_id,
+            "removed_elements": removed_elements,
+        }))
+    } else {
+        see_other("/admin/dashboard")
+    };
     let response = save_response(transaction, &idempotency_key, *user_id, response)
         .await
         .map_err(e500)?;
-    success_message().send();
+    if !json {
+        send_flash_messages(&removed_elements);
+    }
     Ok(response)
 }
 
+fn send_flash_messages(removed_elements: &[String]) {
+    if !removed_elements.is_empty() {
+        removed_elements_message(removed_elements).send();
+    }
+    success_message().send();
+}
+
 fn success_message() -> FlashMessage {
     FlashMessage::info("The newsletter issue has been accepted - emails will go out shortly")
 }
 
+fn removed_elements_message(removed_elements: &[String]) -> FlashMessage {
+    FlashMessage::warning(format!(
+        "The following elements are not allowed and were removed from the HTML content: {}",
+        removed_elements.join(", ")
+    ))
+}
+
 #[tracing::instrument(skip_all)]
 async fn insert_newsletter_issue(
     transaction: &mut Transaction<'static, Postgres>,
-    title: &str,
-    html_content: &str,
-    text_content: &str,
-) -> Result<Uuid, sqlx::Error> {
-    let newsletter_issue_id = Uuid::new_v4();
+    newsletter_issue_id: Uuid,
+    issue: &Issue<'_>,
+    tracking_enabled: bool,
+    request_id: Uuid,
+) -> Result<(), sqlx::Error> {
     sqlx::query!(
         r#"
         INSERT INTO newsletter_issues (
             newsletter_issue_id,
             title,
+            slug,
             text_content,
             html_content,
-            published_at
-        ) VALUES ($1, $2, $3, $4, now())
+            status,
+            tracking_enabled,
+            published_at,
+            request_id
+        ) VALUES ($1, $2, $3, $4, $5, 'published', $6, now(), $7)
     "#,
         newsletter_issue_id,
-        title,
-        text_content,
-        html_content
-    )
-    .execute(transaction)
-    .await?;
-    Ok(newsletter_issue_id)
-}
-
-#[tracing::instrument(skip_all)]
-async fn enqueue_delivery_tasks(
-    transaction: &mut Transaction<'static, Postgres>,
-    newsletter_issue_id: Uuid,
-) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"
-        INSERT INTO issue_delivery_queue (
-            newsletter_issue_id,
-            subscriber_email
-        )
-        SELECT $1, email
-        FROM subscriptions
-        WHERE status = 'confirmed'
-    "#,
-        newsletter_issue_id
+        issue.title,
+        issue.slug,
+        issue.text_content,
+        issue.html_content,
+        tracking_enabled,
+        request_id
     )
     .execute(transaction)
     .await?;
@@ -0,0 +1,88 @@
+use crate::html;
+use crate::startup::ApplicationBaseUrl;
+use crate::subscription_history::CONSENT_TEXT;
+use crate::utils::e500;
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "signup/subscribe.html")]
+struct SubscribeTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    redirect_to: &'a str,
+    consent_text: &'a str,
+    subscribed: bool,
+}
+
+/// The same form as the hosted page, without our layout, to be shown in an iframe on other sites
+#[derive(Template)]
+#[template(path = "signup/widget.html")]
+struct WidgetTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    redirect_to: &'a str,
+    consent_text: &'a str,
+    subscribed: bool,
+}
+
+#[derive(Template)]
+#[template(path = "signup/widget.js", escape = "none")]
+struct WidgetScriptTemplate {
+    /// A JSON string, so that it can be dropped into the script as is
+    widget_url: String,
+}
+
+/// Set by `POST /subscriptions` when it sends the browser back to us
+#[derive(serde::Deserialize)]
+pub struct SignupOutcomeParameters {
+    subscription: Option<String>,
+}
+
+impl SignupOutcomeParameters {
+    fn subscribed(&self) -> bool {
+        self.subscription.as_deref() == Some("pending_confirmation")
+    }
+}
+
+pub async fn subscribe_page(
+    flash_messages: IncomingFlashMessages,
+    query: web::Query<SignupOutcomeParameters>,
+) -> Result<HttpResponse, actix_web::Error> {
+    html::render(&SubscribeTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        redirect_to: "/subscribe",
+        consent_text: CONSENT_TEXT,
+        subscribed: query.subscribed(),
+    })
+}
+
+// Browsers may not hand cookies to a page framed by another site, so the widget cannot rely
+// on flash messages alone to know that the subscription went through
+pub async fn signup_widget(
+    flash_messages: IncomingFlashMessages,
+    query: web::Query<SignupOutcomeParameters>,
+) -> Result<HttpResponse, actix_web::Error> {
+    html::render(&WidgetTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        redirect_to: "/subscribe/widget",
+        consent_text: CONSENT_TEXT,
+        subscribed: query.subscribed(),
+    })
+}
+
+/// A script for other sites to embed our signup widget with a single `<script>` tag
+pub async fn signup_widget_script(
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let widget_url =
+        serde_json::to_string(&format!("{}/subscribe/widget", base_url.0)).map_err(e500)?;
+    let script = WidgetScriptTemplate { widget_url }.render().map_err(e500)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/javascript; charset=utf-8")
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(3600),
+        ]))
+        .body(script))
+}
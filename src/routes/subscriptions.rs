@@ -1,44 +1,228 @@
-use actix_web::http::StatusCode;
-use actix_web::{web, HttpResponse, ResponseError};
+use actix_web::error::UrlencodedError;
+use actix_web::{web, Either, HttpMessage, HttpRequest, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
 use anyhow::Context;
 use chrono::Utc;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use secrecy::Secret;
 use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
 use crate::{
+    allowed_origins::AllowedOrigins,
+    data_requests::was_erased,
     domain::{NewSubscriber, SubscriberEmail, SubscriberName},
     email_client::EmailClient,
-    startup::ApplicationBaseUrl,
+    email_templates::EmailTemplates,
+    error::{form_error, AppError, FieldErrors},
+    signup_protection::{AttemptKind, SignupAttempt, SignupOutcome, SignupProtection},
+    startup::{ApplicationBaseUrl, HmacSecret},
+    subscription_history::{record_subscription_event, RequestOrigin, SubscriptionEvent},
+    suppression::is_suppressed,
+    utils::see_other,
 };
 
+const SUBSCRIBED_MESSAGE: &str =
+    "Thanks for subscribing! Please check your inbox to confirm your subscription.";
+
+/// A subscription request, sent either by our own form or as JSON by an embedded widget
 #[derive(serde::Deserialize)]
 pub struct FormData {
+    // Missing fields are reported like invalid ones, rather than as a deserialization error
+    #[serde(default)]
     email: String,
+    #[serde(default)]
     name: String,
+    /// Honeypot: hidden from humans by the subscription form, so only bots fill it in
+    #[serde(default)]
+    website: String,
+    /// What the CAPTCHA widget handed to the browser, if CAPTCHAs are enabled
+    captcha_response: Option<String>,
+    /// Set by our own forms, which show `CONSENT_TEXT` next to the subscribe button
+    #[serde(default)]
+    consent_shown: bool,
+    /// Where to send the browser back to once we are done, instead of answering with JSON.
+    /// It must be one of our own pages or be on an allowed origin.
+    redirect_to: Option<String>,
 }
 
 impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
+    type Error = FieldErrors;
 
     fn try_from(form: FormData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(form.name)?;
-        let email = SubscriberEmail::parse(form.email)?;
-        Ok(NewSubscriber { email, name })
+        // Check every field, so that all the problems can be reported at once
+        match (
+            SubscriberName::parse(form.name),
+            SubscriberEmail::parse(form.email),
+        ) {
+            (Ok(name), Ok(email)) => Ok(NewSubscriber { email, name }),
+            (name, email) => {
+                let mut errors = FieldErrors::default();
+                if let Err(e) = name {
+                    errors.add("name", e);
+                }
+                if let Err(e) = email {
+                    errors.add("email", e);
+                }
+                Err(errors)
+            }
+        }
     }
 }
 
-#[tracing::instrument(name="Adding a new subscriber", skip(form, pool, email_client, base_url), fields(subscriber_email=%form.email, subscriber_name=%form.name))]
+/// `subscribe` reads the body as a form first, then as JSON, and a body that is neither is
+/// reported with the error of the form: JSON that did not parse would be blamed on its content type
+pub fn subscription_body_error(error: UrlencodedError, request: &HttpRequest) -> actix_web::Error {
+    if matches!(error, UrlencodedError::ContentType) && request.content_type() == "application/json"
+    {
+        return AppError::BadRequest("The request body is not valid JSON.".into()).into();
+    }
+    form_error(error, request)
+}
+
+#[tracing::instrument(
+    name = "Adding a new subscriber",
+    skip_all,
+    fields(subscriber_email = tracing::field::Empty, subscriber_name = tracing::field::Empty)
+)]
+// Every dependency is an extractor, so the handler signature grows with the features
+#[allow(clippy::too_many_arguments)]
 pub async fn subscribe(
-    form: web::Form<FormData>,
+    request: HttpRequest,
+    body: Either<web::Form<FormData>, web::Json<FormData>>,
     pool: web::Data<PgPool>,
     email_client: web::Data<EmailClient>,
+    templates: web::Data<EmailTemplates>,
     base_url: web::Data<ApplicationBaseUrl>,
-) -> Result<HttpResponse, SubscribeError> {
-    // `web::Form` is a wrapper around `FormData`
-    // `form.0` gives us access to the underlying `FormData`
+    signup_protection: web::Data<SignupProtection>,
+    allowed_origins: web::Data<AllowedOrigins>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, AppError> {
+    let mut form = match body {
+        Either::Left(form) => form.into_inner(),
+        Either::Right(json) => json.into_inner(),
+    };
+    tracing::Span::current()
+        .record("subscriber_email", tracing::field::display(&form.email))
+        .record("subscriber_name", tracing::field::display(&form.name));
+    // Check the target first: we cannot send anybody back to a site we do not know
+    let redirect_to = match form.redirect_to.take() {
+        Some(target) => match allowed_origins.redirect_target(&target) {
+            Some(url) => Some(url),
+            None => {
+                let mut errors = FieldErrors::default();
+                errors.add(
+                    "redirect_to",
+                    format!("{} is not one of our allowed sites.", target),
+                );
+                return Err(AppError::Validation(errors));
+            }
+        },
+        None => None,
+    };
+    let result = process_subscription(
+        &request,
+        form,
+        &pool,
+        &email_client,
+        &templates,
+        &base_url.0,
+        &signup_protection,
+        &hmac_secret.0,
+    )
+    .await;
+    let Some(mut url) = redirect_to else {
+        return result;
+    };
+
+    // Browsers posting a plain HTML form are sent back where they came from. The outcome is both
+    // flashed, for our own pages, and added to the query string, for pages on other sites.
+    match result {
+        Ok(_) => {
+            FlashMessage::info(SUBSCRIBED_MESSAGE).send();
+            url.query_pairs_mut()
+                .append_pair("subscription", "pending_confirmation");
+        }
+        Err(e) => {
+            // The error does not make it to the response, and so to the request logs
+            if let AppError::Unexpected(_) = e {
+                tracing::error!("{:?}", e);
+            }
+            let message = e.to_string();
+            FlashMessage::error(&message).send();
+            url.query_pairs_mut()
+                .append_pair("subscription", "error")
+                .append_pair("message", &message);
+        }
+    }
+    Ok(see_other(&allowed_origins.location(&url)))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_subscription(
+    request: &HttpRequest,
+    mut form: FormData,
+    pool: &PgPool,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    base_url: &str,
+    signup_protection: &SignupProtection,
+    hmac_secret: &Secret<String>,
+) -> Result<HttpResponse, AppError> {
+    let honeypot = std::mem::take(&mut form.website);
+    let captcha_response = form.captcha_response.take();
+    let consent_shown = form.consent_shown;
     // since we implemented 'TryFrom<FormData> for NewSubscriber', we can just use try_into()
-    let new_subscriber = form.0.try_into().map_err(SubscribeError::ValidationError)?;
+    let new_subscriber: NewSubscriber = form.try_into().map_err(AppError::Validation)?;
+
+    let origin = RequestOrigin::from_request(request);
+    let outcome = signup_protection
+        .check(
+            pool,
+            &SignupAttempt {
+                kind: AttemptKind::Signup,
+                email: &new_subscriber.email,
+                ip_address: origin.ip_address.as_deref(),
+                honeypot: &honeypot,
+                captcha_response: captcha_response.as_deref(),
+            },
+        )
+        .await
+        .context("Failed to check the signup attempt")?;
+    match outcome {
+        SignupOutcome::Accepted => {}
+        // Bots are told that it worked, so that they have no reason to adapt
+        SignupOutcome::Honeypot => return Ok(HttpResponse::Ok().finish()),
+        SignupOutcome::CaptchaFailed => {
+            let mut errors = FieldErrors::default();
+            errors.add(
+                "captcha_response",
+                "We could not verify that you are not a robot.".into(),
+            );
+            return Err(AppError::Validation(errors));
+        }
+        SignupOutcome::RateLimitedByIp | SignupOutcome::RateLimitedByDomain => {
+            return Err(AppError::RateLimited)
+        }
+    }
+
+    // Answer exactly as if the subscription went through: whether an address is suppressed
+    // is nobody's business but ours
+    if is_suppressed(pool, hmac_secret, new_subscriber.email.as_ref())
+        .await
+        .context("Failed to check the suppression list")?
+    {
+        tracing::info!("Ignoring a subscription request for a suppressed address");
+        return Ok(HttpResponse::Ok().finish());
+    }
+
+    // Coming back after an erasure is fine, but it is worth knowing about
+    let previously_erased = was_erased(pool, hmac_secret, new_subscriber.email.as_ref())
+        .await
+        .context("Failed to look for an erased subscriber")?;
+    if previously_erased {
+        tracing::warn!("A previously erased subscriber is subscribing again");
+    }
 
     let subscription_token = generate_subscription_token();
 
@@ -55,6 +239,15 @@ pub async fn subscribe(
             store_token(&mut transaction, subscriber_id, subscription_token.as_str())
                 .await
                 .context("Failed to store confirmation token for new subscriber")?;
+            record_subscription_event(
+                &mut transaction,
+                subscriber_id,
+                SubscriptionEvent::Subscribed { consent_shown },
+                &serde_json::json!({ "previously_erased": previously_erased }),
+                Some(&origin),
+            )
+            .await
+            .context("Failed to record the subscription as evidence of consent")?;
 
             // commit the tx before sending the email
             transaction
@@ -64,9 +257,10 @@ pub async fn subscribe(
 
             // send confirmation email to the potential subscriber
             send_confirmation_email(
-                &email_client,
+                email_client,
+                templates,
                 new_subscriber,
-                &base_url.0,
+                base_url,
                 &subscription_token,
             )
             .await
@@ -74,41 +268,13 @@ pub async fn subscribe(
 
             Ok(HttpResponse::Ok().finish())
         }
-        Err(e) => match e {
-            sqlx::Error::Database(err) => {
-                if err.constraint() == Some("subscriptions_email_key") {
-                    tracing::info!("User is already subscribed");
-                    Ok(HttpResponse::NoContent().finish())
-                } else {
-                    Ok(HttpResponse::InternalServerError().finish())
-                }
-            }
-            _ => Ok(HttpResponse::InternalServerError().finish()),
-        },
-    }
-}
-
-#[derive(thiserror::Error)]
-pub enum SubscribeError {
-    #[error("{0}")]
-    ValidationError(String),
-
-    #[error(transparent)]
-    UnexpectedError(#[from] anyhow::Error),
-}
-
-impl std::fmt::Debug for SubscribeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        error_chain_fmt(self, f)
-    }
-}
-
-impl ResponseError for SubscribeError {
-    fn status_code(&self) -> StatusCode {
-        match self {
-            SubscribeError::ValidationError(_) => StatusCode::BAD_REQUEST,
-            SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        Err(sqlx::Error::Database(e)) if e.constraint() == Some("subscriptions_email_key") => {
+            tracing::info!("User is already subscribed");
+            Ok(HttpResponse::NoContent().finish())
         }
+        Err(e) => Err(anyhow::Error::new(e)
+            .context("Failed to insert new subscriber in the database")
+            .into()),
     }
 }
 
@@ -138,30 +304,30 @@ pub async fn insert_subscriber(
 
 #[tracing::instrument(
     name = "send a confirmation email to new subscriber",
-    skip(email_client, new_subscriber)
+    skip(email_client, templates, new_subscriber)
 )]
 pub async fn send_confirmation_email(
     email_client: &EmailClient,
+    templates: &EmailTemplates,
     new_subscriber: NewSubscriber,
     base_url: &str,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
+) -> Result<(), anyhow::Error> {
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, subscription_token,
     );
-    let plain_body = format!(
-        "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
-        confirmation_link
-    );
-    let html_body = format!("Welcome to our newsletter!<br />Click <a href=\"{}\">here</a> to confirm your subscription",confirmation_link);
+    let email = templates
+        .render_confirmation(new_subscriber.name.as_ref(), &confirmation_link)
+        .context("Failed to render the confirmation email")?;
 
     email_client
-        .send_email(&new_subscriber.email, "Welcome!", &html_body, &plain_body)
-        .await
+        .send_email(&new_subscriber.email, "Welcome!", &email.html, &email.text)
+        .await?;
+    Ok(())
 }
 
-fn generate_subscription_token() -> String {
+pub fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat_with(|| rng.sample(Alphanumeric))
         .map(char::from)
@@ -177,7 +343,7 @@ pub async fn store_token(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: Uuid,
     subscription_token: &str,
-) -> Result<(), StoreTokenError> {
+) -> Result<(), sqlx::Error> {
     sqlx::query!(
         r#"INSERT INTO subscription_tokens 
     (subscription_token, subscriber_id)
@@ -187,43 +353,6 @@ pub async fn store_token(
         subscriber_id
     )
     .execute(transaction)
-    .await
-    .map_err(StoreTokenError)?;
-    Ok(())
-}
-
-pub struct StoreTokenError(sqlx::Error);
-
-impl std::error::Error for StoreTokenError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        Some(&self.0)
-    }
-}
-
-impl std::fmt::Debug for StoreTokenError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        error_chain_fmt(self, f)
-    }
-}
-
-impl std::fmt::Display for StoreTokenError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "a database error was encountered while trying to store a subscription token"
-        )
-    }
-}
-
-pub fn error_chain_fmt(
-    e: &impl std::error::Error,
-    f: &mut std::fmt::Formatter<'_>,
-) -> std::fmt::Result {
-    writeln!(f, "{}\n", e)?;
-    let mut current = e.source();
-    while let Some(cause) = current {
-        writeln!(f, "Caused by:\n\t{}", cause)?;
-        current = cause.source();
-    }
+    .await?;
     Ok(())
 }
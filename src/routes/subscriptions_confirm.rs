@@ -1,5 +1,8 @@
+use crate::error::AppError;
+use crate::subscription_history::{record_subscription_event, RequestOrigin, SubscriptionEvent};
 use actix_web::web;
-use actix_web::HttpResponse;
+use actix_web::{HttpRequest, HttpResponse};
+use anyhow::Context;
 use sqlx::PgPool;
 use uuid::Uuid;
 
@@ -8,22 +11,24 @@ pub struct Parameters {
     pub subscription_token: String,
 }
 
-#[tracing::instrument(name = "Confirm a pending subscription", skip(parameters, pool))]
-pub async fn confirm(parameters: web::Query<Parameters>, pool: web::Data<PgPool>) -> HttpResponse {
-    let id = match get_subscriber_id_from_token(&pool, &parameters.subscription_token).await {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
-    match id {
-        None => HttpResponse::Unauthorized().finish(),
-        Some(subscriber_id) => {
-            if confirm_subscriber(&pool, subscriber_id).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
-            }
-            HttpResponse::Ok().finish()
-        }
-    };
-    HttpResponse::Ok().finish()
+#[tracing::instrument(
+    name = "Confirm a pending subscription",
+    skip(request, parameters, pool)
+)]
+pub async fn confirm(
+    request: HttpRequest,
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    let subscriber_id = get_subscriber_id_from_token(&pool, &parameters.subscription_token)
+        .await
+        .context("Failed to retrieve the subscriber id associated with the provided token")?
+        .ok_or(AppError::Unauthorized(None))?;
+    let origin = RequestOrigin::from_request(&request);
+    confirm_subscriber(&pool, subscriber_id, Some(&origin))
+        .await
+        .context("Failed to update the subscriber status to `confirmed`")?;
+    Ok(HttpResponse::Ok().finish())
 }
 
 async fn get_subscriber_id_from_token(
@@ -43,17 +48,41 @@ async fn get_subscriber_id_from_token(
     Ok(result.map(|r| r.subscriber_id))
 }
 
+/// Returns `false` if the subscription was not waiting for a confirmation
 #[tracing::instrument(name = "confirm subscription in database", skip(pool, subscriber_id))]
-async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        "UPDATE subscriptions SET status = 'confirmed' WHERE id = $1",
+pub async fn confirm_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    origin: Option<&RequestOrigin>,
+) -> Result<bool, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    // Only a pending subscription can be confirmed: clicking the link again must not
+    // resurrect a subscription that has since been unsubscribed or bounced
+    let confirmed = sqlx::query!(
+        r#"
+        UPDATE subscriptions SET status = 'confirmed'
+        WHERE id = $1 AND status = 'pending_confirmation'
+        "#,
         subscriber_id
     )
-    .execute(pool)
+    .execute(&mut transaction)
     .await
     .map_err(|e| {
         tracing::error!("failed to execute query: {:?}", e);
         e
-    })?;
-    Ok(())
+    })?
+    .rows_affected()
+        > 0;
+    if confirmed {
+        record_subscription_event(
+            &mut transaction,
+            subscriber_id,
+            SubscriptionEvent::Confirmed,
+            &serde_json::json!({}),
+            origin,
+        )
+        .await?;
+    }
+    transaction.commit().await?;
+    Ok(confirmed)
 }
@@ -0,0 +1,462 @@
+use crate::domain::{DeliveryFrequency, EmailFormat, SubscriberEmail, SubscriberName};
+use crate::email_client::EmailClient;
+use crate::email_templates::EmailTemplates;
+use crate::error::AppError;
+use crate::html;
+use crate::issue_delivery_worker::reschedule_deliveries;
+use crate::routes::subscriptions::generate_subscription_token;
+use crate::startup::{ApplicationBaseUrl, HmacSecret};
+use crate::subscription_history::{record_subscription_event, RequestOrigin, SubscriptionEvent};
+use crate::suppression::is_suppressed;
+use crate::utils::{e500, see_other};
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web_flash_messages::{FlashMessage, IncomingFlashMessages};
+use anyhow::Context;
+use askama::Template;
+use secrecy::Secret;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+/// How long the link sent to a new address stays valid
+const EMAIL_CHANGE_VALIDITY_HOURS: i32 = 24;
+/// How many confirmation links a subscriber can have us send within `EMAIL_CHANGE_VALIDITY_HOURS`.
+/// Each one is an email to an address of their choosing, so without a cap a subscription token
+/// would be a way to send our mail to anybody.
+const MAX_EMAIL_CHANGE_REQUESTS: i64 = 3;
+
+struct Preferences {
+    id: Uuid,
+    email: String,
+    name: String,
+    preferred_format: String,
+    delivery_frequency: String,
+}
+
+#[derive(Template)]
+#[template(path = "subscriptions/preferences.html")]
+struct PreferencesTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    subscription_token: &'a str,
+    preferences: Preferences,
+}
+
+#[derive(serde::Deserialize)]
+pub struct Parameters {
+    subscription_token: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PreferencesFormData {
+    subscription_token: String,
+    name: String,
+    email: String,
+    preferred_format: EmailFormat,
+    delivery_frequency: DeliveryFrequency,
+}
+
+#[derive(Template)]
+#[template(path = "subscriptions/confirm_email.html")]
+struct ConfirmEmailChangeTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    new_email: &'a str,
+    token: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+pub struct EmailChangeParameters {
+    token: String,
+}
+
+fn preferences_url(subscription_token: &str) -> String {
+    format!(
+        "/subscriptions/preferences?subscription_token={}",
+        subscription_token
+    )
+}
+
+pub async fn preferences_form(
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(preferences) = get_preferences(&pool, &parameters.subscription_token)
+        .await
+        .map_err(e500)?
+    else {
+        return Err(AppError::Unauthorized(None).into());
+    };
+    html::render(&PreferencesTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        subscription_token: &parameters.subscription_token,
+        preferences,
+    })
+}
+
+#[tracing::instrument(
+    name = "Update subscriber preferences",
+    skip_all,
+    fields(subscriber_id=tracing::field::Empty)
+)]
+pub async fn update_preferences(
+    form: web::Form<PreferencesFormData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let form = form.into_inner();
+    let Some(current) = get_preferences(&pool, &form.subscription_token)
+        .await
+        .map_err(e500)?
+    else {
+        return Err(AppError::Unauthorized(None).into());
+    };
+    tracing::Span::current().record("subscriber_id", tracing::field::display(current.id));
+    let redirect = see_other(&preferences_url(&form.subscription_token));
+
+    let name = match SubscriberName::parse(form.name) {
+        Ok(name) => name,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(redirect);
+        }
+    };
+    let new_email = if form.email.trim().eq_ignore_ascii_case(&current.email) {
+        None
+    } else {
+        match SubscriberEmail::parse(form.email.trim().to_owned()) {
+            Ok(email) => Some(email),
+            Err(e) => {
+                FlashMessage::error(e).send();
+                return Ok(redirect);
+            }
+        }
+    };
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")
+        .map_err(e500)?;
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET name = $2, preferred_format = $3, delivery_frequency = $4
+        WHERE id = $1
+        "#,
+        current.id,
+        name.as_ref(),
+        form.preferred_format.as_str(),
+        form.delivery_frequency.as_str()
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to update the preferences of a subscriber")
+    .map_err(e500)?;
+    // Issues already queued follow the new frequency
+    if current.delivery_frequency != form.delivery_frequency.as_str() {
+        reschedule_deliveries(&mut transaction, current.id)
+            .await
+            .context("Failed to reschedule pending deliveries")
+            .map_err(e500)?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the preferences of a subscriber")
+        .map_err(e500)?;
+    FlashMessage::info("Your preferences have been saved.").send();
+
+    if let Some(new_email) = new_email {
+        let mut connection = pool
+            .acquire()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool")
+            .map_err(e500)?;
+        if !is_available(
+            &mut connection,
+            &hmac_secret.0,
+            current.id,
+            new_email.as_ref(),
+        )
+        .await
+        .map_err(e500)?
+        {
+            FlashMessage::error(format!(
+                "{} cannot be used for this subscription.",
+                new_email.as_ref()
+            ))
+            .send();
+            return Ok(redirect);
+        }
+        if recent_email_change_requests(&pool, current.id)
+            .await
+            .map_err(e500)?
+            >= MAX_EMAIL_CHANGE_REQUESTS
+        {
+            FlashMessage::error(
+                "You have asked to change your address too many times. Please try again tomorrow.",
+            )
+            .send();
+            return Ok(redirect);
+        }
+        request_email_change(
+            &pool,
+            &email_client,
+            &templates,
+            &base_url.0,
+            current.id,
+            &name,
+            &new_email,
+        )
+        .await
+        .map_err(e500)?;
+        FlashMessage::info(format!(
+            "We have sent a confirmation link to {}. Your address will change once you follow it.",
+            new_email.as_ref()
+        ))
+        .send();
+    }
+    Ok(redirect)
+}
+
+// Following the link only asks for confirmation, as for unsubscribing: link scanners must not
+// change anybody's address.
+pub async fn confirm_email_change_form(
+    parameters: web::Query<EmailChangeParameters>,
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let new_email = sqlx::query!(
+        r#"
+        SELECT r.new_email
+        FROM email_change_requests r
+        JOIN subscriptions s ON s.id = r.subscriber_id
+        WHERE r.token = $1
+            AND r.requested_at > now() - make_interval(hours => $2)
+            AND s.status = 'confirmed'
+        "#,
+        parameters.token,
+        EMAIL_CHANGE_VALIDITY_HOURS
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to look up the email change request")
+    .map_err(e500)?;
+    let Some(new_email) = new_email else {
+        return Err(AppError::Unauthorized(None).into());
+    };
+    html::render(&ConfirmEmailChangeTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        new_email: &new_email.new_email,
+        token: &parameters.token,
+    })
+}
+
+#[tracing::instrument(name = "Confirm a change of email address", skip_all)]
+pub async fn confirm_email_change(
+    request: HttpRequest,
+    form: web::Form<EmailChangeParameters>,
+    pool: web::Data<PgPool>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")
+        .map_err(e500)?;
+    let change = sqlx::query!(
+        r#"
+        SELECT r.subscriber_id, r.new_email, s.email AS previous_email
+        FROM email_change_requests r
+        JOIN subscriptions s ON s.id = r.subscriber_id
+        WHERE r.token = $1
+            AND r.requested_at > now() - make_interval(hours => $2)
+            AND s.status = 'confirmed'
+        FOR UPDATE
+        "#,
+        form.token,
+        EMAIL_CHANGE_VALIDITY_HOURS
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to look up the email change request")
+    .map_err(e500)?;
+    let Some(change) = change else {
+        return Err(AppError::Unauthorized(None).into());
+    };
+    // The address may have been suppressed or taken since the change was requested
+    if !is_available(
+        &mut transaction,
+        &hmac_secret.0,
+        change.subscriber_id,
+        &change.new_email,
+    )
+    .await
+    .map_err(e500)?
+    {
+        return Err(AppError::Conflict("This address cannot be used anymore.".into()).into());
+    }
+
+    let updated = sqlx::query!(
+        "UPDATE subscriptions SET email = $2 WHERE id = $1",
+        change.subscriber_id,
+        change.new_email
+    )
+    .execute(&mut transaction)
+    .await;
+    if let Err(sqlx::Error::Database(e)) = &updated {
+        if e.constraint() == Some("subscriptions_email_key") {
+            return Err(AppError::Conflict("This address is already subscribed.".into()).into());
+        }
+    }
+    updated
+        .context("Failed to change the email of a subscriber")
+        .map_err(e500)?;
+    // Issues waiting to be delivered follow the subscriber to their new address
+    sqlx::query!(
+        "UPDATE issue_delivery_queue SET subscriber_email = $2 WHERE subscriber_email = $1",
+        change.previous_email,
+        change.new_email
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to update pending deliveries")
+    .map_err(e500)?;
+    sqlx::query!(
+        "DELETE FROM email_change_requests WHERE subscriber_id = $1",
+        change.subscriber_id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to delete email change requests")
+    .map_err(e500)?;
+    record_subscription_event(
+        &mut transaction,
+        change.subscriber_id,
+        SubscriptionEvent::EmailChanged,
+        &serde_json::json!({ "previous_email": change.previous_email }),
+        Some(&RequestOrigin::from_request(&request)),
+    )
+    .await
+    .context("Failed to record the change of email")
+    .map_err(e500)?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the change of email")
+        .map_err(e500)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body("Your email address has been updated."))
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_preferences(
+    pool: &PgPool,
+    subscription_token: &str,
+) -> Result<Option<Preferences>, sqlx::Error> {
+    sqlx::query_as!(
+        Preferences,
+        r#"
+        SELECT s.id, s.email, s.name, s.preferred_format, s.delivery_frequency
+        FROM subscriptions s
+        JOIN subscription_tokens t ON t.subscriber_id = s.id
+        WHERE t.subscription_token = $1 AND s.status = 'confirmed'
+        "#,
+        subscription_token
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[tracing::instrument(skip(pool))]
+async fn recent_email_change_requests(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<i64, anyhow::Error> {
+    let count = sqlx::query!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM email_change_requests
+        WHERE subscriber_id = $1 AND requested_at > now() - make_interval(hours => $2)
+        "#,
+        subscriber_id,
+        EMAIL_CHANGE_VALIDITY_HOURS
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to count recent email change requests")?
+    .count;
+    Ok(count)
+}
+
+/// Whether a subscriber can move to this address: nobody else uses it and we may email it
+#[tracing::instrument(skip(connection, secret))]
+async fn is_available(
+    connection: &mut PgConnection,
+    secret: &Secret<String>,
+    subscriber_id: Uuid,
+    email: &str,
+) -> Result<bool, anyhow::Error> {
+    if is_suppressed(&mut *connection, secret, email).await? {
+        return Ok(false);
+    }
+    let taken = sqlx::query!(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM subscriptions WHERE lower(email) = lower($1) AND id != $2
+        ) AS "taken!"
+        "#,
+        email,
+        subscriber_id
+    )
+    .fetch_one(connection)
+    .await?
+    .taken;
+    Ok(!taken)
+}
+
+#[tracing::instrument(skip(pool, email_client, templates, base_url, name))]
+async fn request_email_change(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    base_url: &str,
+    subscriber_id: Uuid,
+    name: &SubscriberName,
+    new_email: &SubscriberEmail,
+) -> Result<(), anyhow::Error> {
+    let token = generate_subscription_token();
+    sqlx::query!(
+        r#"
+        INSERT INTO email_change_requests (token, subscriber_id, new_email)
+        VALUES ($1, $2, $3)
+        "#,
+        token,
+        subscriber_id,
+        new_email.as_ref()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store the email change request")?;
+    let confirmation_link = format!(
+        "{}/subscriptions/preferences/confirm-email?token={}",
+        base_url, token
+    );
+    let email = templates
+        .render_email_change(name.as_ref(), &confirmation_link)
+        .context("Failed to render the email change confirmation")?;
+    email_client
+        .send_email(
+            new_email,
+            "Confirm your new address",
+            &email.html,
+            &email.text,
+        )
+        .await
+        .context("Failed to send the email change confirmation")?;
+    Ok(())
+}
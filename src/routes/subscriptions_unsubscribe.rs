@@ -0,0 +1,130 @@
+use actix_web::http::header::ContentType;
+use actix_web::web;
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use askama::Template;
+use sqlx::PgPool;
+
+use crate::error::AppError;
+use crate::html;
+use crate::subscription_history::{record_subscription_event, RequestOrigin, SubscriptionEvent};
+use crate::suppression::{suppress_email, SuppressionSource};
+use crate::utils::e500;
+use anyhow::Context;
+
+#[derive(Template)]
+#[template(path = "subscriptions/unsubscribe.html")]
+struct ConfirmUnsubscribeTemplate<'a> {
+    flash_messages: Vec<&'a str>,
+    subscription_token: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+pub struct Parameters {
+    pub subscription_token: String,
+}
+
+// Following the link only asks for confirmation: link scanners and mail clients that prefetch
+// links must not unsubscribe anybody. Mail clients that offer one-click unsubscription POST to
+// the same URL, as advertised by the `List-Unsubscribe-Post` header of every issue.
+pub async fn confirm_unsubscribe_form(
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    if !is_known_token(&pool, &parameters.subscription_token)
+        .await
+        .map_err(e500)?
+    {
+        return Err(AppError::Unauthorized(None).into());
+    }
+    html::render(&ConfirmUnsubscribeTemplate {
+        flash_messages: html::flash_messages(&flash_messages),
+        subscription_token: &parameters.subscription_token,
+    })
+}
+
+#[tracing::instrument(name = "Unsubscribe a subscriber", skip(request, parameters, pool))]
+pub async fn unsubscribe(
+    request: HttpRequest,
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let origin = RequestOrigin::from_request(&request);
+    let unsubscribed = unsubscribe_subscriber(&pool, &parameters.subscription_token, &origin)
+        .await
+        .map_err(e500)?;
+    if !unsubscribed {
+        return Err(AppError::Unauthorized(None).into());
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body("You have been unsubscribed."))
+}
+
+#[tracing::instrument(skip_all)]
+async fn is_known_token(pool: &PgPool, subscription_token: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM subscription_tokens WHERE subscription_token = $1
+        ) AS "known!"
+        "#,
+        subscription_token
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(row.known)
+}
+
+/// Mark the subscriber as unsubscribed and put their address on the suppression list.
+/// Returns `false` if the token does not match any subscriber.
+#[tracing::instrument(name = "Mark subscriber as unsubscribed in database", skip_all)]
+async fn unsubscribe_subscriber(
+    pool: &PgPool,
+    subscription_token: &str,
+    origin: &RequestOrigin,
+) -> Result<bool, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let unsubscribed = sqlx::query!(
+        r#"
+        UPDATE subscriptions SET status = 'unsubscribed'
+        WHERE id = (
+            SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1
+        )
+        RETURNING id, email
+        "#,
+        subscription_token
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to mark the subscriber as unsubscribed")?;
+    let Some(unsubscribed) = unsubscribed else {
+        return Ok(false);
+    };
+    suppress_email(
+        &mut transaction,
+        &unsubscribed.email,
+        SuppressionSource::Unsubscribe,
+        "Unsubscribed from the newsletter",
+    )
+    .await
+    .context("Failed to suppress the address")?;
+    record_subscription_event(
+        &mut transaction,
+        unsubscribed.id,
+        SubscriptionEvent::Unsubscribed,
+        &serde_json::json!({}),
+        Some(origin),
+    )
+    .await
+    .context("Failed to record the unsubscription")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the unsubscription")?;
+    Ok(true)
+}
@@ -0,0 +1,87 @@
+use crate::error::AppError;
+use crate::tracking::{TrackedEvent, Tracking};
+use actix_web::http::header::{CacheControl, CacheDirective, LOCATION};
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// A transparent 1x1 GIF
+const PIXEL: [u8; 43] = [
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+#[tracing::instrument(name = "Track an issue open", skip_all)]
+pub async fn track_open(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+    tracking: web::Data<Tracking>,
+) -> Result<HttpResponse, AppError> {
+    let event = match tracking.verify(&token) {
+        Ok(event @ TrackedEvent::Open { .. }) => event,
+        _ => return Err(AppError::NotFound),
+    };
+    record_event(&pool, &event).await;
+    Ok(HttpResponse::Ok()
+        .content_type("image/gif")
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .body(PIXEL.as_slice()))
+}
+
+#[tracing::instrument(name = "Track a link click", skip_all)]
+pub async fn track_click(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+    tracking: web::Data<Tracking>,
+) -> Result<HttpResponse, AppError> {
+    let event = tracking.verify(&token).map_err(|_| AppError::NotFound)?;
+    let TrackedEvent::Click { url, .. } = &event else {
+        return Err(AppError::NotFound);
+    };
+    record_event(&pool, &event).await;
+    Ok(HttpResponse::Found()
+        .insert_header((LOCATION, url.as_str()))
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .finish())
+}
+
+/// Store a tracking event. Failures are logged rather than surfaced: the reader should get
+/// their image or redirect regardless of whether we managed to count them.
+/// Nothing is stored if tracking has been disabled for the issue or the subscriber is gone.
+async fn record_event(pool: &PgPool, event: &TrackedEvent) {
+    let (newsletter_issue_id, subscriber_id, kind, url) = match event {
+        TrackedEvent::Open {
+            newsletter_issue_id,
+            subscriber_id,
+        } => (newsletter_issue_id, subscriber_id, "open", None),
+        TrackedEvent::Click {
+            newsletter_issue_id,
+            subscriber_id,
+            url,
+        } => (newsletter_issue_id, subscriber_id, "click", Some(url)),
+    };
+    let outcome = sqlx::query!(
+        r#"
+        INSERT INTO issue_tracking_events
+            (event_id, newsletter_issue_id, subscriber_id, kind, url)
+        SELECT $1, i.newsletter_issue_id, s.id, $4, $5
+        FROM newsletter_issues i, subscriptions s
+        WHERE i.newsletter_issue_id = $2 AND s.id = $3 AND i.tracking_enabled
+        "#,
+        Uuid::new_v4(),
+        newsletter_issue_id,
+        subscriber_id,
+        kind,
+        url
+    )
+    .execute(pool)
+    .await;
+    if let Err(e) = outcome {
+        tracing::error!(
+            error.cause_chain = ?e,
+            error.message = %e,
+            "Failed to record a tracking event"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use crate::configuration::PostmarkWebhookSettings;
+use crate::error::AppError;
+use crate::subscription_history::{record_subscription_event, SubscriptionEvent};
+use crate::suppression::{suppress_email, SuppressionSource};
+use crate::utils::constant_time_eq;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+
+pub const WEBHOOK_SECRET_HEADER: &str = "X-Webhook-Secret";
+
+/// Bounce types that mean the address will never accept our emails
+const PERMANENT_BOUNCE_TYPES: [&str; 2] = ["HardBounce", "BadEmailAddress"];
+
+/// The subset of Postmark's webhook payloads we act upon, see
+/// https://postmarkapp.com/developer/webhooks/webhooks-overview
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "RecordType")]
+enum PostmarkEvent {
+    Bounce {
+        #[serde(rename = "Type")]
+        bounce_type: String,
+        #[serde(rename = "Email")]
+        email: String,
+    },
+    SpamComplaint {
+        #[serde(rename = "Email")]
+        email: String,
+    },
+    /// Deliveries, opens, clicks... - acknowledged and ignored
+    #[serde(other)]
+    Other,
+}
+
+#[tracing::instrument(name = "Handle a Postmark webhook", skip_all)]
+pub async fn postmark_webhook(
+    request: HttpRequest,
+    body: web::Bytes,
+    pool: web::Data<PgPool>,
+    settings: web::Data<PostmarkWebhookSettings>,
+) -> Result<HttpResponse, AppError> {
+    let Some(secret) = &settings.secret else {
+        return Err(AppError::NotFound);
+    };
+    if !is_authenticated(request.headers(), &settings.username, secret) {
+        return Err(AppError::Unauthorized(Some(r#"Basic realm="webhooks""#)));
+    }
+    // Only parsed once authenticated: strangers get a 401, whatever they send us
+    let payload: serde_json::Value =
+        serde_json::from_slice(&body).map_err(|e| AppError::BadRequest(e.to_string()))?;
+    let event: PostmarkEvent =
+        serde_json::from_value(payload.clone()).map_err(|e| AppError::BadRequest(e.to_string()))?;
+    tracing::info!(?event, "Received a Postmark webhook");
+    let (email, subscription_event) = match event {
+        PostmarkEvent::Bounce { bounce_type, email } => {
+            if PERMANENT_BOUNCE_TYPES.contains(&bounce_type.as_str()) {
+                (email, SubscriptionEvent::Bounced)
+            } else {
+                (email, SubscriptionEvent::SoftBounced)
+            }
+        }
+        PostmarkEvent::SpamComplaint { email } => (email, SubscriptionEvent::Complained),
+        PostmarkEvent::Other => return Ok(HttpResponse::Ok().finish()),
+    };
+    apply_event(&pool, &email, subscription_event, &payload).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Suppress the address and update the subscription status (for permanent failures),
+/// then record the event in the subscription history.
+async fn apply_event(
+    pool: &PgPool,
+    email: &str,
+    event: SubscriptionEvent,
+    payload: &serde_json::Value,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let (new_status, suppression_source) = match event {
+        SubscriptionEvent::Bounced => (Some("bounced"), Some(SuppressionSource::Bounce)),
+        SubscriptionEvent::Complained => (Some("complained"), Some(SuppressionSource::Complaint)),
+        // Soft bounces are only recorded
+        _ => (None, None),
+    };
+    if let Some(source) = suppression_source {
+        let reason = format!("Reported by Postmark: {}", event.as_str());
+        suppress_email(&mut transaction, email, source, &reason)
+            .await
+            .context("Failed to suppress the address")?;
+    }
+    // Addresses are compared case-insensitively, so more than one subscription may match
+    let subscriber_ids = sqlx::query!(
+        r#"
+        UPDATE subscriptions SET status = COALESCE($2, status)
+        WHERE lower(email) = lower($1)
+        RETURNING id
+        "#,
+        email,
+        new_status
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to update the subscription status")?;
+    if subscriber_ids.is_empty() {
+        tracing::info!("Received a Postmark event about an address with no subscription");
+    }
+    for r in subscriber_ids {
+        record_subscription_event(&mut transaction, r.id, event, payload, None)
+            .await
+            .context("Failed to record the subscription event")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the subscription event")?;
+    Ok(())
+}
+
+fn is_authenticated(headers: &HeaderMap, username: &str, secret: &Secret<String>) -> bool {
+    let secret = secret.expose_secret();
+    if let Some(shared_secret) = headers
+        .get(WEBHOOK_SECRET_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        return constant_time_eq(shared_secret, secret);
+    }
+    match basic_authentication(headers) {
+        Some((given_username, password)) => {
+            given_username == username && constant_time_eq(&password, secret)
+        }
+        None => false,
+    }
+}
+
+fn basic_authentication(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_owned(), password.to_owned()))
+}
@@ -0,0 +1,24 @@
+use std::future::Future;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+
+/// Cancel `shutdown` on SIGTERM, which is how orchestrators ask us to stop, or on Ctrl-C
+pub fn cancel_on_signal(shutdown: CancellationToken) -> Result<(), std::io::Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => tracing::info!("Received SIGTERM, shutting down"),
+            _ = tokio::signal::ctrl_c() => tracing::info!("Received Ctrl-C, shutting down"),
+            _ = shutdown.cancelled() => return,
+        }
+        shutdown.cancel();
+    });
+    Ok(())
+}
+
+/// Whichever part of the process stops first, for whatever reason, takes the others with it
+pub async fn cancel_on_exit<T>(shutdown: CancellationToken, task: impl Future<Output = T>) -> T {
+    let output = task.await;
+    shutdown.cancel();
+    output
+}
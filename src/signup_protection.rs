@@ -0,0 +1,247 @@
+use crate::captcha::CaptchaClient;
+use crate::domain::SubscriberEmail;
+use crate::metrics::METRICS;
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// How long signup attempts are kept around for the admin dashboard
+const ATTEMPTS_RETENTION_DAYS: i32 = 7;
+
+/// What we made of a call to `POST /subscriptions`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignupOutcome {
+    Accepted,
+    /// A field hidden from humans was filled in
+    Honeypot,
+    CaptchaFailed,
+    RateLimitedByIp,
+    RateLimitedByDomain,
+}
+
+impl SignupOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SignupOutcome::Accepted => "accepted",
+            SignupOutcome::Honeypot => "honeypot",
+            SignupOutcome::CaptchaFailed => "captcha_failed",
+            SignupOutcome::RateLimitedByIp => "rate_limited_ip",
+            SignupOutcome::RateLimitedByDomain => "rate_limited_domain",
+        }
+    }
+}
+
+/// What an attempt is for: each kind has its own limit per domain, and they share the one per IP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttemptKind {
+    Signup,
+    /// An export or erasure of subscriber data, which also sends an email
+    DataRequest,
+}
+
+impl AttemptKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttemptKind::Signup => "signup",
+            AttemptKind::DataRequest => "data_request",
+        }
+    }
+}
+
+/// A signup as it reaches us, with the bits that only bots are expected to get wrong
+pub struct SignupAttempt<'a> {
+    pub kind: AttemptKind,
+    pub email: &'a SubscriberEmail,
+    pub ip_address: Option<&'a str>,
+    pub honeypot: &'a str,
+    pub captcha_response: Option<&'a str>,
+}
+
+/// Keeps `POST /subscriptions`, and `POST /subscriptions/data` which also emails an address of the
+/// visitor's choosing, from being used to bomb somebody's inbox
+pub struct SignupProtection {
+    max_attempts_per_ip: i64,
+    max_attempts_per_domain: i64,
+    window: Duration,
+    captcha: Option<CaptchaClient>,
+}
+
+impl SignupProtection {
+    pub fn new(
+        max_attempts_per_ip: i64,
+        max_attempts_per_domain: i64,
+        window: Duration,
+        captcha: Option<CaptchaClient>,
+    ) -> Self {
+        Self {
+            max_attempts_per_ip,
+            max_attempts_per_domain,
+            window,
+            captcha,
+        }
+    }
+
+    /// Decide whether a signup may go through, and record the attempt
+    #[tracing::instrument(name = "Check a signup attempt", skip_all, fields(outcome=tracing::field::Empty))]
+    pub async fn check(
+        &self,
+        pool: &PgPool,
+        attempt: &SignupAttempt<'_>,
+    ) -> Result<SignupOutcome, anyhow::Error> {
+        let domain = attempt.email.domain();
+        let outcome = self.outcome(pool, attempt, &domain).await?;
+        tracing::Span::current().record("outcome", outcome.as_str());
+        if outcome != SignupOutcome::Accepted {
+            tracing::warn!(outcome = outcome.as_str(), "Rejected a signup attempt");
+            if attempt.kind == AttemptKind::Signup {
+                METRICS
+                    .signup_rejections
+                    .with_label_values(&[outcome.as_str()])
+                    .inc();
+            }
+        }
+        record_signup_attempt(pool, attempt.kind, attempt.ip_address, &domain, outcome).await?;
+        Ok(outcome)
+    }
+
+    async fn outcome(
+        &self,
+        pool: &PgPool,
+        attempt: &SignupAttempt<'_>,
+        domain: &str,
+    ) -> Result<SignupOutcome, anyhow::Error> {
+        if !attempt.honeypot.is_empty() {
+            return Ok(SignupOutcome::Honeypot);
+        }
+        if let Some(captcha) = &self.captcha {
+            let passed = match attempt.captcha_response {
+                Some(response) if !response.is_empty() => {
+                    captcha.verify(response, attempt.ip_address).await?
+                }
+                _ => false,
+            };
+            if !passed {
+                return Ok(SignupOutcome::CaptchaFailed);
+            }
+        }
+        // Every attempt from an address counts, bots included: it is the bots we want to slow down
+        if let Some(ip_address) = attempt.ip_address {
+            if self.recent_attempts_from_ip(pool, ip_address).await? >= self.max_attempts_per_ip {
+                return Ok(SignupOutcome::RateLimitedByIp);
+            }
+        }
+        if self
+            .recent_attempts_for_domain(pool, attempt.kind, domain)
+            .await?
+            >= self.max_attempts_per_domain
+        {
+            return Ok(SignupOutcome::RateLimitedByDomain);
+        }
+        Ok(SignupOutcome::Accepted)
+    }
+
+    async fn recent_attempts_from_ip(
+        &self,
+        pool: &PgPool,
+        ip_address: &str,
+    ) -> Result<i64, sqlx::Error> {
+        let row = sqlx::query!(
+            r#"
+            SELECT COUNT(*) AS "count!"
+            FROM signup_attempts
+            WHERE ip_address = $1 AND attempted_at > now() - make_interval(secs => $2)
+            "#,
+            ip_address,
+            self.window.as_secs_f64()
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(row.count)
+    }
+
+    /// Only attempts of the same kind that went through count: rejected bots must not lock
+    /// real people out, nor may people asking for their data
+    async fn recent_attempts_for_domain(
+        &self,
+        pool: &PgPool,
+        kind: AttemptKind,
+        domain: &str,
+    ) -> Result<i64, sqlx::Error> {
+        let row = sqlx::query!(
+            r#"
+            SELECT COUNT(*) AS "count!"
+            FROM signup_attempts
+            WHERE
+                email_domain = $1
+                AND kind = $3
+                AND outcome = 'accepted'
+                AND attempted_at > now() - make_interval(secs => $2)
+            "#,
+            domain,
+            self.window.as_secs_f64(),
+            kind.as_str()
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(row.count)
+    }
+}
+
+#[tracing::instrument(skip(pool))]
+async fn record_signup_attempt(
+    pool: &PgPool,
+    kind: AttemptKind,
+    ip_address: Option<&str>,
+    email_domain: &str,
+    outcome: SignupOutcome,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO signup_attempts (kind, ip_address, email_domain, outcome)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        kind.as_str(),
+        ip_address,
+        email_domain,
+        outcome.as_str()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Forget the signup attempts we no longer need: returns how many were removed
+#[tracing::instrument(skip(pool))]
+pub async fn purge_signup_attempts(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let purged = sqlx::query!(
+        "DELETE FROM signup_attempts WHERE attempted_at < now() - make_interval(days => $1)",
+        ATTEMPTS_RETENTION_DAYS
+    )
+    .execute(pool)
+    .await?;
+    Ok(purged.rows_affected())
+}
+
+pub struct RejectedSignups {
+    pub outcome: String,
+    pub count: i64,
+}
+
+/// Rejected signups over the last day, by reason
+#[tracing::instrument(skip(pool))]
+pub async fn rejected_signups(pool: &PgPool) -> Result<Vec<RejectedSignups>, sqlx::Error> {
+    sqlx::query_as!(
+        RejectedSignups,
+        r#"
+        SELECT outcome, COUNT(*) AS "count!"
+        FROM signup_attempts
+        WHERE
+            kind = 'signup'
+            AND outcome != 'accepted'
+            AND attempted_at > now() - interval '1 day'
+        GROUP BY outcome
+        ORDER BY outcome
+        "#
+    )
+    .fetch_all(pool)
+    .await
+}
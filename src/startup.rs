@@ -1,26 +1,46 @@
 use std::net::TcpListener;
 
-use crate::authentication::reject_anonymous_users;
-use crate::configuration::{DatabaseSettings, Settings};
+use crate::allowed_origins::AllowedOrigins;
+use crate::authentication::{reject_anonymous_users, reject_invalid_csrf_tokens};
+use crate::configuration::{ApplicationSettings, DatabaseSettings, Settings};
+use crate::data_requests::DataRequestLinks;
 use crate::email_client::EmailClient;
+use crate::email_templates::EmailTemplates;
+use crate::error::{form_error, json_error, not_found, path_error, query_error, render_errors};
+use crate::metrics::{metrics_endpoint, record_http_metrics, DbPoolSize};
 use crate::routes::newsletter::{publish_newsletter, publish_newsletter_form};
 use crate::routes::{
-    admin_dashboard, change_password, change_password_form, confirm, health_check, home, log_out,
-    login, login_form, subscribe,
+    add_suppression, admin_dashboard, admin_erase_data, admin_export_data, atom_feed,
+    change_password, change_password_form, confirm, confirm_email_change,
+    confirm_email_change_form, confirm_erasure_form, confirm_unsubscribe_form, data_request_form,
+    delete_suppression, erase_data, export_data, export_subscriber_history, health_check, home,
+    issue_page, issues_archive, list_issues, list_subscribers, liveness, log_out, login,
+    login_form, postmark_webhook, preferences_form, readiness, request_data, rss_feed,
+    set_issue_visibility, signup_widget, signup_widget_script, subscribe, subscribe_page,
+    subscriber_history, subscription_body_error, suppressions_page, track_click, track_open,
+    unsubscribe, update_preferences,
 };
+use crate::telemetry::add_request_id_header;
+use crate::tracking::Tracking;
+use crate::trusted_proxies::TrustedProxies;
 
+use actix_cors::Cors;
 use actix_session::storage::RedisSessionStore;
 use actix_session::SessionMiddleware;
 use actix_web::cookie::Key;
 use actix_web::dev::Server;
+use actix_web::http::header;
 use actix_web::web::Data;
 use actix_web::{web, App, HttpServer};
 use actix_web_flash_messages::storage::CookieMessageStore;
 use actix_web_flash_messages::FlashMessagesFramework;
 use actix_web_lab::middleware::from_fn;
+use anyhow::Context;
 use secrecy::{ExposeSecret, Secret};
+use sqlx::migrate::Migrator;
 use sqlx::postgres::PgPoolOptions;
-use sqlx::PgPool;
+use sqlx::{Connection, PgConnection, PgPool};
+use tokio_util::sync::CancellationToken;
 use tracing_actix_web::TracingLogger;
 
 pub struct Application {
@@ -31,7 +51,8 @@ pub struct Application {
 impl Application {
     pub async fn build(configuration: Settings) -> Result<Self, anyhow::Error> {
         let connection_pool = get_connection_pool(&configuration.database);
-        let email_client = configuration.email_client.client();
+        let email_client = configuration.email_client.clone().client()?;
+        let templates = EmailTemplates::new(configuration.application.base_url.clone())?;
 
         let server_address = format!(
             "{}:{}",
@@ -43,9 +64,8 @@ impl Application {
             listener,
             connection_pool,
             email_client,
-            configuration.application.base_url,
-            configuration.application.hmac_secret,
-            configuration.redis_uri,
+            templates,
+            configuration,
         )
         .await?;
         Ok(Self { port, server })
@@ -53,7 +73,16 @@ impl Application {
     pub fn port(&self) -> u16 {
         self.port
     }
-    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+    /// Serve requests until `shutdown` is cancelled, then let those in flight finish
+    pub async fn run_until_stopped(
+        self,
+        shutdown: CancellationToken,
+    ) -> Result<(), std::io::Error> {
+        let handle = self.server.handle();
+        tokio::spawn(async move {
+            shutdown.cancelled().await;
+            handle.stop(true).await;
+        });
         self.server.await
     }
 }
@@ -67,17 +96,43 @@ async fn run(
     listener: TcpListener,
     connection_pool: PgPool,
     email_client: EmailClient,
-    base_url: String,
-    hmac_secret: Secret<String>,
-    redis_uri: Secret<String>,
+    templates: EmailTemplates,
+    configuration: Settings,
 ) -> Result<Server, anyhow::Error> {
+    let Settings {
+        application:
+            ApplicationSettings {
+                base_url,
+                hmac_secret,
+                shutdown_deadline_seconds,
+                trusted_proxies,
+                ..
+            },
+        postmark_webhook: postmark_webhook_settings,
+        signup_protection,
+        cors,
+        metrics,
+        redis_uri,
+        ..
+    } = configuration;
+    let allowed_origins = web::Data::new(AllowedOrigins::new(&base_url, &cors.allowed_origins)?);
     // web::Data wraps this as an Arc so that each worker can get a pointer to the PgConnection
     let connection_pool = web::Data::new(connection_pool);
     let email_client = web::Data::new(email_client);
+    let templates = web::Data::new(templates);
+    let postmark_webhook_settings = web::Data::new(postmark_webhook_settings);
+    let metrics_settings = web::Data::new(metrics);
+    let trusted_proxies = web::Data::new(TrustedProxies::new(trusted_proxies));
+    let signup_protection = web::Data::new(signup_protection.protection());
+    let tracking = web::Data::new(Tracking::new(base_url.clone(), hmac_secret.clone()));
+    let data_request_links =
+        web::Data::new(DataRequestLinks::new(base_url.clone(), hmac_secret.clone()));
     let base_url = web::Data::new(ApplicationBaseUrl(base_url));
     let secret_key = Key::from(hmac_secret.expose_secret().as_bytes());
     let message_store = CookieMessageStore::builder(secret_key.clone()).build();
     let message_framework = FlashMessagesFramework::builder(message_store).build();
+    // The session store keeps its connection to itself, so readiness checks ping Redis on their own
+    let redis_client = web::Data::new(redis::Client::open(redis_uri.expose_secret().as_str())?);
     let redis_store = RedisSessionStore::new(redis_uri.expose_secret())
         .await
         .map_err(|e| {
@@ -92,38 +147,215 @@ async fn run(
                 redis_store.clone(),
                 secret_key.clone(),
             ))
+            .wrap(from_fn(record_http_metrics))
+            .wrap(from_fn(render_errors))
+            .wrap(from_fn(add_request_id_header))
             .wrap(TracingLogger::default())
             .route("/health", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
+            .route("/health/live", web::get().to(liveness))
+            .route("/health/ready", web::get().to(readiness))
+            .route("/metrics", web::get().to(metrics_endpoint))
+            .service(
+                web::resource("/subscriptions")
+                    .wrap(signup_cors(allowed_origins.origins()))
+                    .app_data(web::FormConfig::default().error_handler(subscription_body_error))
+                    .route(web::post().to(subscribe)),
+            )
+            .route("/subscribe", web::get().to(subscribe_page))
+            .route("/subscribe/widget", web::get().to(signup_widget))
+            .route("/subscribe/widget.js", web::get().to(signup_widget_script))
             .route("/subscriptions/confirm", web::get().to(confirm))
+            .route(
+                "/subscriptions/unsubscribe",
+                web::get().to(confirm_unsubscribe_form),
+            )
+            .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
+            .route(
+                "/subscriptions/preferences",
+                web::get().to(preferences_form),
+            )
+            .route(
+                "/subscriptions/preferences",
+                web::post().to(update_preferences),
+            )
+            .route(
+                "/subscriptions/preferences/confirm-email",
+                web::get().to(confirm_email_change_form),
+            )
+            .route(
+                "/subscriptions/preferences/confirm-email",
+                web::post().to(confirm_email_change),
+            )
+            .route("/subscriptions/data", web::get().to(data_request_form))
+            .route("/subscriptions/data", web::post().to(request_data))
+            .route("/subscriptions/data/export", web::get().to(export_data))
+            .route(
+                "/subscriptions/data/erase",
+                web::get().to(confirm_erasure_form),
+            )
+            .route("/subscriptions/data/erase", web::post().to(erase_data))
+            .route("/issues", web::get().to(issues_archive))
+            .route("/issues/{issue}", web::get().to(issue_page))
+            .route("/feed.rss", web::get().to(rss_feed))
+            .route("/feed.atom", web::get().to(atom_feed))
+            .route("/t/o/{token}", web::get().to(track_open))
+            .route("/t/c/{token}", web::get().to(track_click))
+            .route("/webhooks/postmark", web::post().to(postmark_webhook))
             .route("/login", web::get().to(login_form))
             .route("/", web::get().to(home))
             .route("/login", web::post().to(login))
             .service(
                 web::scope("/admin")
+                    // middleware registered last runs first: anonymous users are redirected to
+                    // the login page before we look for a CSRF token
+                    .wrap(from_fn(reject_invalid_csrf_tokens))
                     .wrap(from_fn(reject_anonymous_users))
                     .route("/dashboard", web::get().to(admin_dashboard))
                     .route("/password", web::get().to(change_password_form))
                     .route("/password", web::post().to(change_password))
                     .route("/logout", web::post().to(log_out))
                     .route("/newsletters", web::get().to(publish_newsletter_form))
-                    .route("/newsletters", web::post().to(publish_newsletter)),
+                    .route("/newsletters", web::post().to(publish_newsletter))
+                    .route("/issues", web::get().to(list_issues))
+                    .route(
+                        "/issues/{issue_id}/visibility",
+                        web::post().to(set_issue_visibility),
+                    )
+                    .route("/subscribers", web::get().to(list_subscribers))
+                    .route(
+                        "/subscribers/{subscriber_id}",
+                        web::get().to(subscriber_history),
+                    )
+                    .route(
+                        "/subscribers/{subscriber_id}/history.json",
+                        web::get().to(export_subscriber_history),
+                    )
+                    .route("/data-requests/export", web::get().to(admin_export_data))
+                    .route("/data-requests/erase", web::post().to(admin_erase_data))
+                    .route("/suppressions", web::get().to(suppressions_page))
+                    .route("/suppressions", web::post().to(add_suppression))
+                    .route("/suppressions/remove", web::post().to(delete_suppression)),
             )
             .app_data(connection_pool.clone())
+            .app_data(redis_client.clone())
             .app_data(email_client.clone())
+            .app_data(templates.clone())
+            .app_data(tracking.clone())
+            .app_data(data_request_links.clone())
+            .app_data(postmark_webhook_settings.clone())
+            .app_data(metrics_settings.clone())
+            .app_data(Data::new(DbPoolSize(MAX_DB_CONNECTIONS)))
+            .app_data(trusted_proxies.clone())
+            .app_data(signup_protection.clone())
+            .app_data(allowed_origins.clone())
             .app_data(base_url.clone())
             .app_data(Data::new(HmacSecret(hmac_secret.clone())))
+            .app_data(web::JsonConfig::default().error_handler(json_error))
+            .app_data(web::FormConfig::default().error_handler(form_error))
+            .app_data(web::QueryConfig::default().error_handler(query_error))
+            .app_data(web::PathConfig::default().error_handler(path_error))
+            .default_service(web::to(not_found))
     })
+    // Signals are handled for the whole process, see `Application::run_until_stopped`
+    .disable_signals()
+    .shutdown_timeout(shutdown_deadline_seconds)
     .listen(listener)?
     .run();
     Ok(server)
 }
 
+/// Browsers on the allowed origins may post subscriptions, e.g. from a widget on a marketing site
+fn signup_cors(allowed_origins: &[String]) -> Cors {
+    allowed_origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        .allowed_methods(["POST"])
+        .allowed_header(header::CONTENT_TYPE)
+        .max_age(3600)
+}
+
 #[derive(Clone)]
 pub struct HmacSecret(pub Secret<String>);
 
+/// The migrations we ship with, embedded in the binary
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Held while we look at the schema and migrate it, so that instances starting together take turns
+const MIGRATIONS_LOCK_ID: i64 = 0x7a32_7072_6f64; // "z2prod"
+
+/// Make sure the schema is one we can work with. We refuse to run against a schema migrated
+/// by a newer release, and bring an older one up to date if `run_migrations` is set:
+/// otherwise migrations are left to `scripts/init_db.sh` or `zero2prod admin migrate`.
+#[tracing::instrument(skip(configuration))]
+pub async fn prepare_database(
+    configuration: &DatabaseSettings,
+    run_migrations: bool,
+) -> Result<(), anyhow::Error> {
+    // A connection of our own rather than one from a pool: the lock goes away with it
+    let mut connection = PgConnection::connect_with(&configuration.with_db())
+        .await
+        .context("Failed to connect to the database")?;
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(MIGRATIONS_LOCK_ID)
+        .execute(&mut connection)
+        .await
+        .context("Failed to take the migrations lock")?;
+
+    let unknown: Vec<String> = applied_migrations(&mut connection)
+        .await?
+        .into_iter()
+        .filter(|version| MIGRATOR.iter().all(|m| m.version != *version))
+        .map(|version| version.to_string())
+        .collect();
+    if !unknown.is_empty() {
+        anyhow::bail!(
+            "The database schema is newer than this release, which does not know about migrations {}",
+            unknown.join(", ")
+        );
+    }
+    if run_migrations {
+        tracing::info!("Applying pending migrations");
+        MIGRATOR
+            .run(&mut connection)
+            .await
+            .context("Failed to run the migrations")?;
+    }
+
+    connection
+        .close()
+        .await
+        .context("Failed to release the migrations lock")?;
+    Ok(())
+}
+
+async fn applied_migrations(connection: &mut PgConnection) -> Result<Vec<i64>, anyhow::Error> {
+    // The table is created by the first migration run
+    let migrated: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(&mut *connection)
+        .await
+        .context("Failed to look for the migrations table")?;
+    if !migrated {
+        return Ok(Vec::new());
+    }
+    sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+        .fetch_all(connection)
+        .await
+        .context("Failed to read the applied migrations")
+}
+
+/// The default size of a connection pool
+pub const MAX_DB_CONNECTIONS: u32 = 10;
+
 pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
+    get_connection_pool_of_size(configuration, MAX_DB_CONNECTIONS)
+}
+
+pub fn get_connection_pool_of_size(
+    configuration: &DatabaseSettings,
+    max_connections: u32,
+) -> PgPool {
     PgPoolOptions::new()
+        .max_connections(max_connections)
         .acquire_timeout(std::time::Duration::from_secs(2))
         .connect_lazy_with(configuration.with_db())
 }
@@ -0,0 +1,132 @@
+use crate::trusted_proxies::TrustedProxies;
+use actix_web::http::header::USER_AGENT;
+use actix_web::{web, HttpRequest};
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// What a prospective subscriber agrees to. It is shown next to our subscription forms
+/// and stored with the subscriptions made through them as evidence of consent - change it with care.
+pub const CONSENT_TEXT: &str = "I agree to receive the zero2prod newsletter by email. \
+    I can unsubscribe at any time using the link included in every issue.";
+
+/// Something that happened to a subscription, recorded in `subscription_events`
+#[derive(Debug, Clone, Copy)]
+pub enum SubscriptionEvent {
+    /// Someone asked to subscribe, agreeing to `CONSENT_TEXT` if one of our forms showed it to them
+    Subscribed { consent_shown: bool },
+    /// The subscriber clicked the link in the confirmation email
+    Confirmed,
+    /// The subscriber clicked the unsubscribe link
+    Unsubscribed,
+    /// Postmark reported a permanent delivery failure
+    Bounced,
+    /// Postmark reported a temporary delivery failure - the subscription is left untouched
+    SoftBounced,
+    /// The subscriber marked one of our emails as spam
+    Complained,
+    /// The subscriber moved to a new address, after confirming they own it
+    EmailChanged,
+}
+
+impl SubscriptionEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubscriptionEvent::Subscribed { .. } => "subscribed",
+            SubscriptionEvent::Confirmed => "confirmed",
+            SubscriptionEvent::Unsubscribed => "unsubscribed",
+            SubscriptionEvent::Bounced => "bounced",
+            SubscriptionEvent::SoftBounced => "soft_bounced",
+            SubscriptionEvent::Complained => "complained",
+            SubscriptionEvent::EmailChanged => "email_changed",
+        }
+    }
+}
+
+/// Where the request that triggered an event came from
+#[derive(Debug)]
+pub struct RequestOrigin {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl RequestOrigin {
+    pub fn from_request(request: &HttpRequest) -> Self {
+        Self {
+            ip_address: request
+                .app_data::<web::Data<TrustedProxies>>()
+                .map(|proxies| proxies.client_ip(request))
+                .unwrap_or_else(|| request.peer_addr().map(|peer| peer.ip()))
+                .map(|ip| ip.to_string()),
+            user_agent: request
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct StoredSubscriptionEvent {
+    pub event_type: String,
+    pub occurred_at: DateTime<Utc>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub consent_text: Option<String>,
+    pub details: serde_json::Value,
+}
+
+#[tracing::instrument(skip(transaction, details))]
+pub async fn record_subscription_event(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    event: SubscriptionEvent,
+    details: &serde_json::Value,
+    origin: Option<&RequestOrigin>,
+) -> Result<(), sqlx::Error> {
+    let consent_text = matches!(
+        event,
+        SubscriptionEvent::Subscribed {
+            consent_shown: true
+        }
+    )
+    .then_some(CONSENT_TEXT);
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_events
+            (event_id, subscriber_id, event_type, details, ip_address, user_agent, consent_text)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+        Uuid::new_v4(),
+        subscriber_id,
+        event.as_str(),
+        details,
+        origin.and_then(|o| o.ip_address.as_deref()),
+        origin.and_then(|o| o.user_agent.as_deref()),
+        consent_text
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// The history of a subscription, oldest first
+#[tracing::instrument(skip(pool))]
+pub async fn get_subscription_events(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Vec<StoredSubscriptionEvent>, sqlx::Error> {
+    sqlx::query_as!(
+        StoredSubscriptionEvent,
+        r#"
+        SELECT event_type, occurred_at, ip_address, user_agent, consent_text, details
+        FROM subscription_events
+        WHERE subscriber_id = $1
+        ORDER BY occurred_at
+        "#,
+        subscriber_id
+    )
+    .fetch_all(pool)
+    .await
+}
@@ -0,0 +1,119 @@
+use crate::data_requests::email_hash;
+use chrono::{DateTime, Utc};
+use secrecy::Secret;
+use sqlx::{PgExecutor, PgPool};
+
+/// Why an address ended up on the suppression list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuppressionSource {
+    /// Added by an admin
+    Manual,
+    Bounce,
+    Complaint,
+    Unsubscribe,
+}
+
+impl SuppressionSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SuppressionSource::Manual => "manual",
+            SuppressionSource::Bounce => "bounce",
+            SuppressionSource::Complaint => "complaint",
+            SuppressionSource::Unsubscribe => "unsubscribe",
+        }
+    }
+}
+
+pub struct SuppressedEmail {
+    pub email: String,
+    pub source: String,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Add an address to the suppression list. Suppressing an address twice keeps the first entry.
+#[tracing::instrument(skip(executor))]
+pub async fn suppress_email(
+    executor: impl PgExecutor<'_>,
+    email: &str,
+    source: SuppressionSource,
+    reason: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO suppressed_emails (email, source, reason)
+        VALUES (lower($1), $2, $3)
+        ON CONFLICT (email) DO NOTHING
+        "#,
+        email,
+        source.as_str(),
+        reason
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Erased subscribers are only known by the hash of their address, hence the `secret`
+#[tracing::instrument(skip(executor, secret))]
+pub async fn is_suppressed(
+    executor: impl PgExecutor<'_>,
+    secret: &Secret<String>,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT
+            EXISTS(SELECT 1 FROM suppressed_emails WHERE email = lower($1))
+            OR EXISTS(
+                SELECT 1 FROM erased_subscribers
+                WHERE email_hash = $2 AND suppression_source IS NOT NULL
+            ) AS "suppressed!"
+        "#,
+        email,
+        email_hash(secret, email)
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(row.suppressed)
+}
+
+/// Returns `false` if the address was not on the list
+#[tracing::instrument(skip(pool, secret))]
+pub async fn remove_suppression(
+    pool: &PgPool,
+    secret: &Secret<String>,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        WITH
+            listed AS (DELETE FROM suppressed_emails WHERE email = lower($1) RETURNING 1),
+            erased AS (
+                UPDATE erased_subscribers SET suppression_source = NULL
+                WHERE email_hash = $2 AND suppression_source IS NOT NULL
+                RETURNING 1
+            )
+        SELECT (SELECT COUNT(*) FROM listed) + (SELECT COUNT(*) FROM erased) AS "removed!"
+        "#,
+        email,
+        email_hash(secret, email)
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(row.removed > 0)
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn list_suppressions(pool: &PgPool) -> Result<Vec<SuppressedEmail>, sqlx::Error> {
+    sqlx::query_as!(
+        SuppressedEmail,
+        r#"
+        SELECT email, source, reason, created_at
+        FROM suppressed_emails
+        ORDER BY created_at DESC
+        "#
+    )
+    .fetch_all(pool)
+    .await
+}
@@ -1,20 +1,39 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header;
+use actix_web::HttpMessage;
+use actix_web_lab::middleware::Next;
+use opentelemetry::propagation::Injector;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use tokio::task::JoinHandle;
 use tracing::subscriber::set_global_default;
 use tracing::Subscriber;
+use tracing_actix_web::RequestId;
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry};
 
+use crate::configuration::OpenTelemetrySettings;
+
 /// Compose multiple layers into a 'tracing' subscriber
 /// # Implementation notes
 ///
 /// We are using `impl Subscriber` as a return type to avoid having to spell out the actual type of the returned subscriber, which is complex
 /// We need to explicitly call out that the returned subscriber is `Send` and `Sync` to make it possible to pass iit to `init_subscriber` later on.
+/// Spans are also handed to OpenTelemetry when a `tracer` is given, e.g. from `otlp_tracer_provider`.
 pub fn get_subscriber<Sink>(
     name: String,
     env_filter: String,
     sink: Sink,
+    tracer: Option<Tracer>,
 ) -> impl Subscriber + Send + Sync
 where
     Sink: for<'a> MakeWriter<'a> + Send + Sync + 'static,
@@ -22,10 +41,13 @@ where
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
     let formatting_layer = BunyanFormattingLayer::new(name, sink);
+    let opentelemetry_layer =
+        tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
     Registry::default()
         .with(env_filter)
         .with(JsonStorageLayer)
         .with(formatting_layer)
+        .with(opentelemetry_layer)
 }
 
 /// Register a subscriber as a global default to process span data
@@ -34,6 +56,79 @@ where
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("Failed to set logger");
     set_global_default(subscriber).expect("failed to set tracing subscriber");
+    // W3C `traceparent` headers, both for incoming requests (see `TracingLogger`) and outgoing ones
+    global::set_text_map_propagator(TraceContextPropagator::new());
+}
+
+/// Export spans in batches to an OpenTelemetry collector, with OTLP over gRPC.
+/// It must be called from within a Tokio runtime, and be shut down before exiting to flush what is left.
+pub fn otlp_tracer_provider(
+    service_name: &str,
+    settings: &OpenTelemetrySettings,
+) -> Result<TracerProvider, anyhow::Error> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(&settings.endpoint)
+        .with_timeout(settings.timeout())
+        .build()?;
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            service_name.to_owned(),
+        )]))
+        .build())
+}
+
+/// Headers carrying the current trace context, for the services we call to join our traces
+pub fn trace_context_headers() -> HeaderMap {
+    let context = tracing::Span::current().context();
+    let mut headers = HeaderMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers))
+    });
+    headers
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Hand the id `TracingLogger` gave to a request back to the client as `X-Request-Id`,
+/// so that a report about a response can be matched with its logs
+pub async fn add_request_id_header(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| header::HeaderValue::from_str(&id.to_string()).ok());
+    let Some(request_id) = request_id else {
+        return next.call(req).await;
+    };
+    let x_request_id = header::HeaderName::from_static("x-request-id");
+    match next.call(req).await {
+        Ok(mut response) => {
+            response.headers_mut().insert(x_request_id, request_id);
+            Ok(response)
+        }
+        // Middlewares fail with an error rather than with a response
+        Err(e) => {
+            let mut response = e.error_response();
+            response.headers_mut().insert(x_request_id, request_id);
+            Err(InternalError::from_response(e, response).into())
+        }
+    }
 }
 
 // Just copied trait bounds and signature from `spawn_blocking`
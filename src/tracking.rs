@@ -0,0 +1,243 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// Signs and verifies the tokens embedded in the tracking pixel and in rewritten links.
+/// A token is bound to an issue and a subscriber (and, for clicks, to the destination),
+/// so it can neither be forged nor reused to record events for somebody else.
+pub struct Tracking {
+    base_url: String,
+    secret: Secret<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrackedEvent {
+    Open {
+        newsletter_issue_id: Uuid,
+        subscriber_id: Uuid,
+    },
+    Click {
+        newsletter_issue_id: Uuid,
+        subscriber_id: Uuid,
+        url: String,
+    },
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid tracking token")]
+pub struct InvalidTrackingToken;
+
+impl Tracking {
+    pub fn new(base_url: String, secret: Secret<String>) -> Self {
+        Self { base_url, secret }
+    }
+
+    /// Tracking for a single delivery of an issue
+    pub fn for_recipient(
+        &self,
+        newsletter_issue_id: Uuid,
+        subscriber_id: Uuid,
+    ) -> RecipientTracking<'_> {
+        RecipientTracking {
+            tracking: self,
+            newsletter_issue_id,
+            subscriber_id,
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<TrackedEvent, InvalidTrackingToken> {
+        let (payload, signature) = token.split_once('.').ok_or(InvalidTrackingToken)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| InvalidTrackingToken)?;
+        self.mac(payload.as_bytes())
+            .verify_slice(&signature)
+            .map_err(|_| InvalidTrackingToken)?;
+
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| InvalidTrackingToken)?;
+        let payload = String::from_utf8(payload).map_err(|_| InvalidTrackingToken)?;
+        // The url goes last: it is the only part that can contain the separator
+        let mut parts = payload.splitn(4, '|');
+        let kind = parts.next().ok_or(InvalidTrackingToken)?;
+        let mut next_id = || {
+            parts
+                .next()
+                .and_then(|id| Uuid::parse_str(id).ok())
+                .ok_or(InvalidTrackingToken)
+        };
+        let newsletter_issue_id = next_id()?;
+        let subscriber_id = next_id()?;
+        match (kind, parts.next()) {
+            ("o", None) => Ok(TrackedEvent::Open {
+                newsletter_issue_id,
+                subscriber_id,
+            }),
+            ("c", Some(url)) => Ok(TrackedEvent::Click {
+                newsletter_issue_id,
+                subscriber_id,
+                url: url.to_owned(),
+            }),
+            _ => Err(InvalidTrackingToken),
+        }
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(payload);
+        let signature = self.mac(payload.as_bytes()).finalize().into_bytes();
+        format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    fn mac(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC can take a key of any size");
+        mac.update(payload);
+        mac
+    }
+}
+
+pub struct RecipientTracking<'a> {
+    tracking: &'a Tracking,
+    newsletter_issue_id: Uuid,
+    subscriber_id: Uuid,
+}
+
+impl RecipientTracking<'_> {
+    pub fn pixel_url(&self) -> String {
+        let token = self.tracking.sign(&format!(
+            "o|{}|{}",
+            self.newsletter_issue_id, self.subscriber_id
+        ));
+        format!("{}/t/o/{}", self.tracking.base_url, token)
+    }
+
+    pub fn click_url(&self, url: &str) -> String {
+        let token = self.tracking.sign(&format!(
+            "c|{}|{}|{}",
+            self.newsletter_issue_id, self.subscriber_id, url
+        ));
+        format!("{}/t/c/{}", self.tracking.base_url, token)
+    }
+
+    /// Point every http(s) link of the content at the click tracking endpoint.
+    /// The content has been through `html::sanitize_newsletter_html`, so attributes are
+    /// always double-quoted and we do not need a full HTML parser to find them.
+    pub fn rewrite_links(&self, html: &str) -> String {
+        const HREF: &str = "href=\"";
+        let mut rewritten = String::with_capacity(html.len());
+        let mut rest = html;
+        while let Some(start) = rest.find(HREF) {
+            let value_start = start + HREF.len();
+            let Some(value_length) = rest[value_start..].find('"') else {
+                break;
+            };
+            let value = &rest[value_start..value_start + value_length];
+            rewritten.push_str(&rest[..value_start]);
+            match htmlescape::decode_html(value) {
+                Ok(url) if url.starts_with("http://") || url.starts_with("https://") => {
+                    rewritten.push_str(&self.click_url(&url));
+                }
+                _ => rewritten.push_str(value),
+            }
+            rest = &rest[value_start + value_length..];
+        }
+        rewritten.push_str(rest);
+        rewritten
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TrackedEvent, Tracking};
+    use claims::assert_err;
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn tracking() -> Tracking {
+        Tracking::new("http://127.0.0.1".into(), Secret::new("a-secret".into()))
+    }
+
+    fn token(url: &str) -> &str {
+        url.rsplit('/').next().unwrap()
+    }
+
+    #[test]
+    fn open_token_round_trips() {
+        let (issue_id, subscriber_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let tracking = tracking();
+        let url = tracking.for_recipient(issue_id, subscriber_id).pixel_url();
+        assert!(url.starts_with("http://127.0.0.1/t/o/"));
+        assert_eq!(
+            tracking.verify(token(&url)).unwrap(),
+            TrackedEvent::Open {
+                newsletter_issue_id: issue_id,
+                subscriber_id
+            }
+        );
+    }
+
+    #[test]
+    fn click_token_round_trips() {
+        let (issue_id, subscriber_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let tracking = tracking();
+        let url = tracking
+            .for_recipient(issue_id, subscriber_id)
+            .click_url("https://example.com/?a=1|2&b=3");
+        assert_eq!(
+            tracking.verify(token(&url)).unwrap(),
+            TrackedEvent::Click {
+                newsletter_issue_id: issue_id,
+                subscriber_id,
+                url: "https://example.com/?a=1|2&b=3".into()
+            }
+        );
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let tracking = tracking();
+        let url = tracking
+            .for_recipient(Uuid::new_v4(), Uuid::new_v4())
+            .pixel_url();
+        let (payload, signature) = token(&url).split_once('.').unwrap();
+        let forged_payload = {
+            let mut bytes = payload.as_bytes().to_vec();
+            bytes[3] = if bytes[3] == b'A' { b'B' } else { b'A' };
+            String::from_utf8(bytes).unwrap()
+        };
+        assert_err!(tracking.verify(&format!("{}.{}", forged_payload, signature)));
+        assert_err!(tracking.verify(payload));
+        assert_err!(tracking.verify("not-a-token"));
+    }
+
+    #[test]
+    fn tokens_signed_with_another_secret_are_rejected() {
+        let other = Tracking::new("http://127.0.0.1".into(), Secret::new("other".into()));
+        let url = other
+            .for_recipient(Uuid::new_v4(), Uuid::new_v4())
+            .pixel_url();
+        assert_err!(tracking().verify(token(&url)));
+    }
+
+    #[test]
+    fn only_http_links_are_rewritten() {
+        let tracking = tracking();
+        let recipient = tracking.for_recipient(Uuid::new_v4(), Uuid::new_v4());
+        let html = r#"<a href="https://example.com/?a=1&amp;b=2">x</a> <a href="mailto:me@example.com">y</a>"#;
+
+        let rewritten = recipient.rewrite_links(html);
+
+        assert!(!rewritten.contains("https://example.com"));
+        assert!(rewritten.contains(r#"<a href="mailto:me@example.com">y</a>"#));
+        let start = rewritten.find("/t/c/").unwrap() + "/t/c/".len();
+        let end = start + rewritten[start..].find('"').unwrap();
+        match tracking.verify(&rewritten[start..end]).unwrap() {
+            TrackedEvent::Click { url, .. } => assert_eq!(url, "https://example.com/?a=1&b=2"),
+            e => panic!("Expected a click, got {:?}", e),
+        }
+    }
+}
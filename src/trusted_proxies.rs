@@ -0,0 +1,107 @@
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// The load balancers and reverse proxies we sit behind.
+/// Only they get to tell us, through `X-Forwarded-For`, who the client is: anybody else could
+/// put whatever they like in that header.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<IpNet>) -> Self {
+        Self(networks)
+    }
+
+    fn trusts(&self, address: &IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(address))
+    }
+
+    /// The address of the client: the peer itself, unless it is one of our proxies.
+    /// Proxies append the address they got the request from to `X-Forwarded-For`, so we walk
+    /// the header from the right and stop at the first hop we do not trust.
+    pub fn client_ip(&self, request: &HttpRequest) -> Option<IpAddr> {
+        let mut client = request.peer_addr()?.ip();
+        let forwarded_for = request
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        for hop in forwarded_for.into_iter().rev() {
+            if !self.trusts(&client) {
+                break;
+            }
+            // Garbage cannot be trusted either: keep the last address we could make sense of
+            match hop.parse() {
+                Ok(address) => client = address,
+                Err(_) => break,
+            }
+        }
+        Some(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TrustedProxies;
+    use actix_web::test::TestRequest;
+    use std::net::{IpAddr, SocketAddr};
+
+    const PROXY: &str = "10.0.0.7:4000";
+
+    fn trusted_proxies() -> TrustedProxies {
+        TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()])
+    }
+
+    fn client_ip(peer: &str, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let mut request = TestRequest::default().peer_addr(peer.parse::<SocketAddr>().unwrap());
+        if let Some(forwarded_for) = forwarded_for {
+            request = request.insert_header(("X-Forwarded-For", forwarded_for));
+        }
+        trusted_proxies().client_ip(&request.to_http_request())
+    }
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_unless_the_peer_is_a_trusted_proxy() {
+        assert_eq!(
+            client_ip("203.0.113.9:4000", Some("198.51.100.1")),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn trusted_proxies_name_the_client() {
+        assert_eq!(client_ip(PROXY, Some("198.51.100.1")), ip("198.51.100.1"));
+        assert_eq!(
+            client_ip(PROXY, Some("198.51.100.1, 10.0.0.3")),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn addresses_prepended_by_the_client_are_ignored() {
+        assert_eq!(
+            client_ip(PROXY, Some("192.0.2.66, 198.51.100.1")),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn unparseable_hops_stop_the_walk() {
+        assert_eq!(
+            client_ip(PROXY, Some("198.51.100.1, not-an-ip")),
+            ip("10.0.0.7")
+        );
+    }
+
+    #[test]
+    fn a_proxy_without_forwarded_for_is_the_client() {
+        assert_eq!(client_ip(PROXY, None), ip("10.0.0.7"));
+    }
+}
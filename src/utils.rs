@@ -1,21 +1,21 @@
+use crate::error::AppError;
 use actix_web::http::header::LOCATION;
 use actix_web::HttpResponse;
 
 // Return a 400 with the user-representation of the validation error as the body
-// The error root cause is preserved for logging purposes
 pub fn e400<T>(e: T) -> actix_web::Error
 where
-    T: std::fmt::Debug + std::fmt::Display + 'static,
+    T: std::fmt::Display,
 {
-    actix_web::error::ErrorBadRequest(e)
+    AppError::BadRequest(e.to_string()).into()
 }
 
-//Return an opaque 500 while preserving the error root cause for logging
+// Return an opaque 500 while preserving the error root cause for logging
 pub fn e500<T>(e: T) -> actix_web::Error
 where
-    T: std::fmt::Debug + std::fmt::Display + 'static,
+    T: Into<anyhow::Error>,
 {
-    actix_web::error::ErrorInternalServerError(e)
+    AppError::Unexpected(e.into()).into()
 }
 
 pub fn see_other(location: &str) -> HttpResponse {
@@ -23,3 +23,12 @@ pub fn see_other(location: &str) -> HttpResponse {
         .insert_header((LOCATION, location))
         .finish()
 }
+
+// Compare secrets without short-circuiting, so response timings do not leak how much of a guess was right
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
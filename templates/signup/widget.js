@@ -0,0 +1,13 @@
+// Replaces every element marked with `data-newsletter-signup` with our signup form
+(function () {
+  var widgetUrl = {{ widget_url }};
+  document.querySelectorAll("[data-newsletter-signup]").forEach(function (element) {
+    var iframe = document.createElement("iframe");
+    iframe.src = widgetUrl;
+    iframe.title = "Subscribe to our newsletter";
+    iframe.style.border = "0";
+    iframe.style.width = "100%";
+    iframe.style.height = element.getAttribute("data-height") || "260px";
+    element.replaceChildren(iframe);
+  });
+})();
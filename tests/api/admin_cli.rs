@@ -0,0 +1,298 @@
+use crate::helpers::{
+    assert_is_redirected_to, create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app,
+    TestApp,
+};
+use secrecy::Secret;
+use uuid::Uuid;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+use zero2prod::admin::{
+    run_admin_command, AdminCommand, IdempotencyCommand, IssueCommand, SubscriberCommand,
+};
+use zero2prod::authentication::create_user;
+
+async fn run(app: &TestApp, command: AdminCommand) -> Result<(), anyhow::Error> {
+    run_admin_command(app.configuration.clone(), command).await
+}
+
+async fn subscriber_email(app: &TestApp) -> String {
+    sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .email
+}
+
+async fn publish_issue(app: &TestApp) -> Uuid {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.test_user.login(app).await;
+    let response = app
+        .post_newsletters_form(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as text",
+            "html_content": "<p>Newsletter body as html</p>",
+            "idempotency_key": Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_eq!(303, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+    sqlx::query!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .newsletter_issue_id
+}
+
+async fn queued_deliveries(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+#[tokio::test]
+async fn created_users_can_log_in() {
+    let app = spawn_app().await;
+
+    create_user(
+        "operator",
+        Secret::new("a-long-enough-password".into()),
+        &app.db_pool,
+    )
+    .await
+    .unwrap();
+
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": "operator",
+            "password": "a-long-enough-password",
+        }))
+        .await;
+    assert_is_redirected_to(&response, "/admin/dashboard");
+}
+
+#[tokio::test]
+async fn subscribers_can_be_confirmed_without_their_link() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    run(
+        &app,
+        AdminCommand::Subscriber {
+            command: SubscriberCommand::Confirm {
+                email: email.to_uppercase(),
+            },
+        },
+    )
+    .await
+    .unwrap();
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+    let event =
+        sqlx::query!("SELECT ip_address FROM subscription_events WHERE event_type = 'confirmed'")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert!(event.ip_address.is_none());
+}
+
+#[tokio::test]
+async fn confirming_a_subscriber_that_is_not_pending_fails() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    for email in [email, "nobody@example.com".into()] {
+        let outcome = run(
+            &app,
+            AdminCommand::Subscriber {
+                command: SubscriberCommand::Confirm { email },
+            },
+        )
+        .await;
+        assert!(outcome.is_err());
+    }
+}
+
+#[tokio::test]
+async fn confirming_an_address_with_several_subscriptions_needs_the_exact_address() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, $2, 'le guin', now(), 'pending_confirmation')
+        "#,
+        Uuid::new_v4(),
+        email.to_uppercase()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    let confirm = |email: String| AdminCommand::Subscriber {
+        command: SubscriberCommand::Confirm { email },
+    };
+
+    // Matches both, but neither exactly
+    let error = run(&app, confirm(capitalize(&email)))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains(&email.to_uppercase()), "{}", error);
+
+    run(&app, confirm(email.to_uppercase())).await.unwrap();
+    let statuses: Vec<(String, String)> =
+        sqlx::query!("SELECT email, status FROM subscriptions ORDER BY email")
+            .fetch_all(&app.db_pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.email, r.status))
+            .collect();
+    assert_eq!(
+        statuses,
+        [
+            (email.to_uppercase(), "confirmed".to_owned()),
+            (email, "pending_confirmation".to_owned()),
+        ]
+    );
+}
+
+fn capitalize(email: &str) -> String {
+    let mut chars = email.chars();
+    chars
+        .next()
+        .map(|c| c.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+#[tokio::test]
+async fn removing_a_subscriber_erases_their_data() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    run(
+        &app,
+        AdminCommand::Subscriber {
+            command: SubscriberCommand::Remove { email },
+        },
+    )
+    .await
+    .unwrap();
+
+    let saved = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[tokio::test]
+async fn issues_can_be_queued_for_delivery_again() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    create_confirmed_subscriber(&app).await;
+    let issue_id = publish_issue(&app).await;
+    assert_eq!(queued_deliveries(&app).await, 0);
+    let email = subscriber_email(&app).await;
+
+    run(
+        &app,
+        AdminCommand::Issue {
+            command: IssueCommand::Resend {
+                issue_id,
+                to: Some(email),
+            },
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(queued_deliveries(&app).await, 1);
+
+    // The delivery already queued is not queued twice
+    run(
+        &app,
+        AdminCommand::Issue {
+            command: IssueCommand::Resend { issue_id, to: None },
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(queued_deliveries(&app).await, 2);
+}
+
+#[tokio::test]
+async fn resending_an_unknown_issue_fails() {
+    let app = spawn_app().await;
+
+    let outcome = run(
+        &app,
+        AdminCommand::Issue {
+            command: IssueCommand::Resend {
+                issue_id: Uuid::new_v4(),
+                to: None,
+            },
+        },
+    )
+    .await;
+
+    assert!(outcome.is_err());
+}
+
+#[tokio::test]
+async fn only_old_idempotency_records_are_purged() {
+    let app = spawn_app().await;
+    for age in ["1 hour", "3 days"] {
+        sqlx::query!(
+            r#"
+            INSERT INTO idempotency (user_id, idempotency_key, created_at)
+            VALUES ($1, $2, now() - $3::text::interval)
+            "#,
+            app.test_user.user_id,
+            Uuid::new_v4().to_string(),
+            age
+        )
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    }
+
+    run(
+        &app,
+        AdminCommand::Idempotency {
+            command: IdempotencyCommand::Purge {
+                older_than_hours: 48,
+            },
+        },
+    )
+    .await
+    .unwrap();
+
+    let remaining = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM idempotency"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(remaining, 1);
+}
+
+#[tokio::test]
+async fn migrating_an_up_to_date_database_is_a_no_op() {
+    let app = spawn_app().await;
+
+    run(&app, AdminCommand::Migrate).await.unwrap();
+}
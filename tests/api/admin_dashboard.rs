@@ -1,4 +1,4 @@
-use crate::helpers::{assert_is_redirected_to, spawn_app};
+use crate::helpers::{assert_is_redirected_to, extract_csrf_token, spawn_app};
 
 #[tokio::test]
 async fn you_must_be_logged_in_to_access_the_admin_dashboard() {
@@ -40,3 +40,55 @@ async fn logout_clears_session_state() {
     let response = app.get_admin_dashboard().await;
     assert_is_redirected_to(&response, "/login");
 }
+
+#[tokio::test]
+async fn username_is_escaped_on_the_admin_dashboard() {
+    let app = spawn_app().await;
+    let username = "<script>alert('pwned')</script>";
+    sqlx::query!(
+        "UPDATE users SET username = $1 WHERE user_id = $2",
+        username,
+        app.test_user.user_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to update username");
+
+    app.post_login(&serde_json::json!({
+        "username": username,
+        "password": &app.test_user.password,
+    }))
+    .await;
+
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(!html_page.contains(username), "{}", html_page);
+    assert!(
+        html_page.contains("Welcome &lt;script&gt;"),
+        "{}",
+        html_page
+    );
+}
+
+#[tokio::test]
+async fn admin_pages_share_the_navigation_and_embed_the_same_csrf_token() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let pages = [
+        app.get_admin_dashboard_html().await,
+        app.get_change_password_html().await,
+        app.get_publish_newsletter_html().await,
+    ];
+
+    let tokens: Vec<_> = pages.iter().map(|page| extract_csrf_token(page)).collect();
+    for (page, token) in pages.iter().zip(&tokens) {
+        assert!(
+            page.contains(r#"<a href="/admin/newsletters">"#),
+            "{}",
+            page
+        );
+        assert!(page.contains(r#"action="/admin/logout""#), "{}", page);
+        assert!(!token.is_empty());
+        assert_eq!(token, &tokens[0]);
+    }
+}
@@ -0,0 +1,68 @@
+use std::process::{Command, Output};
+
+fn zero2prod(args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_zero2prod"))
+        .args(args)
+        .envs(env.iter().copied())
+        .env_remove("APP_ENVIRONMENT")
+        .output()
+        .expect("Failed to run zero2prod")
+}
+
+#[test]
+fn check_config_accepts_the_local_configuration() {
+    let output = zero2prod(&["--check-config"], &[]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "The configuration is valid\n"
+    );
+}
+
+#[test]
+fn check_config_reports_every_problem() {
+    let output = zero2prod(
+        &["--check-config"],
+        &[
+            ("APP_APPLICATION__HMAC_SECRET", "too-short"),
+            ("APP_EMAIL_CLIENT__SENDER_EMAIL", "not-an-email"),
+        ],
+    );
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("application.hmac_secret: is 9 characters long"));
+    assert!(stderr.contains("email_client.sender_email: not-an-email"));
+}
+
+#[test]
+fn an_unknown_environment_is_reported_rather_than_panicking() {
+    let output = Command::new(env!("CARGO_BIN_EXE_zero2prod"))
+        .arg("--check-config")
+        .env("APP_ENVIRONMENT", "staging")
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("staging is not a supported environment"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn print_config_redacts_secrets() {
+    let output = zero2prod(
+        &["--print-config"],
+        &[(
+            "APP_APPLICATION__HMAC_SECRET",
+            "a-secret-that-must-not-be-printed",
+        )],
+    );
+
+    assert!(output.status.success());
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(config["application"]["hmac_secret"], "[REDACTED]");
+    assert_eq!(config["email_client"]["authorization_token"], "[REDACTED]");
+    assert_eq!(config["email_client"]["sender_email"], "test@example.com");
+}
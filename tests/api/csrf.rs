@@ -0,0 +1,131 @@
+use crate::helpers::{assert_is_redirected_to, spawn_app};
+use uuid::Uuid;
+
+#[tokio::test]
+async fn state_changing_requests_without_a_csrf_token_are_rejected_with_a_403() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let new_password = Uuid::new_v4().to_string();
+
+    let test_cases = vec![
+        (
+            "/admin/password",
+            serde_json::json!({
+                "current_password": &app.test_user.password,
+                "new_password": &new_password,
+                "new_password_check": &new_password,
+            }),
+        ),
+        (
+            "/admin/newsletters",
+            serde_json::json!({
+                "title": "Newsletter title",
+                "text_content": "Newsletter body as plain text",
+                "html_content": "<p>Newsletter body as html</p>",
+                "idempotency_key": Uuid::new_v4().to_string(),
+            }),
+        ),
+        ("/admin/logout", serde_json::json!({})),
+    ];
+
+    for (path, body) in test_cases {
+        let response = app
+            .api_client
+            .post(format!("{}{}", &app.server_address, path))
+            .form(&body)
+            .send()
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(
+            403,
+            response.status().as_u16(),
+            "POST {} without a CSRF token was not rejected",
+            path
+        );
+    }
+}
+
+#[tokio::test]
+async fn state_changing_requests_with_a_wrong_csrf_token_are_rejected_with_a_403() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let response = app
+        .api_client
+        .post(format!("{}/admin/logout", &app.server_address))
+        .form(&serde_json::json!({ "csrf_token": "not-the-right-token" }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(403, response.status().as_u16());
+    // we are still logged in
+    assert_eq!(200, app.get_admin_dashboard().await.status().as_u16());
+}
+
+#[tokio::test]
+async fn csrf_token_can_be_submitted_as_a_form_field() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let csrf_token = app.csrf_token().await;
+
+    let response = app
+        .api_client
+        .post(format!("{}/admin/logout", &app.server_address))
+        .form(&serde_json::json!({ "csrf_token": csrf_token }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_is_redirected_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn csrf_token_changes_when_logging_in_again() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let first_token = app.csrf_token().await;
+
+    app.test_user.login(&app).await;
+    let second_token = app.csrf_token().await;
+
+    assert_ne!(first_token, second_token);
+}
+
+#[tokio::test]
+async fn json_requests_with_a_bearer_token_are_exempt() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let response = app
+        .api_client
+        .post(format!("{}/admin/newsletters", &app.server_address))
+        .bearer_auth("a-token")
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as html</p>",
+            },
+            "idempotency_key": Uuid::new_v4().to_string(),
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(201, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn anonymous_users_are_redirected_to_login_before_the_csrf_check() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .post(format!("{}/admin/logout", &app.server_address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_is_redirected_to(&response, "/login");
+}
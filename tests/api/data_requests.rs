@@ -0,0 +1,425 @@
+use crate::helpers::{
+    assert_is_redirected_to, create_confirmed_subscriber, spawn_app, spawn_app_with_configuration,
+    TestApp,
+};
+use reqwest::Url;
+use uuid::Uuid;
+use wiremock::{
+    matchers::{any, method, path},
+    Mock, ResponseTemplate,
+};
+use zero2prod::data_requests::email_hash;
+use zero2prod::suppression::is_suppressed;
+
+async fn subscriber_email(app: &TestApp) -> String {
+    sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .email
+}
+
+/// Ask for a data request link and return the link we emailed
+async fn request_link(app: &TestApp, email: &str, action: &str) -> Url {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    let response = app.post_data_request(email, action).await;
+    assert_is_redirected_to(&response, "/subscriptions/data");
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_confirmation_links(&email_request).html
+}
+
+fn token(link: &Url) -> String {
+    link.query_pairs()
+        .find(|(key, _)| key == "token")
+        .unwrap()
+        .1
+        .into_owned()
+}
+
+async fn count_rows(app: &TestApp, table: &str) -> i64 {
+    sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM {}", table))
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn the_emailed_export_link_downloads_everything_we_hold() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let link = request_link(&app, &email, "export").await;
+    assert!(app
+        .get_data_request_html()
+        .await
+        .contains(&format!("If we hold any data about {}", email)));
+    let response = reqwest::get(link).await.unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    let disposition = response.headers()["Content-Disposition"].to_str().unwrap();
+    assert!(disposition.starts_with("attachment"));
+    let data: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(data["email"], email);
+    assert_eq!(data["subscriptions"][0]["status"], "confirmed");
+    let event_types: Vec<_> = data["subscriptions"][0]["subscription_events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["event_type"].as_str().unwrap())
+        .collect();
+    assert_eq!(event_types, vec!["subscribed", "confirmed"]);
+}
+
+#[tokio::test]
+async fn requests_for_unknown_addresses_look_the_same_but_send_nothing() {
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_data_request("ursula_le_guin@gmail.com", "export")
+        .await;
+
+    assert_is_redirected_to(&response, "/subscriptions/data");
+    assert!(app
+        .get_data_request_html()
+        .await
+        .contains("If we hold any data about ursula_le_guin@gmail.com"));
+}
+
+#[tokio::test]
+async fn erasing_through_the_emailed_link_deletes_everything_but_a_tombstone_and_suppressions() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    app.test_user.login(&app).await;
+    app.post_add_suppression(&email, "").await;
+
+    let link = request_link(&app, &email, "erase").await;
+    // Following the link only asks for confirmation
+    let confirmation_page = reqwest::get(link.clone()).await.unwrap();
+    assert_eq!(200, confirmation_page.status().as_u16());
+    assert_eq!(1, count_rows(&app, "subscriptions").await);
+
+    let response = app.post_erase_data(&token(&link)).await;
+
+    assert_is_redirected_to(&response, "/subscriptions/data");
+    assert!(app
+        .get_data_request_html()
+        .await
+        .contains("Your data has been erased."));
+    for table in [
+        "subscriptions",
+        "subscription_tokens",
+        "subscription_events",
+        "issue_tracking_events",
+        "issue_delivery_queue",
+    ] {
+        assert_eq!(0, count_rows(&app, table).await, "{} is not empty", table);
+    }
+    let secret = &app.configuration.application.hmac_secret;
+    let tombstone = sqlx::query!("SELECT email_hash, suppression_source FROM erased_subscribers")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tombstone.email_hash, email_hash(secret, &email));
+    assert!(!tombstone.email_hash.contains(&email));
+    // We must still know not to email that address, without keeping it around
+    assert_eq!(0, count_rows(&app, "suppressed_emails").await);
+    assert_eq!(tombstone.suppression_source.as_deref(), Some("manual"));
+    assert!(is_suppressed(&app.db_pool, secret, &email.to_uppercase())
+        .await
+        .unwrap());
+
+    let response = app.post_remove_suppression(&email).await;
+    assert_is_redirected_to(&response, "/admin/suppressions");
+    assert!(!is_suppressed(&app.db_pool, secret, &email).await.unwrap());
+}
+
+/// Another subscription to the same address, typed with different letter case
+async fn add_case_variant_subscription(app: &TestApp, email: &str) -> Uuid {
+    let subscriber_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, $2, 'le guin', now(), 'confirmed')
+        "#,
+        subscriber_id,
+        email.to_uppercase()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        "INSERT INTO subscription_tokens (subscription_token, subscriber_id) VALUES ($1, $2)",
+        Uuid::new_v4().simple().to_string(),
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_events (event_id, subscriber_id, event_type)
+        VALUES ($1, $2, 'confirmed')
+        "#,
+        Uuid::new_v4(),
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    subscriber_id
+}
+
+#[tokio::test]
+async fn every_subscription_to_an_address_is_exported_and_erased_whatever_its_case() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    let other_id = add_case_variant_subscription(&app, &email).await;
+
+    let link = request_link(&app, &email, "export").await;
+    let data: serde_json::Value = reqwest::get(link).await.unwrap().json().await.unwrap();
+    let subscriptions = data["subscriptions"].as_array().unwrap();
+    assert_eq!(subscriptions.len(), 2);
+    assert_eq!(subscriptions[1]["id"], other_id.to_string());
+    assert_eq!(subscriptions[1]["email"], email.to_uppercase());
+    assert_eq!(
+        subscriptions[1]["subscription_events"][0]["event_type"],
+        "confirmed"
+    );
+
+    let link = request_link(&app, &email, "erase").await;
+    app.post_erase_data(&token(&link)).await;
+
+    for table in [
+        "subscriptions",
+        "subscription_tokens",
+        "subscription_events",
+    ] {
+        assert_eq!(0, count_rows(&app, table).await, "{} is not empty", table);
+    }
+    assert_eq!(1, count_rows(&app, "erased_subscribers").await);
+}
+
+#[tokio::test]
+async fn erasure_clears_the_address_from_other_subscribers_history() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    // Somebody else moved away from the address, and somebody else again asked to move to it
+    let other_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, 'ursula@gmail.com', 'le guin', now(), 'confirmed')
+        "#,
+        other_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_events (event_id, subscriber_id, event_type, details)
+        VALUES ($1, $2, 'email_changed', jsonb_build_object('previous_email', $3::text))
+        "#,
+        Uuid::new_v4(),
+        other_id,
+        email.to_uppercase()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        "INSERT INTO email_change_requests (token, subscriber_id, new_email) VALUES ('token', $1, $2)",
+        other_id,
+        email
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let link = request_link(&app, &email, "erase").await;
+    app.post_erase_data(&token(&link)).await;
+
+    assert_eq!(1, count_rows(&app, "subscriptions").await);
+    assert_eq!(0, count_rows(&app, "email_change_requests").await);
+    let details = sqlx::query_scalar!("SELECT details FROM subscription_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(details, serde_json::json!({}));
+}
+
+#[tokio::test]
+async fn data_requests_are_rate_limited_like_signups() {
+    let app = spawn_app_with_configuration(|c| c.signup_protection.max_attempts_per_ip = 2).await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // The subscription itself was the first attempt from this address
+    let response = app.post_data_request(&email, "export").await;
+    assert_is_redirected_to(&response, "/subscriptions/data");
+    let response = app.post_data_request(&email, "export").await;
+
+    assert_eq!(429, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn data_requests_filling_in_the_honeypot_send_nothing() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .api_client
+        .post(format!("{}/subscriptions/data", &app.server_address))
+        .form(&serde_json::json!({
+            "email": email,
+            "action": "export",
+            "website": "https://spam.example.com",
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_is_redirected_to(&response, "/subscriptions/data");
+    assert!(app
+        .get_data_request_html()
+        .await
+        .contains("If we hold any data about"));
+}
+
+#[tokio::test]
+async fn an_erase_link_cannot_be_used_to_export_and_vice_versa() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let erase_link = request_link(&app, &email, "erase").await;
+    let export_link = request_link(&app, &email, "export").await;
+
+    let response = reqwest::get(format!(
+        "{}/subscriptions/data/export?token={}",
+        app.server_address,
+        token(&erase_link)
+    ))
+    .await
+    .unwrap();
+    assert_eq!(401, response.status().as_u16());
+    let response = app.post_erase_data(&token(&export_link)).await;
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(1, count_rows(&app, "subscriptions").await);
+}
+
+#[tokio::test]
+async fn tampered_tokens_are_rejected() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    let link = request_link(&app, &email, "erase").await;
+
+    let response = app.post_erase_data(&format!("x{}", token(&link))).await;
+
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(1, count_rows(&app, "subscriptions").await);
+}
+
+#[tokio::test]
+async fn subscribing_again_after_an_erasure_is_flagged() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    let link = request_link(&app, &email, "erase").await;
+    app.post_erase_data(&token(&link)).await;
+
+    subscribe_with_email(&app, &email).await;
+
+    let event =
+        sqlx::query!("SELECT details FROM subscription_events WHERE event_type = 'subscribed'")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(event.details["previously_erased"], true);
+}
+
+async fn subscribe_with_email(app: &TestApp, email: &str) {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let body = serde_urlencoded::to_string([("name", "le guin"), ("email", email)]).unwrap();
+    app.post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn admins_can_export_and_erase_data() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    app.test_user.login(&app).await;
+
+    let response = app.get_admin_data_export(&email.to_uppercase()).await;
+    assert_eq!(200, response.status().as_u16());
+    let data: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(data["subscriptions"][0]["email"], email);
+
+    let response = app.post_admin_erase_data(&email).await;
+    assert_is_redirected_to(&response, "/admin/subscribers");
+    assert!(app
+        .get_subscribers_html()
+        .await
+        .contains(&format!("All data about {} has been erased.", email)));
+    assert_eq!(0, count_rows(&app, "subscriptions").await);
+    assert_eq!(1, count_rows(&app, "erased_subscribers").await);
+
+    let response = app.get_admin_data_export(&email).await;
+    assert_is_redirected_to(&response, "/admin/subscribers");
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_handle_data_requests_as_an_admin() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let response = app.get_admin_data_export(&email).await;
+    assert_is_redirected_to(&response, "/login");
+    let response = app.post_admin_erase_data(&email).await;
+    assert_is_redirected_to(&response, "/login");
+    assert_eq!(1, count_rows(&app, "subscriptions").await);
+}
@@ -0,0 +1,170 @@
+use crate::helpers::spawn_app;
+
+#[tokio::test]
+async fn errors_are_json_with_a_code_and_the_request_id_by_default() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(format!(
+            "{}/subscriptions/confirm?subscription_token=unknown",
+            app.server_address
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(response.headers()["Content-Type"], "application/json");
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "unauthorized");
+    assert_eq!(body["message"], "Authentication failed");
+    assert!(uuid::Uuid::parse_str(body["request_id"].as_str().unwrap()).is_ok());
+}
+
+#[tokio::test]
+async fn browsers_get_an_error_page() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(format!("{}/issues/does-not-exist", app.server_address))
+        .header("Accept", "text/html,application/xhtml+xml,*/*;q=0.8")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(404, response.status().as_u16());
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "text/html; charset=utf-8"
+    );
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("<h1>Not Found</h1>"));
+    assert!(html_page.contains("Request ID: "));
+}
+
+#[tokio::test]
+async fn validation_errors_list_the_invalid_fields() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions_json(&serde_json::json!({ "name": "le guin", "email": "nope" }))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "validation_failed");
+    assert_eq!(
+        body["fields"]["email"],
+        "nope is not recognized as a valid email"
+    );
+}
+
+#[tokio::test]
+async fn internal_errors_do_not_leak_their_cause() {
+    let app = spawn_app().await;
+    // Sabotage the database
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    for accept in ["application/json", "text/html"] {
+        let response = app
+            .api_client
+            .get(format!(
+                "{}/subscriptions/confirm?subscription_token=whatever",
+                app.server_address
+            ))
+            .header("Accept", accept)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(500, response.status().as_u16());
+        let body = response.text().await.unwrap();
+        assert!(body.contains("Something went wrong, please try again later."));
+        assert!(!body.contains("subscription_token\""));
+        assert!(!body.to_lowercase().contains("column"));
+    }
+}
+
+#[tokio::test]
+async fn malformed_json_bodies_are_rendered_like_our_errors() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .post(format!("{}/subscriptions", app.server_address))
+        .header("Content-Type", "application/json")
+        .body("{\"name\": ")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "bad_request");
+    assert_eq!(body["message"], "The request body is not valid JSON.");
+    assert!(body["request_id"].is_string());
+}
+
+#[tokio::test]
+async fn malformed_form_bodies_are_rendered_like_our_errors() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .post(format!("{}/subscriptions/data", app.server_address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("email=ursula%40gmail.com&action=burn")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(response.headers()["Content-Type"], "application/json");
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "bad_request");
+    assert!(body["request_id"].is_string());
+}
+
+#[tokio::test]
+async fn invalid_query_strings_are_rendered_like_our_errors() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(format!("{}/subscriptions/confirm", app.server_address))
+        .header("Accept", "text/html")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "text/html; charset=utf-8"
+    );
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("subscription_token"));
+    assert!(html_page.contains("Request ID: "));
+}
+
+#[tokio::test]
+async fn unknown_routes_are_rendered_like_our_errors() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(format!("{}/no/such/page", app.server_address))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(404, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "not_found");
+    assert!(body["request_id"].is_string());
+}
@@ -0,0 +1,171 @@
+use crate::helpers::spawn_app;
+
+#[tokio::test]
+async fn rss_feed_lists_published_issues() {
+    let app = spawn_app().await;
+    app.insert_issue("Our first issue", "published", "2023-06-01T08:00:00Z")
+        .await;
+
+    let response = app.get_feed("/feed.rss").await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        "application/rss+xml; charset=utf-8",
+        response.headers().get("Content-Type").unwrap()
+    );
+    let body = response.text().await.unwrap();
+    assert!(body.contains("<title>Our first issue</title>"), "{}", body);
+    assert!(body.contains("<pubDate>Thu, 1 Jun 2023 08:00:00 +0000</pubDate>"));
+    assert!(body.contains("/issues/"));
+    // HTML content is wrapped in CDATA inside the XML document
+    assert!(
+        body.contains("<description><![CDATA[<p>html</p>]]></description>"),
+        "{}",
+        body
+    );
+}
+
+#[tokio::test]
+async fn atom_feed_lists_published_issues() {
+    let app = spawn_app().await;
+    let issue_id = app
+        .insert_issue("Our first issue", "published", "2023-06-01T08:00:00Z")
+        .await;
+
+    let response = app.get_feed("/feed.atom").await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        "application/atom+xml; charset=utf-8",
+        response.headers().get("Content-Type").unwrap()
+    );
+    let body = response.text().await.unwrap();
+    assert!(body.contains("<title>Our first issue</title>"), "{}", body);
+    assert!(body.contains(&format!("<id>urn:uuid:{}</id>", issue_id)));
+    assert!(body.contains("<published>2023-06-01T08:00:00+00:00</published>"));
+    assert!(body.contains(r#"<content type="html">&lt;p&gt;html&lt;/p&gt;</content>"#));
+}
+
+#[tokio::test]
+async fn feeds_only_include_issues_visible_in_the_archive() {
+    let app = spawn_app().await;
+    app.insert_issue("A draft issue", "draft", "2023-06-01T00:00:00Z")
+        .await;
+    app.insert_issue("A scheduled issue", "scheduled", "2023-06-01T00:00:00Z")
+        .await;
+    let hidden_issue_id = app
+        .insert_issue("A hidden issue", "published", "2023-06-01T00:00:00Z")
+        .await;
+    sqlx::query!(
+        "UPDATE newsletter_issues SET hidden_from_archive = true WHERE newsletter_issue_id = $1",
+        hidden_issue_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    for path in ["/feed.rss", "/feed.atom"] {
+        let body = app.get_feed(path).await.text().await.unwrap();
+        assert!(!body.contains("A draft issue"), "{}", path);
+        assert!(!body.contains("A scheduled issue"), "{}", path);
+        assert!(!body.contains("A hidden issue"), "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn unchanged_feeds_are_not_sent_again_for_a_matching_etag() {
+    let app = spawn_app().await;
+    app.insert_issue("Our first issue", "published", "2023-06-01T08:00:00Z")
+        .await;
+
+    for path in ["/feed.rss", "/feed.atom"] {
+        let response = app.get_feed(path).await;
+        let etag = response.headers().get("ETag").unwrap().clone();
+
+        let response = app
+            .api_client
+            .get(format!("{}{}", &app.server_address, path))
+            .header("If-None-Match", etag.clone())
+            .send()
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(304, response.status().as_u16(), "{}", path);
+        assert_eq!(etag, response.headers().get("ETag").unwrap());
+        assert!(response.text().await.unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+async fn etag_changes_when_a_new_issue_is_published() {
+    let app = spawn_app().await;
+    app.insert_issue("Our first issue", "published", "2023-06-01T08:00:00Z")
+        .await;
+    let response = app.get_feed("/feed.rss").await;
+    let etag = response.headers().get("ETag").unwrap().clone();
+
+    app.insert_issue("Our second issue", "published", "2023-06-08T08:00:00Z")
+        .await;
+    let response = app
+        .api_client
+        .get(format!("{}/feed.rss", &app.server_address))
+        .header("If-None-Match", etag.clone())
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, response.status().as_u16());
+    assert_ne!(etag, response.headers().get("ETag").unwrap());
+    assert!(response.text().await.unwrap().contains("Our second issue"));
+}
+
+#[tokio::test]
+async fn feeds_honour_if_modified_since() {
+    let app = spawn_app().await;
+    app.insert_issue("Our first issue", "published", "2023-06-01T08:00:00Z")
+        .await;
+
+    let response = app.get_feed("/feed.atom").await;
+    let last_modified = response.headers().get("Last-Modified").unwrap().clone();
+    assert_eq!("Thu, 01 Jun 2023 08:00:00 GMT", last_modified);
+
+    let test_cases = [
+        ("Thu, 01 Jun 2023 08:00:00 GMT", 304),
+        ("Fri, 02 Jun 2023 08:00:00 GMT", 304),
+        ("Wed, 31 May 2023 08:00:00 GMT", 200),
+    ];
+    for (if_modified_since, expected_status) in test_cases {
+        let response = app
+            .api_client
+            .get(format!("{}/feed.atom", &app.server_address))
+            .header("If-Modified-Since", if_modified_since)
+            .send()
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(
+            expected_status,
+            response.status().as_u16(),
+            "If-Modified-Since: {}",
+            if_modified_since
+        );
+    }
+}
+
+#[tokio::test]
+async fn feeds_honour_if_modified_since_for_issues_published_just_now() {
+    let app = spawn_app().await;
+    // Unlike our fixtures, `now` has sub-second precision
+    app.insert_issue("Our first issue", "published", "now")
+        .await;
+
+    let response = app.get_feed("/feed.atom").await;
+    let last_modified = response.headers().get("Last-Modified").unwrap().clone();
+
+    let response = app
+        .api_client
+        .get(format!("{}/feed.atom", &app.server_address))
+        .header("If-Modified-Since", last_modified)
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(304, response.status().as_u16());
+}
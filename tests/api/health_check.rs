@@ -1,4 +1,5 @@
 use crate::helpers::spawn_app;
+use zero2prod::issue_delivery_worker::{record_heartbeat, WORKER_NAME};
 
 #[tokio::test]
 async fn health_check_works() {
@@ -6,7 +7,7 @@ async fn health_check_works() {
     let client = reqwest::Client::new();
 
     let response = client
-        .get(&format!("{}/health", &test_app.server_address))
+        .get(format!("{}/health", &test_app.server_address))
         .send()
         .await
         .expect("Failed to execute request");
@@ -14,3 +15,90 @@ async fn health_check_works() {
     assert!(response.status().is_success());
     assert_eq!(Some(0), response.content_length());
 }
+
+#[tokio::test]
+async fn liveness_only_needs_the_process() {
+    let test_app = spawn_app().await;
+
+    let response = test_app.get_health("live").await;
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "up");
+}
+
+#[tokio::test]
+async fn readiness_reports_every_dependency() {
+    let test_app = spawn_app().await;
+    record_heartbeat(&test_app.db_pool, WORKER_NAME)
+        .await
+        .unwrap();
+
+    let response = test_app.get_health("ready").await;
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "ready");
+    for dependency in ["database", "redis", "migrations", "worker"] {
+        let check = &body["checks"][dependency];
+        assert_eq!(check["status"], "up", "{}: {}", dependency, check);
+        assert!(check["latency_ms"].is_number());
+    }
+}
+
+#[tokio::test]
+async fn readiness_reports_a_worker_that_never_checked_in_without_failing() {
+    let test_app = spawn_app().await;
+
+    let response = test_app.get_health("ready").await;
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "ready");
+    assert_eq!(body["checks"]["database"]["status"], "up");
+    assert_eq!(body["checks"]["worker"]["status"], "down");
+    assert_eq!(
+        body["checks"]["worker"]["error"],
+        "The delivery worker has never checked in"
+    );
+}
+
+#[tokio::test]
+async fn a_stopped_worker_does_not_take_the_api_out_of_rotation() {
+    let test_app = spawn_app().await;
+    sqlx::query!(
+        "INSERT INTO worker_heartbeats (worker_name, last_seen_at) VALUES ($1, now() - interval '5 minutes')",
+        WORKER_NAME
+    )
+    .execute(&test_app.db_pool)
+    .await
+    .unwrap();
+
+    let response = test_app.get_health("ready").await;
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["checks"]["worker"]["status"], "down");
+}
+
+#[tokio::test]
+async fn pending_migrations_make_the_app_not_ready() {
+    let test_app = spawn_app().await;
+    record_heartbeat(&test_app.db_pool, WORKER_NAME)
+        .await
+        .unwrap();
+    sqlx::query("DELETE FROM _sqlx_migrations WHERE version = 20231028090000")
+        .execute(&test_app.db_pool)
+        .await
+        .unwrap();
+
+    let response = test_app.get_health("ready").await;
+
+    assert_eq!(503, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["checks"]["migrations"]["status"], "down");
+    assert_eq!(
+        body["checks"]["migrations"]["error"],
+        "Pending migrations: 20231028090000"
+    );
+}
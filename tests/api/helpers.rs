@@ -1,29 +1,63 @@
 use argon2::{password_hash::SaltString, Params, PasswordHasher};
+use fake::{
+    faker::{internet::en::SafeEmail, name::en::Name},
+    Fake,
+};
 use once_cell::sync::Lazy;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::testing::trace::{InMemorySpanExporter, InMemorySpanExporterBuilder};
+use opentelemetry_sdk::trace::TracerProvider;
 use reqwest::Url;
-use secrecy::Secret;
+use secrecy::ExposeSecret;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
+use std::num::NonZeroUsize;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
-use wiremock::MockServer;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
 use zero2prod::{
-    configuration::{get_configuration, DatabaseSettings},
+    authentication::CSRF_TOKEN_HEADER,
+    configuration::{get_configuration, DatabaseSettings, PostmarkWebhookSettings, Settings},
     email_client::EmailClient,
-    issue_delivery_worker::{try_execute_task, ExecutionOutcome},
+    email_templates::EmailTemplates,
+    issue_delivery_worker::{run_worker_until_stopped, try_execute_task, ExecutionOutcome},
     startup::{get_connection_pool, Application},
     telemetry::{get_subscriber, init_subscriber},
+    tracking::Tracking,
 };
 
+/// Every span finished by any test, for tests to check what we trace
+pub static SPAN_EXPORTER: Lazy<InMemorySpanExporter> =
+    Lazy::new(|| InMemorySpanExporterBuilder::new().build());
+
 // Ensure that the `tracing` stack is only initialized once
 static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
     let subscriber_name = "test".to_string();
+    let tracer = TracerProvider::builder()
+        .with_simple_exporter(SPAN_EXPORTER.clone())
+        .build()
+        .tracer("test");
     // We cannot assign the output of `get_subscriber` to a variable based on the value TEST_LOG because the sink is part of the type
     // returned by `get_subscriber` so they are not the same type.  We could work around it, but this is the most straight-forward way of moving forward
     if std::env::var("TEST_LOG").is_ok() {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::stdout);
+        let subscriber = get_subscriber(
+            subscriber_name,
+            default_filter_level,
+            std::io::stdout,
+            Some(tracer),
+        );
         init_subscriber(subscriber);
     } else {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::sink);
+        let subscriber = get_subscriber(
+            subscriber_name,
+            default_filter_level,
+            std::io::sink,
+            Some(tracer),
+        );
         init_subscriber(subscriber);
     }
 });
@@ -33,10 +67,15 @@ pub struct TestApp {
     pub db_pool: PgPool,
     pub email_server: MockServer,
     pub email_client: EmailClient,
+    pub email_templates: EmailTemplates,
+    pub tracking: Tracking,
+    pub postmark_webhook: PostmarkWebhookSettings,
     pub port: u16,
     pub test_user: TestUser,
     pub api_client: reqwest::Client,
-    pub redis_uri: Secret<String>,
+    /// Cancel to stop the application, as SIGTERM would
+    pub shutdown: CancellationToken,
+    pub configuration: Settings,
 }
 
 pub struct TestUser {
@@ -93,7 +132,7 @@ impl TestApp {
         Body: serde::Serialize,
     {
         self.api_client
-            .post(&format!("{}/login", &self.server_address))
+            .post(format!("{}/login", &self.server_address))
             // this `reqwest` method makes sure the body is URL-encoded and Content-Type is set correctly
             .form(body)
             .send()
@@ -103,7 +142,7 @@ impl TestApp {
 
     pub async fn get_login_html(&self) -> String {
         self.api_client
-            .get(&format!("{}/login", &self.server_address))
+            .get(format!("{}/login", &self.server_address))
             .send()
             .await
             .expect("Failed to execute request")
@@ -114,7 +153,7 @@ impl TestApp {
 
     pub async fn get_admin_dashboard(&self) -> reqwest::Response {
         self.api_client
-            .get(&format!("{}/admin/dashboard", &self.server_address))
+            .get(format!("{}/admin/dashboard", &self.server_address))
             .send()
             .await
             .expect("Failed to execute request")
@@ -126,7 +165,7 @@ impl TestApp {
 
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         self.api_client
-            .post(&format!("{}/subscriptions", &self.server_address))
+            .post(format!("{}/subscriptions", &self.server_address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
@@ -134,9 +173,28 @@ impl TestApp {
             .expect("failed to execute request")
     }
 
+    pub async fn post_subscriptions_json(&self, body: &serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/subscriptions", &self.server_address))
+            .json(body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    /// The CSRF token of the current session, or an empty string if we are not logged in
+    pub async fn csrf_token(&self) -> String {
+        let response = self.get_admin_dashboard().await;
+        if response.status().as_u16() != 200 {
+            return String::new();
+        }
+        extract_csrf_token(&response.text().await.unwrap())
+    }
+
     pub async fn post_newsletters_form(&self, body: &serde_json::Value) -> reqwest::Response {
         self.api_client
-            .post(&format!("{}/admin/newsletters", &self.server_address))
+            .post(format!("{}/admin/newsletters", &self.server_address))
+            .header(CSRF_TOKEN_HEADER, self.csrf_token().await)
             .form(&body)
             .send()
             .await
@@ -145,7 +203,8 @@ impl TestApp {
 
     pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
         self.api_client
-            .post(&format!("{}/admin/newsletters", &self.server_address))
+            .post(format!("{}/admin/newsletters", &self.server_address))
+            .header(CSRF_TOKEN_HEADER, self.csrf_token().await)
             .json(&body)
             .send()
             .await
@@ -178,7 +237,7 @@ impl TestApp {
 
     pub async fn get_change_password(&self) -> reqwest::Response {
         self.api_client
-            .get(&format!("{}/admin/password", &self.server_address))
+            .get(format!("{}/admin/password", &self.server_address))
             .send()
             .await
             .expect("Failed to execute request")
@@ -188,12 +247,70 @@ impl TestApp {
         self.get_change_password().await.text().await.unwrap()
     }
 
+    pub async fn get_publish_newsletter_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/admin/newsletters", &self.server_address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_issues_archive(&self, page: Option<u32>) -> reqwest::Response {
+        let mut request = self
+            .api_client
+            .get(format!("{}/issues", &self.server_address));
+        if let Some(page) = page {
+            request = request.query(&[("page", page)]);
+        }
+        request.send().await.expect("Failed to execute request")
+    }
+
+    pub async fn get_issues_archive_html(&self, page: Option<u32>) -> String {
+        self.get_issues_archive(page).await.text().await.unwrap()
+    }
+
+    pub async fn get_issue(&self, id_or_slug: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/issues/{}", &self.server_address, id_or_slug))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_admin_issues_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/admin/issues", &self.server_address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_issue_visibility(&self, issue_id: Uuid, hidden: bool) -> reqwest::Response {
+        self.api_client
+            .post(format!(
+                "{}/admin/issues/{}/visibility",
+                &self.server_address, issue_id
+            ))
+            .header(CSRF_TOKEN_HEADER, self.csrf_token().await)
+            .form(&serde_json::json!({ "hidden": hidden }))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
     pub async fn post_change_password<Body>(&self, body: &Body) -> reqwest::Response
     where
         Body: serde::Serialize,
     {
         self.api_client
-            .post(&format!("{}/admin/password", &self.server_address))
+            .post(format!("{}/admin/password", &self.server_address))
+            .header(CSRF_TOKEN_HEADER, self.csrf_token().await)
             .form(body)
             .send()
             .await
@@ -202,21 +319,300 @@ impl TestApp {
 
     pub async fn post_logout(&self) -> reqwest::Response {
         self.api_client
-            .post(&format!("{}/admin/logout", &self.server_address))
+            .post(format!("{}/admin/logout", &self.server_address))
+            .header(CSRF_TOKEN_HEADER, self.csrf_token().await)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    /// Store an issue directly, bypassing the publishing flow
+    pub async fn insert_issue(&self, title: &str, status: &str, published_at: &str) -> Uuid {
+        let issue_id = Uuid::new_v4();
+        sqlx::query!(
+            r#"
+            INSERT INTO newsletter_issues
+                (newsletter_issue_id, title, slug, text_content, html_content, status, published_at)
+            VALUES ($1, $2, $3, 'text', '<p>html</p>', $4, $5::text::timestamptz)
+            "#,
+            issue_id,
+            title,
+            issue_id.to_string(),
+            status,
+            published_at
+        )
+        .execute(&self.db_pool)
+        .await
+        .expect("Failed to insert newsletter issue");
+        issue_id
+    }
+
+    pub async fn get_feed(&self, path: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}{}", &self.server_address, path))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_subscribers_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/admin/subscribers", &self.server_address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_subscriber_history(&self, subscriber_id: Uuid) -> reqwest::Response {
+        self.api_client
+            .get(format!(
+                "{}/admin/subscribers/{}",
+                &self.server_address, subscriber_id
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_subscriber_history_export(&self, subscriber_id: Uuid) -> reqwest::Response {
+        self.api_client
+            .get(format!(
+                "{}/admin/subscribers/{}/history.json",
+                &self.server_address, subscriber_id
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_preferences(&self, subscription_token: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!(
+                "{}/subscriptions/preferences",
+                &self.server_address
+            ))
+            .query(&[("subscription_token", subscription_token)])
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_confirm_email_change(&self, token: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!(
+                "{}/subscriptions/preferences/confirm-email",
+                &self.server_address
+            ))
+            .form(&serde_json::json!({ "token": token }))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_preferences(&self, body: &serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!(
+                "{}/subscriptions/preferences",
+                &self.server_address
+            ))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_subscribe_page(&self, query: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/subscribe{}", &self.server_address, query))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_subscribe_html(&self) -> String {
+        self.get_subscribe_page("").await.text().await.unwrap()
+    }
+
+    pub async fn get_signup_widget(&self, query: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!(
+                "{}/subscribe/widget{}",
+                &self.server_address, query
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_signup_widget_script(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/subscribe/widget.js", &self.server_address))
             .send()
             .await
             .expect("Failed to execute request")
     }
 
+    pub async fn get_data_request_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/subscriptions/data", &self.server_address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_data_request(&self, email: &str, action: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/subscriptions/data", &self.server_address))
+            .form(&serde_json::json!({ "email": email, "action": action }))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_erase_data(&self, token: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/subscriptions/data/erase", &self.server_address))
+            .form(&serde_json::json!({ "token": token }))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_admin_data_export(&self, email: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!(
+                "{}/admin/data-requests/export",
+                &self.server_address
+            ))
+            .query(&[("email", email)])
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_admin_erase_data(&self, email: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!(
+                "{}/admin/data-requests/erase",
+                &self.server_address
+            ))
+            .header(CSRF_TOKEN_HEADER, self.csrf_token().await)
+            .form(&serde_json::json!({ "email": email }))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_suppressions_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/admin/suppressions", &self.server_address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_add_suppression(&self, email: &str, reason: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/suppressions", &self.server_address))
+            .header(CSRF_TOKEN_HEADER, self.csrf_token().await)
+            .form(&serde_json::json!({ "email": email, "reason": reason }))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_remove_suppression(&self, email: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!(
+                "{}/admin/suppressions/remove",
+                &self.server_address
+            ))
+            .header(CSRF_TOKEN_HEADER, self.csrf_token().await)
+            .form(&serde_json::json!({ "email": email }))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    /// Call the Postmark webhook with valid basic auth credentials
+    pub async fn get_health(&self, check: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/health/{}", &self.server_address, check))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_metrics(&self, bearer_token: Option<&str>) -> reqwest::Response {
+        let mut request = self
+            .api_client
+            .get(format!("{}/metrics", &self.server_address));
+        if let Some(token) = bearer_token {
+            request = request.bearer_auth(token);
+        }
+        request.send().await.expect("Failed to execute request")
+    }
+
+    /// The secret Postmark authenticates with, as set in `local.yaml`
+    pub fn postmark_webhook_secret(&self) -> &str {
+        self.postmark_webhook
+            .secret
+            .as_ref()
+            .expect("The webhook secret is not set")
+            .expose_secret()
+    }
+
+    pub async fn post_postmark_webhook(&self, body: &serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/webhooks/postmark", &self.server_address))
+            .basic_auth(
+                &self.postmark_webhook.username,
+                Some(self.postmark_webhook_secret()),
+            )
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    /// Run the delivery worker as `main` does, until `shutdown` is cancelled
+    pub fn spawn_worker(
+        &self,
+        concurrency: usize,
+        shutdown: CancellationToken,
+    ) -> JoinHandle<Result<(), anyhow::Error>> {
+        tokio::spawn(run_worker_until_stopped(
+            self.configuration.clone(),
+            NonZeroUsize::new(concurrency).expect("The worker needs at least one loop"),
+            None,
+            shutdown,
+        ))
+    }
+
     pub async fn dispatch_all_pending_emails(&self) {
         loop {
-            match try_execute_task(&self.db_pool, &self.email_client).await {
+            match try_execute_task(
+                &self.db_pool,
+                &self.email_client,
+                &self.email_templates,
+                &self.tracking,
+                &self.configuration.application.hmac_secret,
+            )
+            .await
+            {
                 Ok(ExecutionOutcome::EmptyQueue) => {
                     break;
                 }
-                Ok(ExecutionOutcome::TaskCompleted) => {
-                    break;
-                }
+                Ok(ExecutionOutcome::TaskCompleted) => {}
                 Err(e) => {
                     panic!("{:?}", e);
                 }
@@ -226,6 +622,11 @@ impl TestApp {
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with_configuration(|_| {}).await
+}
+
+/// Spawn the application after tweaking the configuration it would otherwise use in tests
+pub async fn spawn_app_with_configuration(customize: impl FnOnce(&mut Settings)) -> TestApp {
     // The first time this is invoked, the code in `TRACING` will be executed. All other invocations will skip execution
     Lazy::force(&TRACING);
 
@@ -239,6 +640,7 @@ pub async fn spawn_app() -> TestApp {
         // let OS choose a random port
         c.application.port = 0;
         c.email_client.base_url = email_server.uri();
+        customize(&mut c);
         c
     };
 
@@ -249,7 +651,8 @@ pub async fn spawn_app() -> TestApp {
         .expect("Failed to build application");
     let application_port = application.port();
     let address = format!("http://127.0.0.1:{}", application.port());
-    let _ = tokio::spawn(application.run_until_stopped());
+    let shutdown = CancellationToken::new();
+    tokio::spawn(application.run_until_stopped(shutdown.clone()));
 
     let client = reqwest::Client::builder()
         .redirect(reqwest::redirect::Policy::none())
@@ -258,14 +661,26 @@ pub async fn spawn_app() -> TestApp {
         .unwrap();
 
     let test_app = TestApp {
-        email_client: configuration.email_client.client(),
+        email_client: configuration
+            .email_client
+            .clone()
+            .client()
+            .expect("Failed to build the email client"),
+        email_templates: EmailTemplates::new(configuration.application.base_url.clone())
+            .expect("Failed to load email templates"),
+        tracking: Tracking::new(
+            configuration.application.base_url.clone(),
+            configuration.application.hmac_secret.clone(),
+        ),
+        postmark_webhook: configuration.postmark_webhook.clone(),
         db_pool: get_connection_pool(&configuration.database),
         server_address: address,
         email_server,
         port: application_port,
         test_user: TestUser::generate(),
         api_client: client,
-        redis_uri: configuration.redis_uri.clone(),
+        shutdown,
+        configuration,
     };
     test_app.test_user.store(&test_app.db_pool).await;
     test_app
@@ -273,14 +688,7 @@ pub async fn spawn_app() -> TestApp {
 
 // A little hacky, but we'll create a unique DB for every test so that we don't have to deal with transactions and rollback
 pub async fn configure_database_for_tests(config: &DatabaseSettings) -> PgPool {
-    let mut connection = PgConnection::connect_with(&config.without_db())
-        .await
-        .expect("failed to connect to Postgres");
-
-    connection
-        .execute(format!(r#"CREATE DATABASE "{}";"#, config.database_name).as_str())
-        .await
-        .expect("failed to create ephemeral database");
+    create_database_for_tests(config).await;
 
     let connection_pool = PgPool::connect_with(config.with_db())
         .await
@@ -294,12 +702,90 @@ pub async fn configure_database_for_tests(config: &DatabaseSettings) -> PgPool {
     connection_pool
 }
 
+/// An ephemeral database without any tables
+pub async fn create_database_for_tests(config: &DatabaseSettings) {
+    let mut connection = PgConnection::connect_with(&config.without_db())
+        .await
+        .expect("failed to connect to Postgres");
+
+    connection
+        .execute(format!(r#"CREATE DATABASE "{}";"#, config.database_name).as_str())
+        .await
+        .expect("failed to create ephemeral database");
+}
+
 /// Confirmation links embedded in the request to the email API
 pub struct ConfirmationLinks {
     pub html: reqwest::Url,
     pub plain_text: reqwest::Url,
 }
 
+/// Extract the value of the `csrf_token` hidden field embedded in an admin page
+pub fn extract_csrf_token(html_page: &str) -> String {
+    let marker = r#"name="csrf_token" value=""#;
+    let start = html_page
+        .find(marker)
+        .unwrap_or_else(|| panic!("No CSRF token in {}", html_page))
+        + marker.len();
+    let end = start + html_page[start..].find('"').unwrap();
+    html_page[start..end].to_owned()
+}
+
+pub async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
+    let name: String = Name().fake();
+    create_unconfirmed_subscriber_named(app, &name).await
+}
+
+pub async fn create_unconfirmed_subscriber_named(app: &TestApp, name: &str) -> ConfirmationLinks {
+    let email: String = SafeEmail().fake();
+    let body = serde_urlencoded::to_string(serde_json::json!({
+        "name": name,
+        "email": email,
+    }))
+    .unwrap();
+
+    // since we are using 'mount_as_scoped', we get back a MockGuard, when that goes out of scope
+    // the Drop impl causes the underlying MockServer to stop supporting this route AND check the expectation(s)
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+
+    app.get_confirmation_links(email_request)
+}
+
+pub async fn create_confirmed_subscriber(app: &TestApp) {
+    let name: String = Name().fake();
+    create_confirmed_subscriber_named(app, &name).await
+}
+
+pub async fn create_confirmed_subscriber_named(app: &TestApp, name: &str) {
+    // we can re-use the existing helper and just add the extra step to call the confirmation link
+    let confirmation_link = create_unconfirmed_subscriber_named(app, name).await;
+
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
 pub fn assert_is_redirected_to(response: &reqwest::Response, location: &str) {
     assert_eq!(303, response.status().as_u16());
     assert_eq!(location, response.headers().get("Location").unwrap());
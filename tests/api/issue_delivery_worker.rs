@@ -0,0 +1,66 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+#[tokio::test]
+async fn concurrent_delivery_loops_send_each_issue_exactly_once() {
+    // Arrange
+    let app = spawn_app().await;
+    for _ in 0..6 {
+        create_confirmed_subscriber(&app).await;
+    }
+    let already_sent = app.email_server.received_requests().await.unwrap().len();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(100)))
+        .expect(6)
+        .mount(&app.email_server)
+        .await;
+    app.test_user.login(&app).await;
+    let response = app
+        .post_newsletters_form(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as text",
+            "html_content": "<p>Newsletter body as html</p>",
+            "idempotency_key": uuid::Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_eq!(303, response.status().as_u16());
+
+    // Act
+    let shutdown = CancellationToken::new();
+    let worker = app.spawn_worker(3, shutdown.clone());
+    for _ in 0..100 {
+        let queued = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .count;
+        if queued == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    shutdown.cancel();
+    tokio::time::timeout(Duration::from_secs(5), worker)
+        .await
+        .expect("The worker did not stop")
+        .unwrap()
+        .unwrap();
+
+    // Assert
+    let recipients: HashSet<String> = app.email_server.received_requests().await.unwrap()
+        [already_sent..]
+        .iter()
+        .map(|request| {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            body["To"].as_str().unwrap().to_owned()
+        })
+        .collect();
+    assert_eq!(recipients.len(), 6);
+}
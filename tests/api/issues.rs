@@ -0,0 +1,153 @@
+use crate::helpers::{assert_is_redirected_to, spawn_app, TestApp};
+use uuid::Uuid;
+
+struct PublishedIssue {
+    newsletter_issue_id: Uuid,
+    slug: String,
+}
+
+async fn publish_issue(app: &TestApp, title: &str) -> PublishedIssue {
+    let response = app
+        .post_newsletters_form(&serde_json::json!({
+            "title": title,
+            "text_content": "Hi {{ name }}, here is the newsletter",
+            "html_content": "<p>Hi {{ name }}, here is the newsletter</p>",
+            "idempotency_key": Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_is_redirected_to(&response, "/admin/dashboard");
+    sqlx::query_as!(
+        PublishedIssue,
+        "SELECT newsletter_issue_id, slug FROM newsletter_issues WHERE title = $1",
+        title
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch published issue")
+}
+
+#[tokio::test]
+async fn published_issues_are_listed_in_the_archive() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let issue = publish_issue(&app, "Our first issue").await;
+
+    let response = app.get_issues_archive(None).await;
+    assert_eq!(200, response.status().as_u16());
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("Our first issue"));
+    assert!(html_page.contains(&format!(r#"href="/issues/{}""#, issue.slug)));
+}
+
+#[tokio::test]
+async fn archived_issue_can_be_read_by_slug_or_id() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let issue = publish_issue(&app, "Our first issue").await;
+    assert!(issue.slug.starts_with("our-first-issue-"));
+
+    for id_or_slug in [issue.slug, issue.newsletter_issue_id.to_string()] {
+        let response = app.get_issue(&id_or_slug).await;
+        assert_eq!(200, response.status().as_u16());
+        let html_page = response.text().await.unwrap();
+        assert!(html_page.contains("<h1>Our first issue</h1>"));
+        // Personalization falls back to a generic greeting for web readers
+        assert!(html_page.contains("<p>Hi Subscriber, here is the newsletter</p>"));
+    }
+}
+
+#[tokio::test]
+async fn unknown_issues_return_404() {
+    let app = spawn_app().await;
+
+    let response = app.get_issue("not-an-issue").await;
+
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn drafts_and_scheduled_issues_never_appear_in_the_archive() {
+    let app = spawn_app().await;
+    app.insert_issue("A draft issue", "draft", "2023-06-01T00:00:00Z")
+        .await;
+    app.insert_issue("A scheduled issue", "scheduled", "2023-06-01T00:00:00Z")
+        .await;
+    let unpublished = sqlx::query!("SELECT newsletter_issue_id, slug FROM newsletter_issues")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+
+    let html_page = app.get_issues_archive_html(None).await;
+    assert!(!html_page.contains("A draft issue"));
+    assert!(!html_page.contains("A scheduled issue"));
+    for issue in unpublished {
+        assert_eq!(404, app.get_issue(&issue.slug).await.status().as_u16());
+    }
+}
+
+#[tokio::test]
+async fn hidden_issues_are_removed_from_the_archive_until_shown_again() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let issue = publish_issue(&app, "Our first issue").await;
+
+    // Hide
+    let response = app
+        .post_issue_visibility(issue.newsletter_issue_id, true)
+        .await;
+    assert_is_redirected_to(&response, "/admin/issues");
+    let html_page = app.get_admin_issues_html().await;
+    assert!(html_page.contains("The issue has been hidden from the archive."));
+    assert!(html_page.contains("Show in archive"));
+
+    assert!(!app
+        .get_issues_archive_html(None)
+        .await
+        .contains("Our first issue"));
+    assert_eq!(404, app.get_issue(&issue.slug).await.status().as_u16());
+
+    // Show again
+    let response = app
+        .post_issue_visibility(issue.newsletter_issue_id, false)
+        .await;
+    assert_is_redirected_to(&response, "/admin/issues");
+    assert!(app
+        .get_issues_archive_html(None)
+        .await
+        .contains("Our first issue"));
+    assert_eq!(200, app.get_issue(&issue.slug).await.status().as_u16());
+}
+
+#[tokio::test]
+async fn archive_is_paginated_newest_first() {
+    let app = spawn_app().await;
+    for day in 1..=11 {
+        app.insert_issue(
+            &format!("Issue number {:02}", day),
+            "published",
+            &format!("2023-06-{:02}T00:00:00Z", day),
+        )
+        .await;
+    }
+
+    let first_page = app.get_issues_archive_html(None).await;
+    assert!(first_page.contains("Issue number 11"));
+    assert!(first_page.contains("Issue number 02"));
+    assert!(!first_page.contains("Issue number 01"));
+    assert!(first_page.contains(r#"href="/issues?page=2""#));
+
+    let second_page = app.get_issues_archive_html(Some(2)).await;
+    assert!(second_page.contains("Issue number 01"));
+    assert!(!second_page.contains("Issue number 02"));
+    assert!(second_page.contains(r#"href="/issues?page=1""#));
+    assert!(!second_page.contains(r#"href="/issues?page=3""#));
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_hide_an_issue() {
+    let app = spawn_app().await;
+
+    let response = app.post_issue_visibility(Uuid::new_v4(), true).await;
+
+    assert_is_redirected_to(&response, "/login");
+}
@@ -1,8 +1,28 @@
+mod admin_cli;
 mod admin_dashboard;
 mod change_password;
+mod configuration;
+mod csrf;
+mod data_requests;
+mod errors;
+mod feeds;
 mod health_check;
 mod helpers;
+mod issue_delivery_worker;
+mod issues;
 mod login;
+mod metrics;
+mod migrations;
 mod newsletter;
+mod shutdown;
+mod signup;
+mod signup_protection;
+mod subscribers;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_preferences;
+mod subscriptions_unsubscribe;
+mod suppressions;
+mod telemetry;
+mod tracking;
+mod webhooks;
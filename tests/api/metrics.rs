@@ -0,0 +1,199 @@
+use crate::helpers::{
+    create_confirmed_subscriber, spawn_app, spawn_app_with_configuration, TestApp,
+};
+use secrecy::Secret;
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+const TOKEN: &str = "metrics-token";
+
+async fn spawn_app_with_metrics() -> TestApp {
+    spawn_app_with_configuration(|c| {
+        c.metrics.bearer_token = Some(Secret::new(TOKEN.into()));
+    })
+    .await
+}
+
+async fn scrape(app: &TestApp) -> String {
+    let response = app.get_metrics(Some(TOKEN)).await;
+    assert_eq!(200, response.status().as_u16());
+    response.text().await.unwrap()
+}
+
+#[tokio::test]
+async fn metrics_are_disabled_without_a_token() {
+    let app = spawn_app().await;
+
+    let response = app.get_metrics(Some(TOKEN)).await;
+
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn metrics_require_the_bearer_token() {
+    let app = spawn_app_with_metrics().await;
+
+    for token in [None, Some("wrong-token")] {
+        let response = app.get_metrics(token).await;
+        assert_eq!(401, response.status().as_u16());
+    }
+}
+
+#[tokio::test]
+async fn http_requests_are_counted_by_route() {
+    let app = spawn_app_with_metrics().await;
+    app.get_issue("some-slug").await;
+
+    let metrics = scrape(&app).await;
+
+    assert!(metrics.contains(
+        r#"zero2prod_http_requests_total{method="GET",route="/issues/{issue}",status="404"}"#
+    ));
+    assert!(metrics.contains(
+        r#"zero2prod_http_request_duration_seconds_count{method="GET",route="/issues/{issue}"}"#
+    ));
+}
+
+#[tokio::test]
+async fn rejected_signups_are_counted_by_reason() {
+    let app = spawn_app_with_metrics().await;
+    app.post_subscriptions(
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&website=https%3A%2F%2Fspam.example".into(),
+    )
+    .await;
+
+    let metrics = scrape(&app).await;
+
+    assert!(metrics.contains(r#"zero2prod_signup_rejections_total{reason="honeypot"}"#));
+}
+
+#[tokio::test]
+async fn the_queue_and_the_connection_pool_are_measured() {
+    let app = spawn_app_with_metrics().await;
+
+    let metrics = scrape(&app).await;
+
+    assert!(metrics.contains(r#"zero2prod_issue_delivery_queue_depth{state="due"} 0"#));
+    assert!(metrics.contains(r#"zero2prod_issue_delivery_queue_depth{state="scheduled"} 0"#));
+    assert!(metrics.contains("zero2prod_issue_delivery_queue_oldest_due_task_age_seconds 0"));
+    assert!(metrics.contains(r#"zero2prod_db_pool_connections{state="in_use"}"#));
+    assert!(metrics.contains("zero2prod_db_pool_max_connections 10"));
+}
+
+#[tokio::test]
+async fn deliveries_email_api_responses_and_logins_are_measured() {
+    let app = spawn_app_with_metrics().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.test_user.login(&app).await;
+    let response = app
+        .post_newsletters_form(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as text",
+            "html_content": "<p>Newsletter body as html</p>",
+            "idempotency_key": uuid::Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_eq!(303, response.status().as_u16());
+    app.dispatch_all_pending_emails().await;
+
+    let metrics = scrape(&app).await;
+
+    assert!(metrics.contains(r#"zero2prod_issue_deliveries_total{outcome="success"}"#));
+    assert!(metrics.contains("zero2prod_issue_delivery_duration_seconds_count"));
+    assert!(metrics.contains(r#"zero2prod_email_api_responses_total{status="200"}"#));
+    assert!(metrics.contains("zero2prod_password_verification_duration_seconds_count"));
+}
+
+/// Kills the process when dropped, so that a failed assertion does not leave it running
+struct ChildProcess(Child);
+
+impl Drop for ChildProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[tokio::test]
+async fn a_worker_running_on_its_own_exports_its_delivery_metrics() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.test_user.login(&app).await;
+    let response = app
+        .post_newsletters_form(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as text",
+            "html_content": "<p>Newsletter body as html</p>",
+            "idempotency_key": uuid::Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_eq!(303, response.status().as_u16());
+
+    // A process of its own, so that it cannot share a registry with the API under test
+    let token =
+        uuid::Uuid::new_v4().simple().to_string() + &uuid::Uuid::new_v4().simple().to_string();
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let _worker = ChildProcess(
+        Command::new(env!("CARGO_BIN_EXE_zero2prod"))
+            .args(["worker", "--concurrency", "6"])
+            .env_remove("APP_ENVIRONMENT")
+            .env(
+                "APP_DATABASE__DATABASE_NAME",
+                &app.configuration.database.database_name,
+            )
+            .env("APP_EMAIL_CLIENT__BASE_URL", app.email_server.uri())
+            .env("APP_METRICS__BEARER_TOKEN", &token)
+            .env("APP_METRICS__WORKER_PORT", port.to_string())
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("Failed to start the worker"),
+    );
+
+    let client = reqwest::Client::new();
+    let mut metrics = String::new();
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let Ok(response) = client
+            .get(format!("http://127.0.0.1:{}/metrics", port))
+            .bearer_auth(&token)
+            .send()
+            .await
+        else {
+            continue;
+        };
+        assert_eq!(200, response.status().as_u16());
+        metrics = response.text().await.unwrap();
+        if metrics.contains(r#"zero2prod_issue_deliveries_total{outcome="success"} 1"#) {
+            break;
+        }
+    }
+
+    assert!(
+        metrics.contains(r#"zero2prod_issue_deliveries_total{outcome="success"} 1"#),
+        "{}",
+        metrics
+    );
+    assert!(metrics.contains(r#"zero2prod_email_api_responses_total{status="200"} 1"#));
+    assert!(metrics.contains("zero2prod_issue_delivery_duration_seconds_count 1"));
+    // Six loops get a pool larger than the default
+    assert!(metrics.contains("zero2prod_db_pool_max_connections 12"));
+}
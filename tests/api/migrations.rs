@@ -0,0 +1,77 @@
+use crate::helpers::{create_database_for_tests, spawn_app};
+use uuid::Uuid;
+use zero2prod::configuration::{get_configuration, DatabaseSettings};
+use zero2prod::startup::{prepare_database, MIGRATOR};
+
+async fn empty_database() -> DatabaseSettings {
+    let mut configuration = get_configuration().unwrap().database;
+    configuration.database_name = format!("z2p-{}", Uuid::new_v4());
+    create_database_for_tests(&configuration).await;
+    configuration
+}
+
+async fn applied_migrations(configuration: &DatabaseSettings) -> i64 {
+    let pool = sqlx::PgPool::connect_with(configuration.with_db())
+        .await
+        .unwrap();
+    sqlx::query_scalar("SELECT COUNT(*) FROM _sqlx_migrations WHERE success")
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn instances_starting_together_migrate_the_database_once() {
+    let configuration = empty_database().await;
+
+    let (first, second, third) = tokio::join!(
+        prepare_database(&configuration, true),
+        prepare_database(&configuration, true),
+        prepare_database(&configuration, true),
+    );
+
+    first.unwrap();
+    second.unwrap();
+    third.unwrap();
+    assert_eq!(
+        applied_migrations(&configuration).await,
+        MIGRATOR.iter().count() as i64
+    );
+}
+
+#[tokio::test]
+async fn migrations_are_not_applied_unless_asked_to() {
+    let configuration = empty_database().await;
+
+    prepare_database(&configuration, false).await.unwrap();
+
+    let pool = sqlx::PgPool::connect_with(configuration.with_db())
+        .await
+        .unwrap();
+    let migrated: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(!migrated);
+}
+
+#[tokio::test]
+async fn we_refuse_to_start_on_a_schema_from_a_newer_release() {
+    let app = spawn_app().await;
+    sqlx::query(
+        r#"
+        INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+        VALUES (99991231000000, 'from the future', true, '\x00', 0)
+        "#,
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    for run_migrations in [false, true] {
+        let error = prepare_database(&app.configuration.database, run_migrations)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("99991231000000"));
+    }
+}
@@ -1,8 +1,6 @@
-use crate::helpers::{assert_is_redirected_to, spawn_app, ConfirmationLinks, TestApp};
-
-use fake::{
-    faker::{internet::en::SafeEmail, name::en::Name},
-    Fake,
+use crate::helpers::{
+    assert_is_redirected_to, create_confirmed_subscriber, create_confirmed_subscriber_named,
+    create_unconfirmed_subscriber, spawn_app,
 };
 use std::time::Duration;
 use wiremock::{
@@ -235,48 +233,220 @@ async fn concurrent_form_submission_is_handled_gracefully() {
     app.dispatch_all_pending_emails().await;
 }
 
-async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
-    let name: String = Name().fake();
-    let email: String = SafeEmail().fake();
-    let body = serde_urlencoded::to_string(&serde_json::json!({
-        "name": name,
-        "email": email,
-    }))
-    .unwrap();
+#[tokio::test]
+async fn newsletters_are_personalized_for_each_subscriber() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber_named(&app, "Ursula").await;
+    app.test_user.login(&app).await;
 
-    // since we are using 'mount_as_scoped', we get back a MockGuard, when that goes out of scope
-    // the Drop impl causes the underlying MockServer to stop supporting this route AND check the expectation(s)
-    let _mock_guard = Mock::given(path("/email"))
+    Mock::given(path("/email"))
         .and(method("POST"))
         .respond_with(ResponseTemplate::new(200))
-        .named("Create unconfirmed subscriber")
         .expect(1)
-        .mount_as_scoped(&app.email_server)
+        .mount(&app.email_server)
         .await;
 
-    app.post_subscriptions(body.into())
-        .await
-        .error_for_status()
-        .unwrap();
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "text_content": "Hi {{ name }}, here is the newsletter",
+        "html_content": "<p>Hi {{ name }}, here is the newsletter</p>",
+        "idempotency_key": uuid::Uuid::new_v4().to_string(),
+    });
+    let response = app.post_newsletters_form(&newsletter_request_body).await;
+    assert_is_redirected_to(&response, "/admin/dashboard");
 
-    let email_request = &app
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
         .email_server
         .received_requests()
         .await
         .unwrap()
         .pop()
         .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let html = body["HtmlBody"].as_str().unwrap();
+    let text = body["TextBody"].as_str().unwrap();
+    assert!(
+        html.contains("<p>Hi Ursula, here is the newsletter</p>"),
+        "{}",
+        html
+    );
+    assert!(
+        text.contains("Hi Ursula, here is the newsletter"),
+        "{}",
+        text
+    );
+    assert!(html.contains("/subscriptions/unsubscribe?subscription_token="));
+    assert!(text.contains("/subscriptions/unsubscribe?subscription_token="));
+    assert!(html.contains("/issues/newsletter-title-"));
+    assert!(text.contains("/issues/newsletter-title-"));
+    // Mail clients offer one-click unsubscription with these
+    let headers = body["Headers"].as_array().unwrap();
+    assert_eq!(headers[0]["Name"], "List-Unsubscribe");
+    assert!(headers[0]["Value"]
+        .as_str()
+        .unwrap()
+        .contains("/subscriptions/unsubscribe?subscription_token="));
+    assert_eq!(headers[1]["Name"], "List-Unsubscribe-Post");
+    assert_eq!(headers[1]["Value"], "List-Unsubscribe=One-Click");
+}
+
+#[tokio::test]
+async fn newsletters_with_a_broken_template_are_rejected_before_delivery() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
 
-    app.get_confirmation_links(email_request)
+    let test_cases = vec![
+        ("<p>Hi {{ nickname }}</p>", "Hi", "unknown variable"),
+        ("<p>Hi</p>", "Hi {{ name", "unterminated tag"),
+    ];
+    for (html_content, text_content, description) in test_cases {
+        let response = app
+            .post_newsletters_form(&serde_json::json!({
+                "title": "Newsletter title",
+                "text_content": text_content,
+                "html_content": html_content,
+                "idempotency_key": uuid::Uuid::new_v4().to_string(),
+            }))
+            .await;
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not reject a template with an {}",
+            description
+        );
+    }
+
+    app.dispatch_all_pending_emails().await;
 }
 
-async fn create_confirmed_subscriber(app: &TestApp) {
-    // we can re-use the existing helper and just add the extra step to call the confirmation link
-    let confirmation_link = create_unconfirmed_subscriber(app).await;
+#[tokio::test]
+async fn newsletter_html_is_sanitized_and_removed_elements_are_reported() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "text_content": "Newsletter body as plain text",
+        "html_content": r#"<p>Newsletter body</p><script>alert("pwned")</script><iframe src="https://example.com"></iframe>"#,
+        "idempotency_key": uuid::Uuid::new_v4().to_string(),
+    });
+    let response = app.post_newsletters_form(&newsletter_request_body).await;
+    assert_is_redirected_to(&response, "/admin/dashboard");
 
-    reqwest::get(confirmation_link.html)
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(
+        html_page.contains(
+            "The following elements are not allowed and were removed from the HTML content: iframe, script"
+        ),
+        "{}",
+        html_page
+    );
+    let saved = sqlx::query!("SELECT html_content FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.html_content, "<p>Newsletter body</p>");
+
+    // Submitting the form again warns again
+    let response = app.post_newsletters_form(&newsletter_request_body).await;
+    assert_is_redirected_to(&response, "/admin/dashboard");
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(html_page.contains("were removed from the HTML content: iframe, script"));
+
+    app.dispatch_all_pending_emails().await;
+    let email_request = app
+        .email_server
+        .received_requests()
         .await
         .unwrap()
-        .error_for_status()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let html = body["HtmlBody"].as_str().unwrap();
+    assert!(html.contains("<p>Newsletter body</p>"), "{}", html);
+    assert!(!html.contains("<script>"), "{}", html);
+    assert!(!html.contains("<iframe"), "{}", html);
+}
+
+#[tokio::test]
+async fn api_clients_get_the_created_issue_and_the_removed_elements_in_the_response() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Hi {{ name }}</p><form><input></form>",
+        },
+        "idempotency_key": uuid::Uuid::new_v4().to_string(),
+    });
+    let response = app.post_newsletters(newsletter_request_body.clone()).await;
+    assert_eq!(201, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body["removed_elements"],
+        serde_json::json!(["form", "input"])
+    );
+    let issue = sqlx::query!("SELECT newsletter_issue_id, html_content FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
         .unwrap();
+    assert_eq!(
+        body["newsletter_issue_id"],
+        issue.newsletter_issue_id.to_string()
+    );
+    assert_eq!(issue.html_content, "<p>Hi {{ name }}</p>");
+
+    // A replay gets the same response, and leaves no flash message behind either
+    let response = app.post_newsletters(newsletter_request_body).await;
+    assert_eq!(201, response.status().as_u16());
+    let replayed: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(replayed, body);
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(!html_page.contains("The newsletter issue has been accepted"));
+}
+
+#[tokio::test]
+async fn lists_and_table_headers_are_kept_in_newsletter_html() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let html_content = "<ul><li>First</li><li><u>Second</u></li></ul>\
+        <table><thead><tr><th>Item</th></tr></thead>\
+        <tbody><tr><td>Tea</td></tr></tbody>\
+        <tfoot><tr><td>Total</td></tr></tfoot></table>";
+
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "text_content": "Newsletter body as plain text",
+        "html_content": html_content,
+        "idempotency_key": uuid::Uuid::new_v4().to_string(),
+    });
+    let response = app.post_newsletters_form(&newsletter_request_body).await;
+    assert_is_redirected_to(&response, "/admin/dashboard");
+
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(!html_page.contains("were removed from the HTML content"));
+    let saved = sqlx::query!("SELECT html_content FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved newsletter issue");
+    assert_eq!(saved.html_content, html_content);
 }
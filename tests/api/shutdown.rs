@@ -0,0 +1,114 @@
+use crate::helpers::{
+    create_confirmed_subscriber, spawn_app, spawn_app_with_configuration, TestApp,
+};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+/// Queue an issue for delivery to a confirmed subscriber, whose email takes `delay` to send
+async fn enqueue_slow_delivery(app: &TestApp, delay: Duration) {
+    create_confirmed_subscriber(app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(delay))
+        .mount(&app.email_server)
+        .await;
+    app.test_user.login(app).await;
+    let response = app
+        .post_newsletters_form(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as text",
+            "html_content": "<p>Newsletter body as html</p>",
+            "idempotency_key": uuid::Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_eq!(303, response.status().as_u16());
+}
+
+async fn email_requests(app: &TestApp) -> usize {
+    app.email_server.received_requests().await.unwrap().len()
+}
+
+/// Wait for the worker to be in the middle of sending an email
+async fn wait_for_email_request(app: &TestApp, already_sent: usize) {
+    for _ in 0..100 {
+        if email_requests(app).await > already_sent {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("The worker never called the email API");
+}
+
+async fn queued_deliveries(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+#[tokio::test]
+async fn the_server_stops_once_shut_down() {
+    let app = spawn_app().await;
+
+    app.shutdown.cancel();
+
+    for _ in 0..100 {
+        // A new connection every time: those already open are served until they are idle
+        let response = reqwest::Client::new()
+            .get(format!("{}/health", app.server_address))
+            .send()
+            .await;
+        if response.is_err() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("The server kept accepting requests after being shut down");
+}
+
+#[tokio::test]
+async fn the_worker_finishes_its_current_delivery_before_stopping() {
+    let app = spawn_app().await;
+    enqueue_slow_delivery(&app, Duration::from_millis(500)).await;
+    let already_sent = email_requests(&app).await;
+    let shutdown = CancellationToken::new();
+    let worker = app.spawn_worker(1, shutdown.clone());
+
+    wait_for_email_request(&app, already_sent).await;
+    shutdown.cancel();
+
+    tokio::time::timeout(Duration::from_secs(5), worker)
+        .await
+        .expect("The worker did not stop")
+        .unwrap()
+        .unwrap();
+    assert_eq!(queued_deliveries(&app).await, 0);
+}
+
+#[tokio::test]
+async fn the_worker_abandons_its_current_delivery_at_the_deadline() {
+    let app = spawn_app_with_configuration(|c| {
+        c.application.shutdown_deadline_seconds = 0;
+    })
+    .await;
+    enqueue_slow_delivery(&app, Duration::from_secs(3)).await;
+    let already_sent = email_requests(&app).await;
+    let shutdown = CancellationToken::new();
+    let worker = app.spawn_worker(1, shutdown.clone());
+
+    wait_for_email_request(&app, already_sent).await;
+    shutdown.cancel();
+
+    tokio::time::timeout(Duration::from_secs(2), worker)
+        .await
+        .expect("The worker did not stop at the deadline")
+        .unwrap()
+        .unwrap();
+    // The delivery was rolled back, for the next worker to pick up
+    assert_eq!(queued_deliveries(&app).await, 1);
+}
@@ -0,0 +1,176 @@
+use crate::helpers::{assert_is_redirected_to, spawn_app, spawn_app_with_configuration, TestApp};
+use wiremock::{
+    matchers::{any, method, path},
+    Mock, ResponseTemplate,
+};
+
+async fn accept_emails(app: &TestApp) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+}
+
+async fn reject_all_emails(app: &TestApp) {
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+}
+
+#[tokio::test]
+async fn the_subscribe_page_has_a_signup_form() {
+    let app = spawn_app().await;
+
+    let response = app.get_subscribe_page("").await;
+    assert_eq!(200, response.status().as_u16());
+
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains(r#"<form method="post" action="/subscriptions">"#));
+    assert!(html_page.contains(r#"name="redirect_to" value="/subscribe""#));
+    assert!(html_page.contains(r#"name="website""#));
+    assert!(html_page.contains("I agree to receive the zero2prod newsletter by email."));
+    assert!(html_page.contains(r#"name="consent_shown" value="true""#));
+}
+
+#[tokio::test]
+async fn subscribing_from_the_subscribe_page_sends_you_back_with_a_confirmation() {
+    let app = spawn_app().await;
+    accept_emails(&app).await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&redirect_to=%2Fsubscribe".into(),
+        )
+        .await;
+    assert_is_redirected_to(&response, "/subscribe?subscription=pending_confirmation");
+
+    let response = app
+        .get_subscribe_page("?subscription=pending_confirmation")
+        .await;
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains(
+        "<p><i>Thanks for subscribing! Please check your inbox to confirm your subscription.</i></p>"
+    ));
+    assert!(!html_page.contains("<form"));
+
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn invalid_details_on_the_subscribe_page_are_flashed_back() {
+    let app = spawn_app().await;
+    reject_all_emails(&app).await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=not-an-email&redirect_to=%2Fsubscribe".into())
+        .await;
+    assert_eq!(303, response.status().as_u16());
+    let location = response.headers()["Location"].to_str().unwrap();
+    assert!(location.starts_with("/subscribe?subscription=error&message="));
+
+    let html_page = app.get_subscribe_html().await;
+    assert!(html_page.contains("<p><i>not-an-email is not recognized as a valid email</i></p>"));
+    assert!(html_page.contains("<form"));
+}
+
+#[tokio::test]
+async fn forms_on_allowed_sites_are_sent_back_with_the_outcome() {
+    let app = spawn_app_with_configuration(|c| {
+        c.cors.allowed_origins = vec!["https://www.example.com".into()];
+    })
+    .await;
+    accept_emails(&app).await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&redirect_to=https%3A%2F%2Fwww.example.com%2Fblog"
+                .into(),
+        )
+        .await;
+    assert_is_redirected_to(
+        &response,
+        "https://www.example.com/blog?subscription=pending_confirmation",
+    );
+
+    let response = app
+        .post_subscriptions(
+            "name=&email=ursula_le_guin%40gmail.com&redirect_to=https%3A%2F%2Fwww.example.com%2Fblog"
+                .into(),
+        )
+        .await;
+    assert_is_redirected_to(
+        &response,
+        "https://www.example.com/blog?subscription=error&message=+is+not+a+valid+subscriber+name.",
+    );
+}
+
+#[tokio::test]
+async fn we_do_not_redirect_to_sites_we_do_not_know() {
+    let app = spawn_app().await;
+    reject_all_emails(&app).await;
+
+    for target in [
+        "https%3A%2F%2Fevil.example.com%2F",
+        "%2F%2Fevil.example.com",
+    ] {
+        let response = app
+            .post_subscriptions(format!(
+                "name=le%20guin&email=ursula_le_guin%40gmail.com&redirect_to={}",
+                target
+            ))
+            .await;
+
+        assert_eq!(400, response.status().as_u16());
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(body["fields"]["redirect_to"]
+            .as_str()
+            .unwrap()
+            .ends_with("is not one of our allowed sites."));
+    }
+
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[tokio::test]
+async fn the_widget_is_a_bare_signup_form_that_comes_back_to_itself() {
+    let app = spawn_app().await;
+
+    let html_page = app.get_signup_widget("").await.text().await.unwrap();
+    assert!(html_page.contains(r#"name="redirect_to" value="/subscribe/widget""#));
+
+    let html_page = app
+        .get_signup_widget("?subscription=pending_confirmation")
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(html_page.contains("Thanks for subscribing!"));
+    assert!(!html_page.contains("<form"));
+}
+
+#[tokio::test]
+async fn the_widget_script_embeds_the_widget() {
+    let app = spawn_app().await;
+
+    let response = app.get_signup_widget_script().await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/javascript; charset=utf-8"
+    );
+    let script = response.text().await.unwrap();
+    assert!(script.contains(r#"var widgetUrl = "http://127.0.0.1/subscribe/widget";"#));
+    assert!(script.contains("data-newsletter-signup"));
+}
@@ -0,0 +1,288 @@
+use crate::helpers::{spawn_app, spawn_app_with_configuration, TestApp};
+use secrecy::Secret;
+use wiremock::{
+    matchers::{any, body_string_contains, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+use zero2prod::configuration::CaptchaSettings;
+use zero2prod::signup_protection::purge_signup_attempts;
+
+async fn count_subscriptions(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+async fn accept_emails(app: &TestApp) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+}
+
+async fn sent_emails(app: &TestApp) -> usize {
+    app.email_server.received_requests().await.unwrap().len()
+}
+
+#[tokio::test]
+async fn filling_in_the_honeypot_looks_successful_but_does_nothing() {
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&website=https%3A%2F%2Fspam.example"
+                .into(),
+        )
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(0, count_subscriptions(&app).await);
+}
+
+#[tokio::test]
+async fn signups_from_the_same_ip_are_rate_limited() {
+    let app = spawn_app_with_configuration(|c| c.signup_protection.max_attempts_per_ip = 2).await;
+    accept_emails(&app).await;
+
+    for (i, domain) in ["gmail.com", "yahoo.com"].iter().enumerate() {
+        let response = app
+            .post_subscriptions(format!("name=le%20guin&email=ursula{}%40{}", i, domain))
+            .await;
+        assert_eq!(200, response.status().as_u16());
+    }
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula%40outlook.com".into())
+        .await;
+
+    assert_eq!(429, response.status().as_u16());
+    assert_eq!(2, count_subscriptions(&app).await);
+    assert_eq!(2, sent_emails(&app).await);
+}
+
+#[tokio::test]
+async fn signups_for_the_same_domain_are_rate_limited() {
+    let app =
+        spawn_app_with_configuration(|c| c.signup_protection.max_attempts_per_domain = 2).await;
+    accept_emails(&app).await;
+
+    for i in 0..2 {
+        let response = app
+            .post_subscriptions(format!("name=le%20guin&email=ursula{}%40victim.com", i))
+            .await;
+        assert_eq!(200, response.status().as_u16());
+    }
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula%40VICTIM.com".into())
+        .await;
+    assert_eq!(429, response.status().as_u16());
+
+    // Other domains are not affected
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula%40gmail.com".into())
+        .await;
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(3, sent_emails(&app).await);
+}
+
+async fn spawn_app_with_captcha(captcha_server: &MockServer) -> TestApp {
+    let verify_url = format!("{}/siteverify", captcha_server.uri());
+    spawn_app_with_configuration(|c| {
+        c.signup_protection.captcha = Some(CaptchaSettings {
+            verify_url,
+            secret: Secret::new("captcha-secret".into()),
+            timeout_milliseconds: 1000,
+        })
+    })
+    .await
+}
+
+#[tokio::test]
+async fn signups_with_a_valid_captcha_go_through() {
+    let captcha_server = MockServer::start().await;
+    let app = spawn_app_with_captcha(&captcha_server).await;
+    accept_emails(&app).await;
+    Mock::given(path("/siteverify"))
+        .and(body_string_contains("secret=captcha-secret"))
+        .and(body_string_contains("response=a-valid-response"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({"success": true})),
+        )
+        .expect(1)
+        .mount(&captcha_server)
+        .await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&captcha_response=a-valid-response"
+                .into(),
+        )
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(1, count_subscriptions(&app).await);
+}
+
+#[tokio::test]
+async fn signups_with_a_rejected_captcha_are_refused() {
+    let captcha_server = MockServer::start().await;
+    let app = spawn_app_with_captcha(&captcha_server).await;
+    Mock::given(path("/siteverify"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({"success": false})),
+        )
+        .expect(1)
+        .mount(&captcha_server)
+        .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&captcha_response=a-bogus-response"
+                .into(),
+        )
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(0, count_subscriptions(&app).await);
+}
+
+#[tokio::test]
+async fn signups_without_a_captcha_are_refused_when_captchas_are_required() {
+    let captcha_server = MockServer::start().await;
+    let app = spawn_app_with_captcha(&captcha_server).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&captcha_server)
+        .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(0, count_subscriptions(&app).await);
+}
+
+#[tokio::test]
+async fn rejected_signups_are_counted_on_the_dashboard() {
+    let app = spawn_app_with_configuration(|c| c.signup_protection.max_attempts_per_ip = 1).await;
+    accept_emails(&app).await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40gmail.com".into())
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40yahoo.com&website=spam".into())
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40outlook.com".into())
+        .await;
+
+    app.test_user.login(&app).await;
+    let html_page = app.get_admin_dashboard_html().await;
+
+    assert!(html_page.contains("Rejected signups in the last 24 hours"));
+    assert!(html_page.contains("<td>honeypot</td>\n    <td>1</td>"));
+    assert!(html_page.contains("<td>rate_limited_ip</td>\n    <td>1</td>"));
+}
+
+#[tokio::test]
+async fn rejected_signups_do_not_count_towards_the_domain_limit() {
+    let app =
+        spawn_app_with_configuration(|c| c.signup_protection.max_attempts_per_domain = 1).await;
+    accept_emails(&app).await;
+
+    for i in 0..3 {
+        let response = app
+            .post_subscriptions(format!("name=bot&email=bot{}%40gmail.com&website=spam", i))
+            .await;
+        assert_eq!(200, response.status().as_u16());
+    }
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula%40gmail.com".into())
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(1, count_subscriptions(&app).await);
+}
+
+#[tokio::test]
+async fn data_requests_do_not_count_as_signups() {
+    let app =
+        spawn_app_with_configuration(|c| c.signup_protection.max_attempts_per_domain = 1).await;
+    accept_emails(&app).await;
+
+    let response = app.post_data_request("ursula@gmail.com", "export").await;
+    assert_eq!(303, response.status().as_u16());
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula%40gmail.com".into())
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(1, count_subscriptions(&app).await);
+    let signups = sqlx::query!("SELECT kind FROM signup_attempts ORDER BY attempted_at")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    let kinds: Vec<_> = signups.iter().map(|r| r.kind.as_str()).collect();
+    assert_eq!(kinds, ["data_request", "signup"]);
+}
+
+#[tokio::test]
+async fn forwarded_for_headers_do_not_get_around_the_ip_limit() {
+    let app = spawn_app_with_configuration(|c| c.signup_protection.max_attempts_per_ip = 1).await;
+    accept_emails(&app).await;
+
+    let mut statuses = Vec::new();
+    for (i, forwarded_for) in ["198.51.100.1", "198.51.100.2"].iter().enumerate() {
+        let response = app
+            .api_client
+            .post(format!("{}/subscriptions", &app.server_address))
+            .header("X-Forwarded-For", *forwarded_for)
+            .form(&[
+                ("name", "le guin".to_string()),
+                ("email", format!("ursula{}@gmail.com", i)),
+            ])
+            .send()
+            .await
+            .unwrap();
+        statuses.push(response.status().as_u16());
+    }
+
+    assert_eq!(statuses, [200, 429]);
+}
+
+#[tokio::test]
+async fn signup_attempts_are_purged_once_they_are_a_week_old() {
+    let app = spawn_app().await;
+    sqlx::query!(
+        r#"
+        INSERT INTO signup_attempts (kind, ip_address, email_domain, outcome, attempted_at)
+        VALUES
+            ('signup', '127.0.0.1', 'gmail.com', 'accepted', now() - interval '8 days'),
+            ('signup', '127.0.0.1', 'gmail.com', 'accepted', now() - interval '6 days')
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let purged = purge_signup_attempts(&app.db_pool).await.unwrap();
+
+    assert_eq!(purged, 1);
+    let left = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM signup_attempts"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(left, 1);
+}
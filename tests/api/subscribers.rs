@@ -0,0 +1,237 @@
+use crate::helpers::{assert_is_redirected_to, spawn_app, spawn_app_with_configuration, TestApp};
+use uuid::Uuid;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+use zero2prod::subscription_history::CONSENT_TEXT;
+
+const USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64) Firefox/118.0";
+
+/// Subscribes through the public form with a browser-like user agent,
+/// returning the id of the new subscriber and its confirmation link
+async fn subscribe(app: &TestApp) -> (Uuid, reqwest::Url) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.api_client
+        .post(format!("{}/subscriptions", &app.server_address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("User-Agent", USER_AGENT)
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com&consent_shown=true")
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request).html;
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id;
+    (subscriber_id, confirmation_link)
+}
+
+#[tokio::test]
+async fn subscribing_records_the_consent_evidence() {
+    let app = spawn_app().await;
+
+    let (subscriber_id, _) = subscribe(&app).await;
+
+    let event = sqlx::query!(
+        r#"
+        SELECT event_type, ip_address, user_agent, consent_text
+        FROM subscription_events
+        WHERE subscriber_id = $1
+        "#,
+        subscriber_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(event.event_type, "subscribed");
+    assert_eq!(event.ip_address.as_deref(), Some("127.0.0.1"));
+    assert_eq!(event.user_agent.as_deref(), Some(USER_AGENT));
+    assert_eq!(event.consent_text.as_deref(), Some(CONSENT_TEXT));
+}
+
+/// The address and consent text recorded when subscribing with `body` and `headers`
+async fn subscribed_event(
+    app: &TestApp,
+    body: &'static str,
+    headers: &[(&str, &str)],
+) -> (Option<String>, Option<String>) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let mut request = app
+        .api_client
+        .post(format!("{}/subscriptions", &app.server_address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(body);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request.send().await.unwrap().error_for_status().unwrap();
+    let event = sqlx::query!("SELECT ip_address, consent_text FROM subscription_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    (event.ip_address, event.consent_text)
+}
+
+#[tokio::test]
+async fn subscriptions_made_without_our_forms_record_no_consent_text() {
+    let app = spawn_app().await;
+
+    let (_, consent_text) =
+        subscribed_event(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com", &[]).await;
+
+    assert_eq!(consent_text, None);
+}
+
+#[tokio::test]
+async fn forwarded_for_headers_from_clients_are_not_recorded() {
+    let app = spawn_app().await;
+
+    let (ip_address, _) = subscribed_event(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com",
+        &[("X-Forwarded-For", "203.0.113.7")],
+    )
+    .await;
+
+    assert_eq!(ip_address.as_deref(), Some("127.0.0.1"));
+}
+
+#[tokio::test]
+async fn trusted_proxies_tell_us_the_address_of_the_client() {
+    let app = spawn_app_with_configuration(|c| {
+        c.application.trusted_proxies = vec!["127.0.0.0/8".parse().unwrap()];
+    })
+    .await;
+
+    let (ip_address, _) = subscribed_event(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com",
+        &[("X-Forwarded-For", "192.0.2.66, 203.0.113.7")],
+    )
+    .await;
+
+    assert_eq!(ip_address.as_deref(), Some("203.0.113.7"));
+}
+
+#[tokio::test]
+async fn confirming_a_subscription_is_recorded_once() {
+    let app = spawn_app().await;
+    let (subscriber_id, confirmation_link) = subscribe(&app).await;
+
+    for _ in 0..2 {
+        reqwest::Client::new()
+            .get(confirmation_link.clone())
+            .header("User-Agent", USER_AGENT)
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+    }
+
+    let events = sqlx::query!(
+        r#"
+        SELECT event_type, user_agent
+        FROM subscription_events
+        WHERE subscriber_id = $1
+        ORDER BY occurred_at
+        "#,
+        subscriber_id
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    let event_types: Vec<_> = events.iter().map(|e| e.event_type.as_str()).collect();
+    assert_eq!(event_types, vec!["subscribed", "confirmed"]);
+    assert_eq!(events[1].user_agent.as_deref(), Some(USER_AGENT));
+}
+
+#[tokio::test]
+async fn confirming_with_an_unknown_token_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?subscription_token=aaaaaaaaaaaaaaaaaaaaaaaaa",
+        app.server_address
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn admins_can_see_the_history_of_a_subscriber() {
+    let app = spawn_app().await;
+    let (subscriber_id, _) = subscribe(&app).await;
+    app.test_user.login(&app).await;
+
+    let html_page = app.get_subscribers_html().await;
+    assert!(html_page.contains(&format!(
+        r#"<a href="/admin/subscribers/{}">ursula_le_guin@gmail.com</a>"#,
+        subscriber_id
+    )));
+
+    let response = app.get_subscriber_history(subscriber_id).await;
+    assert_eq!(200, response.status().as_u16());
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("<td>subscribed</td>"));
+    assert!(html_page.contains("<td>127.0.0.1</td>"));
+    assert!(html_page.contains(USER_AGENT));
+}
+
+#[tokio::test]
+async fn the_history_of_a_subscriber_can_be_exported_as_json() {
+    let app = spawn_app().await;
+    let (subscriber_id, _) = subscribe(&app).await;
+    app.test_user.login(&app).await;
+
+    let response = app.get_subscriber_history_export(subscriber_id).await;
+
+    assert_eq!(200, response.status().as_u16());
+    let disposition = response.headers()["Content-Disposition"].to_str().unwrap();
+    assert!(disposition.starts_with("attachment"));
+    let export: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(export["subscriber"]["email"], "ursula_le_guin@gmail.com");
+    assert_eq!(export["events"][0]["event_type"], "subscribed");
+    assert_eq!(export["events"][0]["ip_address"], "127.0.0.1");
+    assert_eq!(export["events"][0]["user_agent"], USER_AGENT);
+    assert_eq!(export["events"][0]["consent_text"], CONSENT_TEXT);
+}
+
+#[tokio::test]
+async fn the_history_of_an_unknown_subscriber_is_a_404() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let response = app.get_subscriber_history_export(Uuid::new_v4()).await;
+
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_subscriber_history() {
+    let app = spawn_app().await;
+    let (subscriber_id, _) = subscribe(&app).await;
+
+    let response = app.get_subscriber_history(subscriber_id).await;
+    assert_is_redirected_to(&response, "/login");
+    let response = app.get_subscriber_history_export(subscriber_id).await;
+    assert_is_redirected_to(&response, "/login");
+}
@@ -1,5 +1,5 @@
-use crate::helpers::spawn_app;
-use wiremock::matchers::{method, path};
+use crate::helpers::{spawn_app, spawn_app_with_configuration};
+use wiremock::matchers::{any, method, path};
 use wiremock::{Mock, ResponseTemplate};
 
 #[tokio::test]
@@ -184,3 +184,122 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error() {
 
     assert_eq!(response.status().as_u16(), 500);
 }
+
+#[tokio::test]
+async fn subscribe_accepts_json() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions_json(&serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+        }))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email, name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved.name, "le guin");
+}
+
+#[tokio::test]
+async fn validation_errors_are_reported_for_each_field() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions_json(&serde_json::json!({
+            "name": "",
+            "email": "definitely-not-an-email",
+        }))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body["fields"]["email"],
+        "definitely-not-an-email is not recognized as a valid email"
+    );
+    assert_eq!(body["fields"]["name"], " is not a valid subscriber name.");
+}
+
+#[tokio::test]
+async fn validation_errors_for_forms_are_json_too() {
+    let app = spawn_app().await;
+
+    let response = app.post_subscriptions("name=jane%20doe".into()).await;
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["fields"].get("name").is_none());
+    assert_eq!(
+        body["fields"]["email"],
+        " is not recognized as a valid email"
+    );
+}
+
+#[tokio::test]
+async fn browsers_on_allowed_origins_can_subscribe() {
+    let app = spawn_app_with_configuration(|c| {
+        c.cors.allowed_origins = vec!["https://www.example.com".into()];
+    })
+    .await;
+
+    let response = app
+        .api_client
+        .request(
+            reqwest::Method::OPTIONS,
+            format!("{}/subscriptions", app.server_address),
+        )
+        .header("Origin", "https://www.example.com")
+        .header("Access-Control-Request-Method", "POST")
+        .header("Access-Control-Request-Headers", "content-type")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        response.headers()["Access-Control-Allow-Origin"],
+        "https://www.example.com"
+    );
+}
+
+#[tokio::test]
+async fn browsers_on_other_origins_cannot_subscribe() {
+    let app = spawn_app_with_configuration(|c| {
+        c.cors.allowed_origins = vec!["https://www.example.com".into()];
+    })
+    .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .api_client
+        .post(format!("{}/subscriptions", app.server_address))
+        .header("Origin", "https://evil.example.com")
+        .json(&serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com",
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert!(response
+        .headers()
+        .get("Access-Control-Allow-Origin")
+        .is_none());
+    assert_eq!(400, response.status().as_u16());
+}
@@ -0,0 +1,622 @@
+use zero2prod::suppression::{suppress_email, SuppressionSource};
+
+use crate::helpers::{
+    assert_is_redirected_to, create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app,
+    TestApp,
+};
+use reqwest::Url;
+use uuid::Uuid;
+use wiremock::{
+    matchers::{any, method, path},
+    Mock, ResponseTemplate,
+};
+
+struct Subscriber {
+    email: String,
+    name: String,
+    token: String,
+}
+
+async fn subscriber(app: &TestApp) -> Subscriber {
+    let row = sqlx::query!(
+        r#"
+        SELECT s.email, s.name, t.subscription_token
+        FROM subscriptions s
+        JOIN subscription_tokens t ON t.subscriber_id = s.id
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    Subscriber {
+        email: row.email,
+        name: row.name,
+        token: row.subscription_token,
+    }
+}
+
+fn preferences(
+    subscriber: &Subscriber,
+    name: &str,
+    email: &str,
+    format: &str,
+    frequency: &str,
+) -> serde_json::Value {
+    serde_json::json!({
+        "subscription_token": subscriber.token,
+        "name": name,
+        "email": email,
+        "preferred_format": format,
+        "delivery_frequency": frequency,
+    })
+}
+
+fn preferences_url(subscriber: &Subscriber) -> String {
+    format!(
+        "/subscriptions/preferences?subscription_token={}",
+        subscriber.token
+    )
+}
+
+fn token(link: &Url) -> String {
+    link.query_pairs()
+        .find(|(key, _)| key == "token")
+        .unwrap()
+        .1
+        .into_owned()
+}
+
+/// Ask to move the subscriber to `new_email` and return the link we emailed there
+async fn request_email_change(app: &TestApp, subscriber: &Subscriber, new_email: &str) -> Url {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let response = app
+        .post_preferences(&preferences(
+            subscriber,
+            &subscriber.name,
+            new_email,
+            "html",
+            "every_issue",
+        ))
+        .await;
+    assert_is_redirected_to(&response, &preferences_url(subscriber));
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_confirmation_links(&email_request).html
+}
+
+async fn current_email(app: &TestApp) -> String {
+    sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .email
+}
+
+async fn publish_newsletter(app: &TestApp) {
+    publish_newsletter_titled(app, "Newsletter title").await;
+}
+
+async fn publish_newsletter_titled(app: &TestApp, title: &str) {
+    app.test_user.login(app).await;
+    let response = app
+        .post_newsletters_form(&serde_json::json!({
+            "title": title,
+            "text_content": "Newsletter body as plain text",
+            "html_content": "<p>Newsletter body as html</p>",
+            "idempotency_key": Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_is_redirected_to(&response, "/admin/dashboard");
+}
+
+#[tokio::test]
+async fn the_preferences_page_requires_a_known_token() {
+    let app = spawn_app().await;
+
+    let response = app.get_preferences("not-a-token").await;
+
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn the_preferences_page_shows_the_current_preferences() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+
+    let response = app.get_preferences(&subscriber.token).await;
+
+    assert_eq!(200, response.status().as_u16());
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains(&subscriber.email));
+    assert!(html_page.contains(r#"value="html" checked="true""#));
+    assert!(html_page.contains(r#"value="every_issue" checked="true""#));
+}
+
+#[tokio::test]
+async fn subscribers_can_update_their_preferences() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+
+    let response = app
+        .post_preferences(&preferences(
+            &subscriber,
+            "Ursula",
+            &subscriber.email,
+            "text",
+            "weekly",
+        ))
+        .await;
+
+    assert_is_redirected_to(&response, &preferences_url(&subscriber));
+    let html_page = app
+        .get_preferences(&subscriber.token)
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(html_page.contains("Your preferences have been saved."));
+    assert!(html_page.contains(r#"value="text" checked="true""#));
+    assert!(html_page.contains(r#"value="weekly" checked="true""#));
+    let saved =
+        sqlx::query!("SELECT name, preferred_format, delivery_frequency FROM subscriptions")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(saved.name, "Ursula");
+    assert_eq!(saved.preferred_format, "text");
+    assert_eq!(saved.delivery_frequency, "weekly");
+}
+
+#[tokio::test]
+async fn invalid_names_are_rejected() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+
+    let response = app
+        .post_preferences(&preferences(
+            &subscriber,
+            "<script>",
+            &subscriber.email,
+            "text",
+            "every_issue",
+        ))
+        .await;
+
+    assert_is_redirected_to(&response, &preferences_url(&subscriber));
+    let html_page = app
+        .get_preferences(&subscriber.token)
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(html_page.contains("is not a valid subscriber name."));
+    let saved = sqlx::query!("SELECT name, preferred_format FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.name, subscriber.name);
+    assert_eq!(saved.preferred_format, "html");
+}
+
+#[tokio::test]
+async fn issues_link_to_the_preferences_page() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    publish_newsletter(&app).await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let link = preferences_url(&subscriber);
+    assert!(body["HtmlBody"].as_str().unwrap().contains(&link));
+    assert!(body["TextBody"].as_str().unwrap().contains(&link));
+}
+
+#[tokio::test]
+async fn text_only_subscribers_receive_no_html() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+    app.post_preferences(&preferences(
+        &subscriber,
+        &subscriber.name,
+        &subscriber.email,
+        "text",
+        "every_issue",
+    ))
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    publish_newsletter(&app).await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert!(body.get("HtmlBody").is_none());
+    assert!(body["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("Newsletter body as plain text"));
+}
+
+#[tokio::test]
+async fn issues_for_weekly_subscribers_are_held_back() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+    app.post_preferences(&preferences(
+        &subscriber,
+        &subscriber.name,
+        &subscriber.email,
+        "html",
+        "weekly",
+    ))
+    .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    publish_newsletter(&app).await;
+    app.dispatch_all_pending_emails().await;
+
+    let task = sqlx::query!(
+        r#"
+        SELECT execute_after, date_trunc('week', now()) + interval '7 days' AS "next_monday!"
+        FROM issue_delivery_queue
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(task.execute_after, task.next_monday);
+}
+
+#[tokio::test]
+async fn issues_held_back_are_sent_together_in_one_digest() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+    app.post_preferences(&preferences(
+        &subscriber,
+        &subscriber.name,
+        &subscriber.email,
+        "html",
+        "weekly",
+    ))
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter_titled(&app, "First issue").await;
+    publish_newsletter_titled(&app, "Second issue").await;
+
+    // Monday comes
+    sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "Your weekly digest");
+    let text = body["TextBody"].as_str().unwrap();
+    let first = text.find("First issue").unwrap();
+    let second = text.find("Second issue").unwrap();
+    assert!(first < second, "{}", text);
+    assert!(body["HtmlBody"].as_str().unwrap().contains("Second issue"));
+    let queued = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(queued, 0);
+}
+
+#[tokio::test]
+async fn changing_the_frequency_reschedules_issues_already_held_back() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+    let weekly = preferences(
+        &subscriber,
+        &subscriber.name,
+        &subscriber.email,
+        "html",
+        "weekly",
+    );
+    app.post_preferences(&weekly).await;
+    publish_newsletter(&app).await;
+
+    let every_issue = preferences(
+        &subscriber,
+        &subscriber.name,
+        &subscriber.email,
+        "html",
+        "every_issue",
+    );
+    app.post_preferences(&every_issue).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "Newsletter title");
+}
+
+#[tokio::test]
+async fn changing_email_requires_confirming_the_new_address() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_preferences(&preferences(
+            &subscriber,
+            &subscriber.name,
+            "ursula_le_guin@gmail.com",
+            "html",
+            "every_issue",
+        ))
+        .await;
+
+    assert_is_redirected_to(&response, &preferences_url(&subscriber));
+    let html_page = app
+        .get_preferences(&subscriber.token)
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(html_page.contains("We have sent a confirmation link to ursula_le_guin@gmail.com."));
+    assert_eq!(current_email(&app).await, subscriber.email);
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], "ursula_le_guin@gmail.com");
+    let confirmation_link = app.get_confirmation_links(&email_request).html;
+    // Following the link only shows a confirmation page, whoever follows it
+    let response = reqwest::get(confirmation_link.clone()).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("The newsletter will be sent to ursula_le_guin@gmail.com from now on."));
+    assert_eq!(current_email(&app).await, subscriber.email);
+
+    let response = app
+        .post_confirm_email_change(&token(&confirmation_link))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(current_email(&app).await, "ursula_le_guin@gmail.com");
+    let event =
+        sqlx::query!("SELECT details FROM subscription_events WHERE event_type = 'email_changed'")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(event.details["previous_email"], subscriber.email);
+}
+
+#[tokio::test]
+async fn unconfirmed_subscribers_cannot_use_the_preferences_page() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let page = app.get_preferences(&subscriber.token).await;
+    let update = app
+        .post_preferences(&preferences(
+            &subscriber,
+            &subscriber.name,
+            "ursula_le_guin@gmail.com",
+            "html",
+            "every_issue",
+        ))
+        .await;
+
+    assert_eq!(401, page.status().as_u16());
+    assert_eq!(401, update.status().as_u16());
+}
+
+#[tokio::test]
+async fn email_change_requests_are_rate_limited() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(3)
+        .mount(&app.email_server)
+        .await;
+
+    for i in 0..4 {
+        let response = app
+            .post_preferences(&preferences(
+                &subscriber,
+                &subscriber.name,
+                &format!("victim{}@example.com", i),
+                "html",
+                "every_issue",
+            ))
+            .await;
+        assert_is_redirected_to(&response, &preferences_url(&subscriber));
+    }
+
+    let html_page = app
+        .get_preferences(&subscriber.token)
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(html_page.contains("You have asked to change your address too many times."));
+}
+
+#[tokio::test]
+async fn unknown_email_change_tokens_are_rejected() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!(
+        "{}/subscriptions/preferences/confirm-email?token=not-a-token",
+        app.server_address
+    ))
+    .await
+    .unwrap();
+    assert_eq!(401, response.status().as_u16());
+
+    let response = app.post_confirm_email_change("not-a-token").await;
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn addresses_suppressed_or_taken_since_the_request_cannot_be_confirmed() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+    let suppressed_link = request_email_change(&app, &subscriber, "ursula@gmail.com").await;
+    let taken_link = request_email_change(&app, &subscriber, "le_guin@gmail.com").await;
+    suppress_email(
+        &app.db_pool,
+        "Ursula@gmail.com",
+        SuppressionSource::Bounce,
+        "Hard bounce",
+    )
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, 'Le_Guin@gmail.com', 'Ursula', now(), 'confirmed')
+        "#,
+        Uuid::new_v4()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    for link in [suppressed_link, taken_link] {
+        let response = app.post_confirm_email_change(&token(&link)).await;
+        assert_eq!(409, response.status().as_u16());
+    }
+    assert_eq!(current_email(&app).await, subscriber.email);
+}
+
+#[tokio::test]
+async fn you_cannot_move_to_an_address_that_is_already_subscribed() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let subscriber = subscriber(&app).await;
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, 'ursula_le_guin@gmail.com', 'Ursula', now(), 'confirmed')
+        "#,
+        Uuid::new_v4()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_preferences(&preferences(
+            &subscriber,
+            &subscriber.name,
+            "Ursula_Le_Guin@gmail.com",
+            "html",
+            "every_issue",
+        ))
+        .await;
+
+    assert_is_redirected_to(&response, &preferences_url(&subscriber));
+    let html_page = app
+        .get_preferences(&subscriber.token)
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(html_page.contains("Ursula_Le_Guin@gmail.com cannot be used for this subscription."));
+}
@@ -0,0 +1,133 @@
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::{spawn_app, TestApp};
+
+#[tokio::test]
+async fn unsubscribing_without_token_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(&format!("{}/subscriptions/unsubscribe", app.server_address))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn unsubscribing_with_an_unknown_token_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/unsubscribe?subscription_token=unknown",
+        app.server_address
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+/// Subscribe and confirm, returning the subscription token
+async fn confirmed_subscription_token(app: &TestApp) -> String {
+    let body = "name=jose&email=josecuervo%40example.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    sqlx::query!("SELECT subscription_token FROM subscription_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("failed to fetch subscription token")
+        .subscription_token
+}
+
+async fn subscription_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("failed to fetch saved subscription")
+        .status
+}
+
+#[tokio::test]
+async fn following_the_unsubscribe_link_only_asks_for_confirmation() {
+    let app = spawn_app().await;
+    let token = confirmed_subscription_token(&app).await;
+
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/unsubscribe?subscription_token={}",
+        app.server_address, token
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains(&format!(
+        r#"<form method="post" action="/subscriptions/unsubscribe?subscription_token={}">"#,
+        token
+    )));
+    assert_eq!(subscription_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn unsubscribing_marks_the_subscription_as_unsubscribed() {
+    let app = spawn_app().await;
+    let token = confirmed_subscription_token(&app).await;
+
+    let response = app
+        .api_client
+        .post(format!(
+            "{}/subscriptions/unsubscribe?subscription_token={}",
+            app.server_address, token
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(subscription_status(&app).await, "unsubscribed");
+    let suppressed = sqlx::query!("SELECT email, source FROM suppressed_emails")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("failed to fetch suppressed email");
+    assert_eq!(suppressed.email, "josecuervo@example.com");
+    assert_eq!(suppressed.source, "unsubscribe");
+}
+
+#[tokio::test]
+async fn mail_clients_can_unsubscribe_in_one_click() {
+    let app = spawn_app().await;
+    let token = confirmed_subscription_token(&app).await;
+
+    let response = app
+        .api_client
+        .post(format!(
+            "{}/subscriptions/unsubscribe?subscription_token={}",
+            app.server_address, token
+        ))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscription_status(&app).await, "unsubscribed");
+}
@@ -0,0 +1,200 @@
+use crate::helpers::{assert_is_redirected_to, create_confirmed_subscriber, spawn_app, TestApp};
+use uuid::Uuid;
+use wiremock::{
+    matchers::{any, method, path},
+    Mock, ResponseTemplate,
+};
+
+async fn subscriber_email(app: &TestApp) -> String {
+    sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .email
+}
+
+async fn publish_newsletter(app: &TestApp) {
+    let response = app
+        .post_newsletters_form(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as plain text",
+            "html_content": "<p>Newsletter body as html</p>",
+            "idempotency_key": Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_is_redirected_to(&response, "/admin/dashboard");
+}
+
+#[tokio::test]
+async fn subscribing_with_a_suppressed_address_looks_successful_but_does_nothing() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    app.post_add_suppression("ursula_le_guin@gmail.com", "Asked us to stop")
+        .await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    let n_subscriptions = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(0, n_subscriptions);
+}
+
+#[tokio::test]
+async fn suppressed_addresses_can_subscribe_again_once_removed() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    app.post_add_suppression("ursula_le_guin@gmail.com", "")
+        .await;
+
+    let response = app
+        .post_remove_suppression("ursula_le_guin@gmail.com")
+        .await;
+    assert_is_redirected_to(&response, "/admin/suppressions");
+    assert!(app
+        .get_suppressions_html()
+        .await
+        .contains("ursula_le_guin@gmail.com has been removed from the suppression list."));
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_suppressed_addresses() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    app.post_add_suppression(&subscriber_email(&app).await, "")
+        .await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    publish_newsletter(&app).await;
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn the_worker_skips_addresses_suppressed_after_the_issue_was_published() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    publish_newsletter(&app).await;
+    // The delivery task is already in the queue when the address gets suppressed
+    app.post_add_suppression(&subscriber_email(&app).await, "")
+        .await;
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn complaints_and_hard_bounces_are_added_to_the_suppression_list() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    app.post_postmark_webhook(&serde_json::json!({
+        "RecordType": "SpamComplaint",
+        "Email": "complainer@example.com",
+    }))
+    .await;
+    app.post_postmark_webhook(&serde_json::json!({
+        "RecordType": "Bounce",
+        "Type": "HardBounce",
+        "Email": "bouncer@example.com",
+    }))
+    .await;
+    app.post_postmark_webhook(&serde_json::json!({
+        "RecordType": "Bounce",
+        "Type": "SoftBounce",
+        "Email": "soft-bouncer@example.com",
+    }))
+    .await;
+
+    let suppressed = sqlx::query!("SELECT email, source FROM suppressed_emails ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(2, suppressed.len());
+    assert_eq!(
+        ("bouncer@example.com", "bounce"),
+        (suppressed[0].email.as_str(), suppressed[0].source.as_str())
+    );
+    assert_eq!(
+        ("complainer@example.com", "complaint"),
+        (suppressed[1].email.as_str(), suppressed[1].source.as_str())
+    );
+}
+
+#[tokio::test]
+async fn suppression_list_is_shown_to_admins() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let response = app
+        .post_add_suppression("ursula_le_guin@gmail.com", "Asked us to stop")
+        .await;
+    assert_is_redirected_to(&response, "/admin/suppressions");
+
+    let html_page = app.get_suppressions_html().await;
+    assert!(html_page.contains("ursula_le_guin@gmail.com will not receive any more emails."));
+    assert!(html_page.contains("<td>ursula_le_guin@gmail.com</td>"));
+    assert!(html_page.contains("<td>manual</td>"));
+    assert!(html_page.contains("<td>Asked us to stop</td>"));
+}
+
+#[tokio::test]
+async fn invalid_addresses_cannot_be_suppressed() {
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    let response = app.post_add_suppression("not-an-email", "").await;
+    assert_is_redirected_to(&response, "/admin/suppressions");
+
+    let html_page = app.get_suppressions_html().await;
+    assert!(html_page.contains("not-an-email is not recognized as a valid email"));
+    assert!(html_page.contains("No address has been suppressed."));
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_manage_the_suppression_list() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_add_suppression("ursula_le_guin@gmail.com", "")
+        .await;
+    assert_is_redirected_to(&response, "/login");
+
+    let response = app
+        .post_remove_suppression("ursula_le_guin@gmail.com")
+        .await;
+    assert_is_redirected_to(&response, "/login");
+}
@@ -0,0 +1,182 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, SPAN_EXPORTER};
+use opentelemetry::trace::{SpanId, TraceId};
+use opentelemetry_sdk::export::trace::SpanData;
+use std::time::Duration;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+const PARENT_SPAN_ID: &str = "00f067aa0ba902b7";
+
+fn random_trace_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+fn traceparent(trace_id: &str) -> String {
+    format!("00-{}-{}-01", trace_id, PARENT_SPAN_ID)
+}
+
+/// Spans are exported when they close, which may be just after we got our response
+async fn spans_of_trace(trace_id: &str, name: &str) -> Vec<SpanData> {
+    let trace_id = TraceId::from_hex(trace_id).unwrap();
+    for _ in 0..50 {
+        let spans: Vec<SpanData> = SPAN_EXPORTER
+            .get_finished_spans()
+            .unwrap()
+            .into_iter()
+            .filter(|span| span.span_context.trace_id() == trace_id)
+            .collect();
+        if spans.iter().any(|span| span.name == name) {
+            return spans;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("No span named {} was exported for trace {}", name, trace_id);
+}
+
+#[tokio::test]
+async fn requests_join_the_trace_of_the_traceparent_header() {
+    let app = spawn_app().await;
+    let trace_id = random_trace_id();
+
+    let response = app
+        .api_client
+        .get(format!("{}/health", app.server_address))
+        .header("traceparent", traceparent(&trace_id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
+
+    let spans = spans_of_trace(&trace_id, "GET /health").await;
+    let root_span = spans
+        .iter()
+        .find(|span| span.name == "GET /health")
+        .unwrap();
+    assert_eq!(
+        root_span.parent_span_id,
+        SpanId::from_hex(PARENT_SPAN_ID).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn calls_to_the_email_api_carry_the_trace_context() {
+    let app = spawn_app().await;
+    let trace_id = random_trace_id();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.api_client
+        .post(format!("{}/subscriptions", app.server_address))
+        .header("traceparent", traceparent(&trace_id))
+        .form(&[("name", "le guin"), ("email", "ursula_le_guin@gmail.com")])
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let header = email_request
+        .headers
+        .iter()
+        .find(|(name, _)| name.as_str() == "traceparent")
+        .map(|(_, values)| values.last().as_str().to_owned())
+        .unwrap();
+    assert!(header.starts_with(&format!("00-{}-", trace_id)));
+    spans_of_trace(&trace_id, "Call the email API").await;
+}
+
+fn request_id_of(response: &reqwest::Response) -> uuid::Uuid {
+    let header = response.headers()["X-Request-Id"].to_str().unwrap();
+    uuid::Uuid::parse_str(header).unwrap()
+}
+
+#[tokio::test]
+async fn responses_carry_the_id_of_their_request() {
+    let app = spawn_app().await;
+
+    let health = app.get_health("live").await;
+    let error = app
+        .api_client
+        .get(format!(
+            "{}/subscriptions/confirm?subscription_token=unknown",
+            app.server_address
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, health.status().as_u16());
+    assert_eq!(401, error.status().as_u16());
+    assert_ne!(request_id_of(&health), request_id_of(&error));
+    let error_request_id = request_id_of(&error);
+    let body: serde_json::Value = error.json().await.unwrap();
+    assert_eq!(body["request_id"], error_request_id.to_string());
+}
+
+#[tokio::test]
+async fn middleware_errors_carry_the_id_of_their_request() {
+    let app = spawn_app().await;
+
+    // Rejected by the authentication middleware, before reaching a handler
+    let response = app.get_admin_dashboard().await;
+
+    assert_eq!(303, response.status().as_u16());
+    request_id_of(&response);
+}
+
+#[tokio::test]
+async fn issue_deliveries_can_be_traced_back_to_the_publishing_request() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.test_user.login(&app).await;
+
+    // Act
+    let response = app
+        .post_newsletters_form(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as text",
+            "html_content": "<p>Newsletter body as html</p>",
+            "idempotency_key": uuid::Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_eq!(303, response.status().as_u16());
+    let request_id = request_id_of(&response);
+
+    // Assert
+    let issue = sqlx::query!("SELECT request_id FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(issue.request_id, Some(request_id));
+    let task = sqlx::query!("SELECT request_id FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(task.request_id, Some(request_id));
+
+    app.dispatch_all_pending_emails().await;
+    let traced = SPAN_EXPORTER
+        .get_finished_spans()
+        .unwrap()
+        .into_iter()
+        .filter(|span| span.name == "try_execute_task")
+        .any(|span| {
+            span.attributes.iter().any(|attribute| {
+                attribute.key.as_str() == "request_id"
+                    && attribute.value.as_str() == request_id.to_string()
+            })
+        });
+    assert!(traced);
+}
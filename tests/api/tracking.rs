@@ -0,0 +1,162 @@
+use crate::helpers::{assert_is_redirected_to, create_confirmed_subscriber, spawn_app, TestApp};
+use reqwest::Url;
+use uuid::Uuid;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+/// Publish an issue with a single link, deliver it and return the HTML body that was sent
+async fn publish_and_deliver(app: &TestApp, tracking_enabled: bool) -> String {
+    create_confirmed_subscriber(app).await;
+    app.test_user.login(app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_newsletters_form(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as plain text",
+            "html_content": r#"<p>Read <a href="https://example.com/article?id=1&amp;ref=mail">this</a></p>"#,
+            "idempotency_key": Uuid::new_v4().to_string(),
+            "tracking_enabled": tracking_enabled,
+        }))
+        .await;
+    assert_is_redirected_to(&response, "/admin/dashboard");
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    body["HtmlBody"].as_str().unwrap().to_owned()
+}
+
+/// Find the tracking URL with the given prefix in an email, pointing it at the test server
+fn tracking_url(app: &TestApp, html: &str, prefix: &str) -> Url {
+    let start = html
+        .find(prefix)
+        .unwrap_or_else(|| panic!("No {} link in {}", prefix, html));
+    let end = start + html[start..].find('"').unwrap();
+    let mut url = Url::parse(&html[start..end]).unwrap();
+    url.set_port(Some(app.port)).unwrap();
+    url
+}
+
+#[tokio::test]
+async fn tracked_issues_contain_a_pixel_and_rewritten_links() {
+    let app = spawn_app().await;
+
+    let html = publish_and_deliver(&app, true).await;
+
+    assert!(!html.contains("https://example.com"), "{}", html);
+    tracking_url(&app, &html, "http://127.0.0.1/t/c/");
+    tracking_url(&app, &html, "http://127.0.0.1/t/o/");
+}
+
+#[tokio::test]
+async fn untracked_issues_are_sent_as_written() {
+    let app = spawn_app().await;
+
+    let html = publish_and_deliver(&app, false).await;
+
+    assert!(html.contains(r#"href="https://example.com/article?id=1&amp;ref=mail""#));
+    assert!(!html.contains("/t/c/"));
+    assert!(!html.contains("/t/o/"));
+}
+
+#[tokio::test]
+async fn opens_and_clicks_are_recorded_and_shown_on_the_dashboard() {
+    let app = spawn_app().await;
+    let html = publish_and_deliver(&app, true).await;
+
+    // Open, twice - e.g. the email was read on two devices
+    for _ in 0..2 {
+        let response = app
+            .api_client
+            .get(tracking_url(&app, &html, "http://127.0.0.1/t/o/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!("image/gif", response.headers().get("Content-Type").unwrap());
+    }
+
+    // Click
+    let response = app
+        .api_client
+        .get(tracking_url(&app, &html, "http://127.0.0.1/t/c/"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(302, response.status().as_u16());
+    assert_eq!(
+        "https://example.com/article?id=1&ref=mail",
+        response.headers().get("Location").unwrap()
+    );
+
+    let events = sqlx::query!("SELECT kind, url FROM issue_tracking_events ORDER BY kind")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(3, events.len());
+    assert_eq!(
+        Some("https://example.com/article?id=1&ref=mail"),
+        events[0].url.as_deref()
+    );
+
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(
+        html_page
+            .contains("<td>Newsletter title</td>\n    <td>1</td>\n    <td>1</td>\n    <td>1</td>"),
+        "{}",
+        html_page
+    );
+}
+
+#[tokio::test]
+async fn tampered_tracking_tokens_are_rejected() {
+    let app = spawn_app().await;
+    let html = publish_and_deliver(&app, true).await;
+    let click_url = tracking_url(&app, &html, "http://127.0.0.1/t/c/");
+    let token = click_url.path().trim_start_matches("/t/c/");
+    let (_, signature) = token.split_once('.').unwrap();
+
+    // Point the redirect somewhere else, keeping the original signature
+    let forged_payload = base64_url("c|00000000-0000-0000-0000-000000000000|00000000-0000-0000-0000-000000000000|https://evil.example");
+    let test_cases = [
+        format!("/t/c/{}.{}", forged_payload, signature),
+        "/t/c/not-a-token".to_string(),
+        // A click token cannot be used as an open token
+        format!("/t/o/{}", token),
+    ];
+    for path in test_cases {
+        let response = app
+            .api_client
+            .get(format!("{}{}", app.server_address, path))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(404, response.status().as_u16(), "{}", path);
+    }
+
+    let n_events = sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM issue_tracking_events"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(0, n_events);
+}
+
+fn base64_url(input: &str) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(input)
+}
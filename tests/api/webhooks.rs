@@ -0,0 +1,310 @@
+use crate::helpers::{
+    create_confirmed_subscriber, spawn_app, spawn_app_with_configuration, TestApp,
+};
+use uuid::Uuid;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+use zero2prod::routes::WEBHOOK_SECRET_HEADER;
+
+fn bounce(email: &str, bounce_type: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "Bounce",
+        "MessageStream": "outbound",
+        "ID": 4323372036854775807u64,
+        "Type": bounce_type,
+        "TypeCode": 1,
+        "Name": "Hard bounce",
+        "Tag": "",
+        "MessageID": "883953f4-6105-42a2-a16a-77a8eac79483",
+        "Description": "The server was unable to deliver your message (ex: unknown user, mailbox not found).",
+        "Details": "Test bounce details",
+        "Email": email,
+        "From": "sender@example.com",
+        "BouncedAt": "2023-06-01T08:00:00Z",
+        "Inactive": true,
+        "CanActivate": true,
+        "Subject": "Test subject"
+    })
+}
+
+fn spam_complaint(email: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "SpamComplaint",
+        "MessageStream": "outbound",
+        "ID": 42,
+        "Type": "SpamComplaint",
+        "TypeCode": 512,
+        "Name": "Spam complaint",
+        "MessageID": "00000000-0000-0000-0000-000000000000",
+        "Email": email,
+        "From": "sender@example.com",
+        "BouncedAt": "2023-06-01T08:00:00Z",
+        "Subject": "Test subject"
+    })
+}
+
+async fn subscriber_email(app: &TestApp) -> String {
+    sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .email
+}
+
+async fn subscription_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .status
+}
+
+/// The full history of the subscriber, including its subscription and confirmation
+async fn subscription_events(app: &TestApp) -> Vec<(String, serde_json::Value)> {
+    sqlx::query!("SELECT event_type, details FROM subscription_events ORDER BY occurred_at")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| (r.event_type, r.details))
+        .collect()
+}
+
+#[tokio::test]
+async fn hard_bounces_mark_the_subscriber_as_bounced() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let response = app
+        .post_postmark_webhook(&bounce(&email, "HardBounce"))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!("bounced", subscription_status(&app).await);
+    let events = subscription_events(&app).await;
+    assert_eq!(3, events.len());
+    assert_eq!("bounced", events[2].0);
+    assert_eq!("Test bounce details", events[2].1["Details"]);
+}
+
+#[tokio::test]
+async fn spam_complaints_mark_the_subscriber_as_complained() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    // Postmark may report the address with a different case
+    let email = subscriber_email(&app).await.to_uppercase();
+
+    let response = app.post_postmark_webhook(&spam_complaint(&email)).await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!("complained", subscription_status(&app).await);
+    let events = subscription_events(&app).await;
+    assert_eq!(3, events.len());
+    assert_eq!("complained", events[2].0);
+}
+
+#[tokio::test]
+async fn soft_bounces_are_recorded_without_changing_the_subscription() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let response = app
+        .post_postmark_webhook(&bounce(&email, "SoftBounce"))
+        .await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!("confirmed", subscription_status(&app).await);
+    let events = subscription_events(&app).await;
+    assert_eq!(3, events.len());
+    assert_eq!("soft_bounced", events[2].0);
+}
+
+#[tokio::test]
+async fn bounced_subscribers_do_not_receive_newsletters() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    app.post_postmark_webhook(&bounce(&email, "HardBounce"))
+        .await;
+    app.test_user.login(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters_form(&serde_json::json!({
+        "title": "Newsletter title",
+        "text_content": "Newsletter body as plain text",
+        "html_content": "<p>Newsletter body as html</p>",
+        "idempotency_key": Uuid::new_v4().to_string(),
+    }))
+    .await;
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn events_about_unknown_addresses_and_other_record_types_are_acknowledged() {
+    let app = spawn_app().await;
+
+    let test_cases = [
+        bounce("unknown@example.com", "HardBounce"),
+        serde_json::json!({ "RecordType": "Delivery", "Email": "unknown@example.com" }),
+    ];
+    for body in test_cases {
+        let response = app.post_postmark_webhook(&body).await;
+        assert_eq!(200, response.status().as_u16(), "{}", body);
+    }
+    assert!(subscription_events(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn malformed_payloads_are_rejected_with_a_400() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_postmark_webhook(&serde_json::json!({ "RecordType": "Bounce" }))
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn the_webhook_is_disabled_without_a_secret() {
+    let app = spawn_app_with_configuration(|c| c.postmark_webhook.secret = None).await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    let response = app
+        .api_client
+        .post(format!("{}/webhooks/postmark", &app.server_address))
+        .basic_auth(&app.postmark_webhook.username, Some(""))
+        .json(&bounce(&email, "HardBounce"))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(404, response.status().as_u16());
+    assert_eq!("confirmed", subscription_status(&app).await);
+}
+
+#[tokio::test]
+async fn shared_secret_header_is_accepted() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .post(format!("{}/webhooks/postmark", &app.server_address))
+        .header(WEBHOOK_SECRET_HEADER, app.postmark_webhook_secret())
+        .json(&spam_complaint("unknown@example.com"))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn requests_with_missing_or_invalid_credentials_are_rejected() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    let url = format!("{}/webhooks/postmark", &app.server_address);
+    let body = bounce(&email, "HardBounce");
+
+    let test_cases = [
+        ("no credentials", app.api_client.post(&url)),
+        (
+            "wrong password",
+            app.api_client
+                .post(&url)
+                .basic_auth(&app.postmark_webhook.username, Some("wrong")),
+        ),
+        (
+            "wrong username",
+            app.api_client
+                .post(&url)
+                .basic_auth("someone-else", Some(app.postmark_webhook_secret())),
+        ),
+        (
+            "wrong shared secret",
+            app.api_client
+                .post(&url)
+                .header(WEBHOOK_SECRET_HEADER, "wrong"),
+        ),
+    ];
+    for (description, request) in test_cases {
+        let response = request.json(&body).send().await.unwrap();
+        assert_eq!(401, response.status().as_u16(), "{}", description);
+        assert_eq!(
+            r#"Basic realm="webhooks""#,
+            response.headers()["WWW-Authenticate"],
+            "{}",
+            description
+        );
+    }
+    assert_eq!("confirmed", subscription_status(&app).await);
+}
+
+#[tokio::test]
+async fn malformed_payloads_without_credentials_are_rejected_as_unauthorized() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .post(format!("{}/webhooks/postmark", &app.server_address))
+        .header("Content-Type", "application/json")
+        .body("{ not json")
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn bounces_apply_to_every_subscription_with_the_same_address() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    // Addresses are unique, but only as they were typed
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, $2, 'le guin', now(), 'confirmed')
+        "#,
+        Uuid::new_v4(),
+        email.to_uppercase()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let response = app
+        .post_postmark_webhook(&bounce(&email, "HardBounce"))
+        .await;
+    assert_eq!(200, response.status().as_u16());
+
+    let subscriptions = sqlx::query!(
+        r#"
+        SELECT s.status, COUNT(e.event_id) AS "bounces!"
+        FROM subscriptions s
+        LEFT JOIN subscription_events e ON e.subscriber_id = s.id AND e.event_type = 'bounced'
+        GROUP BY s.id
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(subscriptions.len(), 2);
+    for subscription in subscriptions {
+        assert_eq!(subscription.status, "bounced");
+        assert_eq!(subscription.bounces, 1);
+    }
+}